@@ -0,0 +1,144 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+
+use rand::Rng;
+
+use super::{NetAddr, NetSim};
+use super::latency::LatencyModel;
+use crate::node::{Node, types::route_dist};
+
+/// Kind of connection a node has to the internet
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum NodeClass {
+	Datacenter,
+	Broadband,
+	Mobile,
+	Satellite,
+}
+impl NodeClass {
+	pub const ALL: [NodeClass; 4] = [NodeClass::Datacenter, NodeClass::Broadband, NodeClass::Mobile, NodeClass::Satellite];
+	/// Ranges a node of this class draws its access link from
+	pub fn profile(self) -> AccessProfile {
+		match self {
+			NodeClass::Datacenter => AccessProfile { delay: 0..=1, jitter: 0..=0, loss: 0.0..=0.0 },
+			NodeClass::Broadband => AccessProfile { delay: 5..=20, jitter: 1..=3, loss: 0.0..=0.002 },
+			NodeClass::Mobile => AccessProfile { delay: 20..=60, jitter: 5..=20, loss: 0.005..=0.02 },
+			// Geostationary, the dish to satellite to ground station hop alone is ~250 ticks one way
+			NodeClass::Satellite => AccessProfile { delay: 250..=300, jitter: 10..=30, loss: 0.002..=0.01 },
+		}
+	}
+}
+impl std::str::FromStr for NodeClass {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"datacenter" => Ok(NodeClass::Datacenter),
+			"broadband" => Ok(NodeClass::Broadband),
+			"mobile" => Ok(NodeClass::Mobile),
+			"satellite" => Ok(NodeClass::Satellite),
+			_ => Err(format!("unknown node class {:?}, valid: datacenter, broadband, mobile, satellite", s)),
+		}
+	}
+}
+
+/// Ranges the access link of a node class is drawn from, uniformly
+#[derive(Debug, Clone)]
+pub struct AccessProfile {
+	pub delay: RangeInclusive<isize>,
+	pub jitter: RangeInclusive<isize>,
+	pub loss: RangeInclusive<f64>,
+}
+impl AccessProfile {
+	pub fn draw(&self, class: NodeClass, rng: &mut impl Rng) -> AccessLink {
+		let loss = if self.loss.start() < self.loss.end() { rng.gen_range(self.loss.clone()) } else { *self.loss.start() };
+		AccessLink { class, delay: rng.gen_range(self.delay.clone()), jitter: rng.gen_range(self.jitter.clone()), loss }
+	}
+}
+
+/// A node's last-mile link, its delay is added to every packet the node sends or receives regardless of geometry
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct AccessLink {
+	pub class: NodeClass,
+	/// One-way delay in ticks
+	pub delay: isize,
+	/// Maximum uniform jitter in ticks added per packet
+	pub jitter: isize,
+	/// Chance that a packet is lost on this link
+	pub loss: f64,
+}
+impl AccessLink {
+	pub fn new(class: NodeClass, rng: &mut impl Rng) -> Self { class.profile().draw(class, rng) }
+	pub fn roll_jitter(&self, rng: &mut impl Rng) -> isize {
+		if self.jitter <= 0 { return 0 }
+		rng.gen_range(0..=self.jitter)
+	}
+	pub fn roll_loss(&self, rng: &mut impl Rng) -> bool { self.loss > 0. && rng.gen_bool(self.loss.min(1.)) }
+}
+
+/// Relative weights of the node classes given to nodes added to the router
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ClassMix {
+	pub datacenter: f64,
+	pub broadband: f64,
+	pub mobile: f64,
+	pub satellite: f64,
+}
+impl ClassMix {
+	pub fn new(datacenter: f64, broadband: f64, mobile: f64, satellite: f64) -> Self { Self { datacenter, broadband, mobile, satellite } }
+	/// Pick a class for a new node and draw its access link, None if every weight is 0
+	pub fn roll(&self, rng: &mut impl Rng) -> Option<AccessLink> {
+		let weights = [self.datacenter, self.broadband, self.mobile, self.satellite].map(|weight| weight.max(0.));
+		let total: f64 = weights.iter().sum();
+		if total <= 0. { return None }
+		let mut pick = rng.gen::<f64>() * total;
+		let class = NodeClass::ALL.iter().zip(weights).find(|&(_, weight)| { pick -= weight; pick < 0. }).map_or(NodeClass::Satellite, |(&class, _)| class);
+		Some(AccessLink::new(class, rng))
+	}
+}
+
+/// How far route coordinate distances are from actual latencies, see `NetSim::embedding_error`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EmbeddingError {
+	/// Number of node pairs sampled
+	pub pairs: usize,
+	/// Mean of |coordinate distance - latency| / latency
+	pub relative_error: f64,
+	/// Same, if each coordinate also had a height equal to its node's access delay (the best a height component could do)
+	pub height_error: f64,
+	/// Mean relative error of pairs by the class of their higher-delay end, nodes without an access link are left out
+	pub by_class: BTreeMap<NodeClass, (usize, f64)>,
+}
+
+impl<LM: LatencyModel> NetSim<Node, LM> {
+	/// Compare route coordinate distances with jitter-free latencies between `samples` random pairs of nodes
+	pub fn embedding_error(&self, samples: usize, rng: &mut impl Rng) -> EmbeddingError {
+		let coords: Vec<(NetAddr, _)> = self.nodes.iter().filter_map(|(&net_addr, node)| Some((net_addr, node.route_coord?))).collect();
+		let mut error = EmbeddingError::default();
+		if coords.len() < 2 { return error }
+		let (mut error_sum, mut height_sum) = (0., 0.);
+		for _ in 0..samples {
+			let ((src, src_coord), (dest, dest_coord)) = (coords[rng.gen_range(0..coords.len())], coords[rng.gen_range(0..coords.len())]);
+			if src == dest { continue }
+			let latency = match self.router.expected_latency(src, dest) { Some(latency) if latency > 0 => latency as f64, _ => continue };
+			let access = |net_addr| self.router.node_map.get(&net_addr).and_then(|rn| rn.access);
+			let (src_access, dest_access) = (access(src), access(dest));
+			let dist = route_dist(&src_coord, &dest_coord);
+			let heights = src_access.map_or(0, |link| link.delay) + dest_access.map_or(0, |link| link.delay);
+			let relative_error = (dist - latency).abs() / latency;
+			error_sum += relative_error;
+			height_sum += (dist + heights as f64 - latency).abs() / latency;
+			error.pairs += 1;
+			if let Some(link) = [src_access, dest_access].iter().flatten().max_by_key(|link| link.delay) {
+				let (count, sum) = error.by_class.entry(link.class).or_default();
+				*count += 1;
+				*sum += relative_error;
+			}
+		}
+		if error.pairs > 0 {
+			error.relative_error = error_sum / error.pairs as f64;
+			error.height_error = height_sum / error.pairs as f64;
+		}
+		for (count, sum) in error.by_class.values_mut() { *sum /= *count as f64; }
+		error
+	}
+}
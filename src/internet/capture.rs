@@ -0,0 +1,270 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{CustomNode, InternetError, NetAddr, NetSim, NetSimPacket, NetSimPacketVec, NetSimRequest};
+use super::latency::LatencyModel;
+use crate::node::RouteCoord;
+
+/// First bytes of a capture log
+const CAPTURE_MAGIC: &[u8; 8] = b"DBRCAPT\0";
+
+/// One entry of a capture log, the log is these entries one after another in bincode
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum CaptureRecord<CN: CustomNode> {
+	/// Bincode of a node when capturing started, when it joined or when the simulation was rewound, replay starts over from it
+	Node { time: usize, net_addr: NetAddr, state: Vec<u8> },
+	/// A node was ticked after sleeping for `skipped` ticks. `sent` is what the node returned, before the simulator answered requests
+	/// and filled in return addresses. Afterwards the node's oracle coordinate was set to `oracle`.
+	Tick { time: usize, net_addr: NetAddr, skipped: usize, received: Vec<NetSimPacket<CN>>, sent: Vec<NetSimPacket<CN>>, oracle: Option<RouteCoord> },
+	/// A node moved, its oracle coordinate was set to `oracle`
+	Moved { time: usize, net_addr: NetAddr, oracle: RouteCoord },
+}
+
+/// Recording of node ticks in progress, see `NetSim::start_capture`
+#[derive(Derivative)]
+#[derivative(Debug(bound = ""))]
+pub struct Capture<CN: CustomNode> {
+	/// Nodes whose ticks are recorded, every node if empty
+	pub nodes: BTreeSet<NetAddr>,
+	/// Number of records written so far
+	pub records: usize,
+	#[derivative(Debug="ignore")]
+	writer: Box<dyn Write + Send>,
+	#[derivative(Debug="ignore")]
+	encode_node: fn(&CN) -> bincode::Result<Vec<u8>>,
+}
+impl<CN: CustomNode> Capture<CN> {
+	pub fn captures(&self, net_addr: NetAddr) -> bool { self.nodes.is_empty() || self.nodes.contains(&net_addr) }
+	fn write(&mut self, record: &CaptureRecord<CN>) -> bincode::Result<()> {
+		bincode::serialize_into(&mut self.writer, record)?;
+		self.records += 1;
+		Ok(())
+	}
+	fn write_node(&mut self, time: usize, net_addr: NetAddr, node: &CN) -> bincode::Result<()> {
+		let state = (self.encode_node)(node)?;
+		self.write(&CaptureRecord::Node { time, net_addr, state })
+	}
+}
+
+/// A captured node's tick, waiting for the simulator to route what it sent
+pub(super) struct PendingTick<CN: CustomNode> {
+	net_addr: NetAddr,
+	skipped: usize,
+	received: Vec<NetSimPacket<CN>>,
+	sent: Vec<NetSimPacket<CN>>,
+}
+
+impl<CN: CustomNode, LM: LatencyModel> NetSim<CN, LM> {
+	/// Stop capturing after the log couldn't be written, ticking can't return the error
+	fn capture_failed(&mut self, err: bincode::Error) {
+		log::error!("Capture: failed to write log, stopped capturing: {}", err);
+		self.capture = None;
+	}
+	/// Record the state of every captured node, replay starts over from these
+	pub(super) fn capture_node_states(&mut self) {
+		let time = self.ticks();
+		let capture = match &mut self.capture { Some(capture) => capture, None => return };
+		let result = self.nodes.iter().try_for_each(|(&net_addr, node)| {
+			if capture.captures(net_addr) { capture.write_node(time, net_addr, node) } else { Ok(()) }
+		});
+		if let Err(err) = result { self.capture_failed(err); }
+	}
+	/// Record the state of a node that was just added or changed outside of a tick, replay starts over from it
+	pub(super) fn capture_node_state(&mut self, net_addr: NetAddr) {
+		let time = self.ticks();
+		let (capture, node) = match (&mut self.capture, self.nodes.get(&net_addr)) { (Some(capture), Some(node)) if capture.captures(net_addr) => (capture, node), _ => return };
+		if let Err(err) = capture.write_node(time, net_addr, node) { self.capture_failed(err); }
+	}
+	pub(super) fn capture_moved(&mut self, net_addr: NetAddr, oracle: RouteCoord) {
+		let time = self.ticks();
+		let capture = match &mut self.capture { Some(capture) if capture.captures(net_addr) => capture, _ => return };
+		if let Err(err) = capture.write(&CaptureRecord::Moved { time, net_addr, oracle }) { self.capture_failed(err); }
+	}
+	/// Copy what captured nodes are about to receive, before they are ticked
+	pub(super) fn capture_received(&self, ready: &BTreeMap<NetAddr, NetSimPacketVec<CN>>) -> Option<Vec<PendingTick<CN>>> {
+		let capture = self.capture.as_ref()?;
+		let time = self.ticks();
+		Some(ready.iter().filter(|(&net_addr, _)| capture.captures(net_addr) && self.nodes.contains_key(&net_addr)).map(|(&net_addr, incoming)| PendingTick {
+			net_addr,
+			skipped: self.clocks.get(&net_addr).map_or(0, |&clock| time.saturating_sub(clock)),
+			received: incoming.to_vec(),
+			sent: Vec::new(),
+		}).collect())
+	}
+	/// Copy what captured nodes sent, before it is routed
+	pub(super) fn capture_sent(pending: &mut [PendingTick<CN>], sent: &[(NetAddr, NetSimPacketVec<CN>)]) {
+		let sent: BTreeMap<NetAddr, &NetSimPacketVec<CN>> = sent.iter().map(|(net_addr, outgoing)| (*net_addr, outgoing)).collect();
+		for tick in pending {
+			if let Some(outgoing) = sent.get(&tick.net_addr) { tick.sent = outgoing.to_vec(); }
+		}
+	}
+	/// Write the ticks of captured nodes once their oracle coordinates are updated
+	pub(super) fn capture_ticks(&mut self, pending: Vec<PendingTick<CN>>) {
+		let time = self.ticks();
+		let capture = match &mut self.capture { Some(capture) => capture, None => return };
+		let router = &self.router;
+		let result = pending.into_iter().try_for_each(|PendingTick { net_addr, skipped, received, sent }| {
+			let oracle = router.node_map.get(&net_addr).map(|rn| rn.latency_position().map(|s| s.floor() as i64));
+			capture.write(&CaptureRecord::Tick { time, net_addr, skipped, received, sent, oracle })
+		});
+		if let Err(err) = result { self.capture_failed(err); }
+	}
+}
+
+impl<CN: CustomNode + Serialize, LM: LatencyModel> NetSim<CN, LM> {
+	/// Start recording every tick of `nodes` (every node if empty) to `writer`, replacing any capture in progress
+	pub fn start_capture(&mut self, mut writer: impl Write + Send + 'static, nodes: BTreeSet<NetAddr>) -> Result<(), InternetError> {
+		self.stop_capture()?;
+		writer.write_all(CAPTURE_MAGIC)?;
+		self.capture = Some(Capture { nodes, records: 0, writer: Box::new(writer), encode_node: bincode::serialize::<CN> });
+		self.capture_node_states();
+		Ok(())
+	}
+	/// Stop capturing, returns the number of records written or None if nothing was being captured
+	pub fn stop_capture(&mut self) -> Result<Option<usize>, InternetError> {
+		match self.capture.take() {
+			Some(mut capture) => { capture.writer.flush()?; Ok(Some(capture.records)) }
+			None => Ok(None),
+		}
+	}
+}
+
+/// Read every record of a capture log written by `NetSim::start_capture`
+pub fn read_capture<CN: CustomNode>(mut reader: impl Read) -> Result<Vec<CaptureRecord<CN>>, InternetError> {
+	let mut magic = [0; CAPTURE_MAGIC.len()];
+	reader.read_exact(&mut magic)?;
+	if &magic != CAPTURE_MAGIC { return Err(InternetError::InvalidCapture { reason: "not a capture log".to_owned() }) }
+	let mut records = Vec::new();
+	loop {
+		match bincode::deserialize_from(&mut reader) {
+			Ok(record) => records.push(record),
+			// A log cut off mid-record (e.g. the simulator was killed) is read up to the last whole record
+			Err(err) => match *err {
+				bincode::ErrorKind::Io(ref io_err) if io_err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+				_ => return Err(err.into()),
+			},
+		}
+	}
+	Ok(records)
+}
+
+/// Variant names of a packet's request, or of its payload if it has none
+pub fn packet_variants<CN: CustomNode>(packet: &NetSimPacket<CN>) -> Vec<&'static str> {
+	match &packet.request {
+		Some(request) => vec![request.name()],
+		None => CN::packet_variants(&packet.data),
+	}
+}
+
+/// Which packets of a capture log to show
+#[derive(Debug, Clone, Default)]
+pub struct CaptureFilter {
+	/// Only packets sent or received by these nodes, every node if empty
+	pub nodes: BTreeSet<NetAddr>,
+	/// Only packets with one of these variant names (see `packet_variants`), every packet if empty
+	pub kinds: BTreeSet<String>,
+	/// Only packets sent or received in this time range
+	pub time: Option<Range<usize>>,
+}
+impl CaptureFilter {
+	pub fn matches(&self, time: usize, src_addr: NetAddr, dest_addr: NetAddr, variants: &[&str]) -> bool {
+		(self.nodes.is_empty() || self.nodes.contains(&src_addr) || self.nodes.contains(&dest_addr))
+			&& (self.kinds.is_empty() || variants.iter().any(|variant| self.kinds.contains(*variant)))
+			&& self.time.as_ref().map_or(true, |range| range.contains(&time))
+	}
+}
+
+/// One line of the NDJSON view of a capture log
+#[derive(Serialize)]
+#[serde(bound = "")]
+struct PacketView<'a, CN: CustomNode> {
+	time: usize,
+	/// "received" or "sent", from the point of view of the captured node
+	direction: &'static str,
+	src_addr: NetAddr,
+	dest_addr: NetAddr,
+	/// Length of the payload in bytes
+	size: usize,
+	variants: Vec<&'static str>,
+	request: Option<&'a NetSimRequest<CN>>,
+}
+
+/// Write the packets of a capture log that pass `filter` as newline-delimited JSON, returns the number of packets written
+pub fn write_capture_view<CN: CustomNode>(records: &[CaptureRecord<CN>], filter: &CaptureFilter, mut writer: impl Write) -> Result<usize, InternetError> {
+	let mut written = 0;
+	for record in records {
+		let (time, net_addr, received, sent) = match record {
+			CaptureRecord::Tick { time, net_addr, received, sent, .. } => (*time, *net_addr, received, sent),
+			_ => continue,
+		};
+		let packets = received.iter().map(|packet| ("received", packet.src_addr, packet)).chain(sent.iter().map(|packet| ("sent", net_addr, packet)));
+		for (direction, src_addr, packet) in packets {
+			let variants = packet_variants(packet);
+			if !filter.matches(time, src_addr, packet.dest_addr, &variants) { continue }
+			let view = PacketView { time, direction, src_addr, dest_addr: packet.dest_addr, size: packet.data.len(), variants, request: packet.request.as_ref() };
+			serde_json::to_writer(&mut writer, &view)?;
+			writer.write_all(b"\n")?;
+			written += 1;
+		}
+	}
+	writer.flush()?;
+	Ok(written)
+}
+
+/// First tick at which a replayed node didn't send what it sent in the capture
+#[derive(Debug, Clone)]
+pub struct Divergence {
+	pub time: usize,
+	pub expected: Vec<String>,
+	pub replayed: Vec<String>,
+}
+#[derive(Debug, Clone)]
+pub struct ReplayReport {
+	pub net_addr: NetAddr,
+	/// Ticks replayed
+	pub ticks: usize,
+	/// Number of times the node was reset to a captured state
+	pub resets: usize,
+	pub divergence: Option<Divergence>,
+}
+
+fn describe<CN: CustomNode>(packet: &NetSimPacket<CN>) -> String {
+	format!("to NetAddr({}) {}B {:?}", packet.dest_addr, packet.data.len(), packet_variants(packet))
+}
+
+/// Feed everything a node received in a capture back through it, starting from its captured state, and check that it sends the same packets.
+/// Replay stops at the first tick where it doesn't, the node is returned as it was right after that tick.
+/// Whatever was done to the node outside of ticks (e.g. actions given on the command line) is not in the capture and shows up as a divergence.
+pub fn replay_capture<CN: CustomNode + DeserializeOwned>(records: Vec<CaptureRecord<CN>>, net_addr: NetAddr) -> Result<(CN, ReplayReport), InternetError> {
+	let mut report = ReplayReport { net_addr, ticks: 0, resets: 0, divergence: None };
+	let mut node: Option<CN> = None;
+	for record in records {
+		match record {
+			CaptureRecord::Node { net_addr: addr, state, .. } if addr == net_addr => {
+				node = Some(bincode::deserialize(&state)?);
+				report.resets += 1;
+			}
+			CaptureRecord::Tick { time, net_addr: addr, skipped, received, sent, oracle } if addr == net_addr => {
+				let node = node.as_mut().ok_or_else(|| InternetError::InvalidCapture { reason: format!("NetAddr({}) was ticked before its state was captured", net_addr) })?;
+				if skipped > 0 { node.skip_ticks(skipped); }
+				let replayed: Vec<NetSimPacket<CN>> = node.tick(received.into_iter().collect()).into_vec();
+				report.ticks += 1;
+				if bincode::serialize(&replayed)? != bincode::serialize(&sent)? {
+					report.divergence = Some(Divergence { time, expected: sent.iter().map(describe).collect(), replayed: replayed.iter().map(describe).collect() });
+					break;
+				}
+				if oracle.is_some() { node.set_deus_ex_data(oracle); }
+			}
+			CaptureRecord::Moved { net_addr: addr, oracle, .. } if addr == net_addr => {
+				if let Some(node) = &mut node { node.set_deus_ex_data(Some(oracle)); }
+			}
+			_ => {}
+		}
+	}
+	let node = node.ok_or_else(|| InternetError::InvalidCapture { reason: format!("NetAddr({}) is not in the capture", net_addr) })?;
+	Ok((node, report))
+}
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{CustomNode, InternetError, NetSim};
+use super::latency::LatencyModel;
+use crate::node::Node;
+use crate::rng::SimRng;
+
+/// Default number of checkpoints kept, the oldest is dropped when another is taken
+pub const MAX_CHECKPOINTS: usize = 16;
+
+/// The whole simulation and the random number generator driving it at one point in time
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Checkpoint {
+	pub ticks: usize,
+	#[derivative(Debug="ignore")]
+	state: Vec<u8>,
+}
+impl Checkpoint {
+	/// Bytes of memory the checkpoint takes up
+	pub fn size(&self) -> usize { self.state.len() }
+}
+
+/// Checkpoints kept in memory to rewind the simulation to, they are not part of snapshots
+#[derive(Debug)]
+pub struct Checkpoints {
+	/// Take a checkpoint whenever simulation time reaches a multiple of this, see `NetSim::tick_checkpointed`
+	pub interval: Option<usize>,
+	/// Number of checkpoints kept
+	pub max: usize,
+	list: VecDeque<Checkpoint>,
+}
+impl Default for Checkpoints {
+	fn default() -> Self { Self { interval: None, max: MAX_CHECKPOINTS, list: VecDeque::new() } }
+}
+impl Checkpoints {
+	pub fn iter(&self) -> impl Iterator<Item = &Checkpoint> { self.list.iter() }
+	pub fn len(&self) -> usize { self.list.len() }
+	pub fn is_empty(&self) -> bool { self.list.is_empty() }
+	pub fn clear(&mut self) { self.list.clear(); }
+	fn insert(&mut self, checkpoint: Checkpoint) {
+		if self.list.back().map_or(false, |last| last.ticks == checkpoint.ticks) { self.list.pop_back(); }
+		self.list.push_back(checkpoint);
+		while self.list.len() > self.max.max(1) { self.list.pop_front(); }
+	}
+	/// Time of the first automatic checkpoint after `now`
+	fn next_due(&self, now: usize) -> Option<usize> {
+		self.interval.filter(|&interval| interval > 0).map(|interval| (now / interval + 1) * interval)
+	}
+}
+
+impl<CN, LM> NetSim<CN, LM>
+where CN: CustomNode + Serialize + DeserializeOwned, LM: LatencyModel + Serialize + DeserializeOwned {
+	/// Keep a copy of the simulation and `rng` in memory to rewind to later
+	pub fn checkpoint(&mut self, rng: &SimRng) -> Result<&Checkpoint, InternetError> {
+		let state = bincode::serialize(&(&*self, rng))?;
+		self.checkpoints.insert(Checkpoint { ticks: self.ticks(), state });
+		Ok(self.checkpoints.list.back().unwrap())
+	}
+	/// Go back to the latest checkpoint taken at or before `time` and return its time.
+	/// Later checkpoints are dropped, the simulation may take a different course from here.
+	pub fn restore_checkpoint(&mut self, time: usize, rng: &mut SimRng) -> Result<usize, InternetError> {
+		let idx = self.checkpoints.list.iter().rposition(|checkpoint| checkpoint.ticks <= time).ok_or(InternetError::NoCheckpoint { time })?;
+		self.checkpoints.list.truncate(idx + 1);
+		let (mut restored, restored_rng): (Self, SimRng) = bincode::deserialize(&self.checkpoints.list[idx].state)?;
+		restored.threads = self.threads;
+		restored.pool = self.pool.take();
+		restored.checkpoints = std::mem::take(&mut self.checkpoints);
+		restored.capture = self.capture.take();
+		*self = restored;
+		*rng = restored_rng;
+		// Nodes go back in time, replay has to start over from their restored state
+		self.capture_node_states();
+		Ok(self.ticks())
+	}
+}
+
+impl<LM: LatencyModel + Serialize + DeserializeOwned> NetSim<Node, LM> {
+	/// Same as `tick_churn`, but stops to take a checkpoint whenever one is due
+	pub fn tick_checkpointed(&mut self, ticks: usize, rng: &mut SimRng) -> Result<(), InternetError> {
+		let end = self.ticks() + ticks;
+		while let Some(time) = self.checkpoints.next_due(self.ticks()).filter(|&time| time <= end) {
+			self.tick_churn(time - self.ticks(), rng)?;
+			self.checkpoint(rng)?;
+		}
+		self.tick_churn(end - self.ticks(), rng)
+	}
+	/// Bring the simulation back to exactly `time` by restoring the latest checkpoint before it and re-running up to `time`,
+	/// returns the time of the checkpoint that was restored. `time` can't be later than the current time.
+	pub fn rewind(&mut self, time: usize, rng: &mut SimRng) -> Result<usize, InternetError> {
+		if time > self.ticks() { return Err(InternetError::RewindAhead { time, now: self.ticks() }) }
+		let restored = self.restore_checkpoint(time, rng)?;
+		self.tick_checkpointed(time - restored, rng)?;
+		Ok(restored)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rand::SeedableRng;
+
+	#[test]
+	fn rewind_only_goes_back_to_checkpoints() {
+		let mut rng = SimRng::seed_from_u64(3);
+		let mut net: NetSim<Node> = NetSim::new();
+		for i in 0..4 {
+			let node = Node::new(i, net.lease());
+			net.add_node(node, &mut rng).unwrap();
+		}
+		net.tick(100, &mut rng);
+		assert!(matches!(net.rewind(50, &mut rng), Err(InternetError::NoCheckpoint { time: 50 })));
+		net.checkpoint(&rng).unwrap();
+		net.tick(100, &mut rng);
+		assert!(matches!(net.rewind(50, &mut rng), Err(InternetError::NoCheckpoint { time: 50 })));
+		assert!(matches!(net.rewind(300, &mut rng), Err(InternetError::RewindAhead { time: 300, now: 200 })));
+		assert_eq!(net.ticks(), 200);
+		assert_eq!(net.rewind(150, &mut rng).unwrap(), 100);
+		assert_eq!(net.ticks(), 150);
+	}
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeSet;
+
+use rand::Rng;
+use rand::seq::IteratorRandom;
+
+use super::{CustomNode, InternetError, NetAddr, NetSim};
+use super::latency::LatencyModel;
+use crate::node::{Node, NodeAction, NodeID, types::route_dist};
+
+/// Default number of ticks between churn metric samples
+pub const SAMPLE_INTERVAL: usize = 1000;
+/// Default number of random node pairs greedy routing is tested between per sample
+pub const ROUTING_SAMPLES: usize = 100;
+/// Greedy routes that haven't arrived after this many hops count as failures
+const MAX_HOPS: usize = 64;
+/// Longest session length drawn, so that the time of a departure can't overflow
+const MAX_SESSION_LENGTH: usize = u32::MAX as usize;
+
+/// Distribution of how long a node stays in the network
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SessionLength {
+	/// Memoryless sessions with a mean length in ticks
+	Exponential { mean: f64 },
+	/// Weibull distributed sessions, `shape` below 1 gives many short sessions and a few very long ones
+	Weibull { shape: f64, scale: f64 },
+}
+impl SessionLength {
+	/// Draw a session length in ticks
+	pub fn sample(&self, rng: &mut impl Rng) -> usize {
+		let uniform = 1. - rng.gen::<f64>(); // (0, 1]
+		let ticks = match *self {
+			SessionLength::Exponential { mean } => -uniform.ln() * mean,
+			SessionLength::Weibull { shape, scale } => scale * (-uniform.ln()).powf(1. / shape),
+		};
+		ticks.round().clamp(1., MAX_SESSION_LENGTH as f64) as usize
+	}
+	fn validate(&self) -> Result<(), InternetError> {
+		let parameters = match *self {
+			SessionLength::Exponential { mean } => vec![("mean", mean)],
+			SessionLength::Weibull { shape, scale } => vec![("shape", shape), ("scale", scale)],
+		};
+		match parameters.into_iter().find(|&(_, value)| !value.is_finite() || value <= 0.) {
+			Some((name, value)) => Err(InternetError::InvalidChurnModel { reason: format!("session length {} must be finite and above 0, got {}", name, value) }),
+			None => Ok(()),
+		}
+	}
+}
+
+/// Parameters of the process nodes join and leave the network by
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChurnModel {
+	/// Mean number of nodes joining per tick, joins are a Poisson process
+	pub arrival_rate: f64,
+	/// How long a node stays before leaving
+	pub session: SessionLength,
+	/// Chance that a leaving node crashes instead of leaving gracefully
+	pub crash_ratio: f64,
+	/// Ticks between metric samples
+	pub sample_interval: usize,
+	/// Number of random node pairs greedy routing is tested between per sample
+	pub routing_samples: usize,
+}
+impl ChurnModel {
+	/// Fails unless the arrival rate is finite and not negative, the crash ratio is a probability and the session length's parameters are finite and positive
+	pub fn new(arrival_rate: f64, session: SessionLength, crash_ratio: f64) -> Result<Self, InternetError> {
+		if !arrival_rate.is_finite() || arrival_rate < 0. {
+			return Err(InternetError::InvalidChurnModel { reason: format!("arrival rate must be finite and at least 0, got {}", arrival_rate) })
+		}
+		if !(0. ..=1.).contains(&crash_ratio) {
+			return Err(InternetError::InvalidChurnModel { reason: format!("crash ratio must be between 0 and 1, got {}", crash_ratio) })
+		}
+		session.validate()?;
+		Ok(Self { arrival_rate, session, crash_ratio, sample_interval: SAMPLE_INTERVAL, routing_samples: ROUTING_SAMPLES })
+	}
+	/// Ticks until the next join, f64::MAX rather than infinity if nobody joins so that snapshots can hold it as JSON
+	fn interarrival(&self, rng: &mut impl Rng) -> f64 {
+		if self.arrival_rate <= 0. { return f64::MAX }
+		-(1. - rng.gen::<f64>()).ln() / self.arrival_rate
+	}
+}
+
+/// One point of the churn metrics time series
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChurnSample {
+	pub time: usize,
+	pub live_nodes: usize,
+	/// Nodes that joined since the previous sample
+	pub joins: usize,
+	/// Nodes that left gracefully since the previous sample
+	pub leaves: usize,
+	/// Nodes that crashed since the previous sample
+	pub crashes: usize,
+	/// Fraction of random live node pairs that greedy routing over peer lists connects
+	pub routing_success: f64,
+	/// DHT reads since the previous sample
+	pub dht_reads: usize,
+	/// Fraction of those reads that found a route coordinate
+	pub dht_hit_rate: f64,
+	/// Fraction of live nodes that have a route coordinate in the DHT
+	pub dht_coverage: f64,
+	/// DHT entries whose node is no longer in the network (left behind by crashes)
+	pub stale_dht_entries: usize,
+	/// Sessions live nodes still hold with nodes that are no longer in the network, until they time out
+	pub stale_sessions: usize,
+	/// Packets dropped since the previous sample because their destination had left the network
+	pub dead_dropped: usize,
+}
+
+/// Running churn process, see `NetSim::tick_churn`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Churn {
+	pub model: ChurnModel,
+	/// Time of the next join, fractional so that several nodes can join in one tick
+	next_arrival: f64,
+	/// Scheduled departures as (time, NetAddr)
+	departures: BTreeSet<(usize, NetAddr)>,
+	next_sample: usize,
+	joins: usize,
+	leaves: usize,
+	crashes: usize,
+	/// DHT (reads, hits) at the previous sample
+	dht_last: (usize, usize),
+	/// `RouterStats::dead_dropped` at the previous sample
+	dropped_last: usize,
+	/// Metrics time series, one sample every `model.sample_interval` ticks
+	pub samples: Vec<ChurnSample>,
+}
+impl Churn {
+	fn new(model: ChurnModel, now: usize, rng: &mut impl Rng) -> Self {
+		Self {
+			next_arrival: now as f64 + model.interarrival(rng),
+			departures: BTreeSet::new(),
+			next_sample: now + model.sample_interval.max(1),
+			joins: 0, leaves: 0, crashes: 0,
+			dht_last: (0, 0),
+			dropped_last: 0,
+			samples: Vec::new(),
+			model,
+		}
+	}
+	fn schedule_departure(&mut self, net_addr: NetAddr, now: usize, rng: &mut impl Rng) {
+		self.departures.insert((now.saturating_add(self.model.session.sample(rng)), net_addr));
+	}
+	/// Time of the next join, departure or sample
+	fn next_event(&self) -> usize {
+		let arrival = self.next_arrival.ceil() as usize;
+		let departure = self.departures.iter().next().map_or(usize::MAX, |&(time, _)| time);
+		arrival.min(departure).min(self.next_sample)
+	}
+	/// Write the metrics time series as CSV
+	pub fn write_csv(&self, writer: impl std::io::Write) -> csv::Result<()> {
+		let mut wtr = csv::Writer::from_writer(writer);
+		for sample in &self.samples { wtr.serialize(sample)?; }
+		wtr.flush()?;
+		Ok(())
+	}
+}
+
+impl<LM: LatencyModel> NetSim<Node, LM> {
+	/// Start (or restart) churning the network, every node already in it is given a session length too
+	pub fn start_churn(&mut self, model: ChurnModel, rng: &mut impl Rng) {
+		let now = self.ticks();
+		let mut churn = Churn::new(model, now, rng);
+		for &net_addr in self.nodes.keys() { churn.schedule_departure(net_addr, now, rng); }
+		churn.dht_last = (self.dht_stats.reads, self.dht_stats.hits);
+		churn.dropped_last = self.router.stats.dead_dropped;
+		self.churn = Some(churn);
+	}
+	/// Same as `tick`, but nodes join and leave according to the churn model while the simulation runs
+	pub fn tick_churn(&mut self, ticks: usize, rng: &mut impl Rng) -> Result<(), InternetError> {
+		let end = self.ticks() + ticks;
+		while let Some(time) = self.churn.as_ref().map(Churn::next_event).filter(|&time| time < end) {
+			self.tick(time.saturating_sub(self.ticks()), rng);
+			self.churn_step(rng)?;
+		}
+		self.tick(end - self.ticks(), rng);
+		Ok(())
+	}
+	/// Run every churn event that is due at the current time
+	fn churn_step(&mut self, rng: &mut impl Rng) -> Result<(), InternetError> {
+		let mut churn = match self.churn.take() { Some(churn) => churn, None => return Ok(()) };
+		// Churn goes back in even if an event failed, so that it can be resumed
+		let result = self.churn_events(&mut churn, rng);
+		self.churn = Some(churn);
+		result
+	}
+	fn churn_events(&mut self, churn: &mut Churn, rng: &mut impl Rng) -> Result<(), InternetError> {
+		let now = self.ticks();
+
+		while let Some(&(time, net_addr)) = churn.departures.iter().next() {
+			if time > now { break }
+			churn.departures.remove(&(time, net_addr));
+			// Node might have been removed by hand already
+			if !self.nodes.contains_key(&net_addr) { continue }
+			if rng.gen_bool(churn.model.crash_ratio.clamp(0., 1.)) {
+				self.crash_node(net_addr)?;
+				churn.crashes += 1;
+			} else {
+				self.leave_node(net_addr, rng)?;
+				churn.leaves += 1;
+			}
+		}
+
+		while churn.next_arrival <= now as f64 {
+			churn.next_arrival += churn.model.interarrival(rng);
+			let net_addr = self.lease();
+			// Nodes added by hand pick their own NodeID, a joining node takes its NetAddr as NodeID unless a live node has it already, then the next free one
+			let live: BTreeSet<NodeID> = self.nodes.values().map(|node| node.node_id).collect();
+			let node_id = (net_addr as NodeID..=NodeID::MAX).chain(0..net_addr as NodeID).find(|node_id| !live.contains(node_id)).expect("more live nodes than NodeIDs");
+			let mut node = Node::new(node_id, net_addr);
+			// Bootstrap off a node that already knows where it is, a node can't estimate its coordinate off nodes that don't
+			let placed = self.nodes.iter().filter(|(_, node)| node.route_coord.is_some()).choose(rng);
+			if let Some((&bootstrap_addr, bootstrap_node)) = placed.or_else(|| self.nodes.iter().choose(rng)) {
+				node.action(NodeAction::Bootstrap(bootstrap_node.node_id, bootstrap_addr));
+				// Coordinates are only comparable between nodes that estimate them the same way
+				node.estimator = bootstrap_node.estimator.fresh();
+			}
+			self.add_node(node, rng)?;
+			churn.schedule_departure(net_addr, now, rng);
+			churn.joins += 1;
+		}
+
+		if churn.next_sample <= now {
+			let sample = self.churn_sample(churn, rng);
+			log::info!("Churn: {:?}", sample);
+			churn.samples.push(sample);
+			churn.next_sample = now + churn.model.sample_interval.max(1);
+		}
+		Ok(())
+	}
+	fn churn_sample(&self, churn: &mut Churn, rng: &mut impl Rng) -> ChurnSample {
+		let routable: Vec<NetAddr> = self.nodes.iter().filter(|(_, node)| node.route_coord.is_some()).map(|(&net_addr, _)| net_addr).collect();
+		let (mut attempts, mut successes) = (0, 0);
+		if routable.len() >= 2 {
+			for _ in 0..churn.model.routing_samples {
+				let (src, dest) = (routable[rng.gen_range(0..routable.len())], routable[rng.gen_range(0..routable.len())]);
+				// A node always reaches itself, that says nothing about the network
+				if src == dest { continue }
+				attempts += 1;
+				if self.greedy_route(src, dest) { successes += 1; }
+			}
+		}
+
+		let (reads, hits) = (self.dht_stats.reads - churn.dht_last.0, self.dht_stats.hits - churn.dht_last.1);
+		churn.dht_last = (self.dht_stats.reads, self.dht_stats.hits);
+		let dead_dropped = self.router.stats.dead_dropped - churn.dropped_last;
+		churn.dropped_last = self.router.stats.dead_dropped;
+
+		let sample = ChurnSample {
+			time: self.ticks(),
+			live_nodes: self.nodes.len(),
+			joins: churn.joins,
+			leaves: churn.leaves,
+			crashes: churn.crashes,
+			routing_success: if attempts > 0 { successes as f64 / attempts as f64 } else { 0. },
+			dht_reads: reads,
+			dht_hit_rate: if reads > 0 { hits as f64 / reads as f64 } else { 0. },
+			dht_coverage: self.dht_coverage(),
+			stale_dht_entries: self.stale_dht_entries(),
+			stale_sessions: self.stale_sessions(),
+			dead_dropped,
+		};
+		churn.joins = 0; churn.leaves = 0; churn.crashes = 0;
+		sample
+	}
+	/// Have a node leave the network gracefully, it tells its remotes and withdraws its DHT entry before it is removed
+	pub fn leave_node(&mut self, net_addr: NetAddr, rng: &mut impl Rng) -> Result<Node, InternetError> {
+		self.nodes.get_mut(&net_addr).ok_or(InternetError::NoNodeError { net_addr })?.action(NodeAction::Shutdown);
+		self.capture_node_state(net_addr);
+		self.tick_node_now(net_addr, rng)?;
+		self.crash_node(net_addr)
+	}
+	/// Number of sessions live nodes hold with nodes that are no longer in the network
+	pub fn stale_sessions(&self) -> usize {
+		let live: BTreeSet<NodeID> = self.nodes.values().map(|node| node.node_id).collect();
+		self.nodes.values().map(|node| node.remotes.values().filter(|remote| remote.session.is_some() && !live.contains(&remote.node_id)).count()).sum()
+	}
+	/// Follow peer lists from `src` towards `dest`'s route coordinate, always picking the closest peer.
+	/// Fails on a local minimum, a peer that has left the network, a hop cut by an active fault or after `MAX_HOPS`.
+	pub fn greedy_route(&self, src: NetAddr, dest: NetAddr) -> bool {
+		let (target_id, target) = match self.nodes.get(&dest) {
+			Some(Node { node_id, route_coord: Some(route_coord), .. }) => (*node_id, *route_coord),
+			_ => return false,
+		};
+		let mut current = match self.nodes.get(&src) { Some(node) => node, None => return false };
+		for _ in 0..MAX_HOPS {
+			if current.node_id == target_id { return true }
+			let current_dist = current.route_coord.map_or(f64::INFINITY, |coord| route_dist(&coord, &target));
+			let next: Option<NetAddr> = try {
+				let peer_idx = current.find_closest_peer(&target).ok()?;
+				let peer_coord = current.peer_list.get_by_left(&peer_idx)?;
+				if route_dist(peer_coord, &target) >= current_dist { return false } // Stuck in a local minimum
+				current.remote(peer_idx).ok()?.session().ok()?.direct().ok()?.net_addr
+			};
+			let next = next.filter(|&net_addr| !self.router.is_cut(current.net_addr, net_addr));
+			current = match next.and_then(|net_addr| self.nodes.get(&net_addr)) { Some(node) => node, None => return false };
+		}
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rng::SimRng;
+	use rand::SeedableRng;
+
+	#[test]
+	fn joining_nodes_take_free_node_ids() {
+		let mut rng = SimRng::seed_from_u64(3);
+		let mut net: NetSim<Node> = NetSim::new();
+		// Hand-added nodes hold the NodeIDs of the NetAddrs joining nodes are leased next
+		for node_id in [4, 5, 0] {
+			let node = Node::new(node_id, net.lease());
+			net.add_node(node, &mut rng).unwrap();
+		}
+		net.start_churn(ChurnModel::new(0.01, SessionLength::Exponential { mean: 1e9 }, 0.).unwrap(), &mut rng);
+		net.tick_churn(1000, &mut rng).unwrap();
+		let node_ids: BTreeSet<NodeID> = net.nodes.values().map(|node| node.node_id).collect();
+		assert!(net.nodes.len() > 5);
+		assert_eq!(node_ids.len(), net.nodes.len());
+	}
+}
@@ -0,0 +1,125 @@
+use rand::Rng;
+
+use super::InternetError;
+
+/// Default number of extra ticks a reordered packet may be held back for
+pub const REORDER_DELAY: isize = 10;
+
+/// Probabilities of a packet being mistreated while travelling through the simulated internet
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LinkConditions {
+	/// Chance that a packet is dropped
+	pub loss: f64,
+	/// Chance that a packet is delivered twice (the duplicate gets its own latency)
+	pub duplication: f64,
+	/// Chance that a packet is held back so that later packets overtake it
+	pub reordering: f64,
+	/// Maximum number of ticks a reordered packet is held back for
+	pub reorder_delay: isize,
+}
+impl Default for LinkConditions {
+	fn default() -> Self { Self { loss: 0., duplication: 0., reordering: 0., reorder_delay: REORDER_DELAY } }
+}
+impl LinkConditions {
+	/// Fails unless every probability is between 0 and 1
+	pub fn new(loss: f64, duplication: f64, reordering: f64) -> Result<Self, InternetError> {
+		for (name, probability) in [("loss", loss), ("duplication", duplication), ("reordering", reordering)] {
+			if !(0. ..=1.).contains(&probability) {
+				return Err(InternetError::InvalidLinkConditions { reason: format!("{} must be a probability between 0 and 1, got {}", name, probability) })
+			}
+		}
+		Ok(Self { loss, duplication, reordering, reorder_delay: REORDER_DELAY })
+	}
+	pub fn is_perfect(&self) -> bool { self.loss <= 0. && self.duplication <= 0. && self.reordering <= 0. }
+	pub fn roll_loss(&self, rng: &mut impl Rng) -> bool { self.loss > 0. && rng.gen_bool(self.loss.min(1.)) }
+	pub fn roll_duplication(&self, rng: &mut impl Rng) -> bool { self.duplication > 0. && rng.gen_bool(self.duplication.min(1.)) }
+	/// Returns extra delay to add to a packet if it gets reordered
+	pub fn roll_reordering(&self, rng: &mut impl Rng) -> Option<isize> {
+		if self.reordering > 0. && self.reorder_delay > 0 && rng.gen_bool(self.reordering.min(1.)) {
+			Some(rng.gen_range(1..=self.reorder_delay))
+		} else { None }
+	}
+}
+
+/// Capacity of a node's connection to the internet
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Bandwidth {
+	/// Bytes per tick the node can send
+	pub uplink: f64,
+	/// Bytes per tick the node can receive
+	pub downlink: f64,
+	/// Maximum number of bytes waiting in each direction's queue before packets are dropped
+	pub buffer: usize,
+}
+impl Bandwidth {
+	/// Fails unless both rates are finite and positive and the buffer can hold at least one byte
+	pub fn new(uplink: f64, downlink: f64, buffer: usize) -> Result<Self, InternetError> {
+		for (name, rate) in [("uplink", uplink), ("downlink", downlink)] {
+			if !rate.is_finite() || rate <= 0. {
+				return Err(InternetError::InvalidBandwidth { reason: format!("{} must be a finite number of bytes per tick above 0, got {}", name, rate) })
+			}
+		}
+		if buffer == 0 { return Err(InternetError::InvalidBandwidth { reason: "buffer must be at least 1 byte".into() }) }
+		Ok(Self { uplink, downlink, buffer })
+	}
+}
+
+/// FIFO queue modelled by the time at which the link finishes sending everything currently queued
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LinkQueue {
+	pub free_at: f64,
+}
+impl LinkQueue {
+	/// Enqueue `size` bytes at time `now` on a link of `rate` bytes per tick.
+	/// Returns the number of ticks until the packet has been fully transmitted, or None if the buffer is full.
+	pub fn enqueue(&mut self, now: usize, size: usize, rate: f64, buffer: usize) -> Option<isize> {
+		let now = now as f64;
+		let start = self.free_at.max(now);
+		let backlog = (start - now) * rate;
+		if backlog + size as f64 > buffer as f64 { return None }
+		self.free_at = start + size as f64 / rate;
+		Some((self.free_at - now).round() as isize)
+	}
+	pub fn clear(&mut self) { self.free_at = 0.; }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn queue_serializes_packets() {
+		let mut queue = LinkQueue::default();
+		// 100 bytes at 10 bytes per tick take 10 ticks, the next packet waits behind it
+		assert_eq!(queue.enqueue(0, 100, 10., 1000), Some(10));
+		assert_eq!(queue.enqueue(0, 50, 10., 1000), Some(15));
+		// Once drained the queue starts over from the current time
+		assert_eq!(queue.enqueue(100, 20, 10., 1000), Some(2));
+	}
+
+	#[test]
+	fn queue_drops_when_buffer_is_full() {
+		let mut queue = LinkQueue::default();
+		assert_eq!(queue.enqueue(0, 80, 1., 100), Some(80));
+		assert_eq!(queue.enqueue(0, 30, 1., 100), None);
+		// A dropped packet takes no room, half the backlog has drained after 40 ticks
+		assert_eq!(queue.enqueue(40, 60, 1., 100), Some(100));
+	}
+
+	#[test]
+	fn conditions_reject_invalid_probabilities() {
+		assert!(LinkConditions::new(0., 0.5, 1.).is_ok());
+		for (loss, duplication, reordering) in [(-0.1, 0., 0.), (0., 1.5, 0.), (0., 0., f64::NAN), (f64::INFINITY, 0., 0.)] {
+			assert!(LinkConditions::new(loss, duplication, reordering).is_err(), "{} {} {}", loss, duplication, reordering);
+		}
+	}
+
+	#[test]
+	fn bandwidth_rejects_unusable_links() {
+		assert!(Bandwidth::new(10., 5., 100).is_ok());
+		for (uplink, downlink) in [(0., 5.), (10., -1.), (f64::NAN, 5.), (10., f64::INFINITY)] {
+			assert!(Bandwidth::new(uplink, downlink, 100).is_err(), "{} {}", uplink, downlink);
+		}
+		assert!(Bandwidth::new(10., 5., 0).is_err());
+	}
+}
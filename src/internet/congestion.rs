@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use nalgebra::Point2;
+use rand::Rng;
+
+use super::{InternetError, NetAddr, NetSim};
+use super::router::RouterNode;
+use super::latency::LatencyModel;
+use crate::node::{Node, NodeID};
+
+/// Daily load cycle, the phase follows a node's x position like time zones follow longitude
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Diurnal {
+	/// Ticks in one day
+	pub period: usize,
+	/// Extra load at the daily peak, 0.5 makes latencies up to 50% longer
+	pub amplitude: f64,
+}
+
+/// Random congestion episodes, each loads every node within `radius` of a random point for a while
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct EpisodeModel {
+	/// Mean number of episodes starting per tick, starts are a Poisson process
+	pub rate: f64,
+	/// Mean length of an episode in ticks
+	pub mean_duration: f64,
+	pub radius: f32,
+	/// Extra load inside an episode
+	pub severity: f64,
+}
+
+impl EpisodeModel {
+	/// Ticks between the starts of two episodes, exponentially distributed
+	fn interarrival(&self, rng: &mut impl Rng) -> f64 { -(1. - rng.gen::<f64>()).ln() / self.rate }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Episode {
+	center: Point2<f32>,
+	start: usize,
+	end: usize,
+}
+
+/// Constant load multiplier for nodes inside a rectangle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegionLoad {
+	pub min: Point2<f32>,
+	pub max: Point2<f32>,
+	pub multiplier: f64,
+}
+impl RegionLoad {
+	fn contains(&self, position: &Point2<f32>) -> bool {
+		(self.min.x..self.max.x).contains(&position.x) && (self.min.y..self.max.y).contains(&position.y)
+	}
+}
+
+/// Time-varying load that stretches base latencies.
+/// Every node has a load factor of at least 1, a link's latency is multiplied by the mean load of its two ends.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Congestion {
+	pub diurnal: Option<Diurnal>,
+	pub episodes: Option<EpisodeModel>,
+	pub regions: Vec<RegionLoad>,
+	/// Episodes that started and haven't ended yet
+	active: Vec<Episode>,
+	/// Time the next episode starts, fractional so that several can start in one tick
+	next_episode: f64,
+}
+impl Congestion {
+	/// Number of congestion episodes going on
+	pub fn active_episodes(&self, now: usize) -> usize { self.active.iter().filter(|episode| (episode.start..episode.end).contains(&now)).count() }
+	/// Have episodes start according to `model` from `now` on, the first one an inter-arrival time after `now`
+	pub fn set_episodes(&mut self, model: EpisodeModel, now: usize, rng: &mut impl Rng) {
+		self.episodes = Some(model);
+		self.next_episode = now as f64;
+		if model.rate > 0. { self.next_episode += model.interarrival(rng); }
+	}
+	/// Start the episodes due by `now` and forget the ones that ended
+	pub fn advance(&mut self, now: usize, field: &(Range<i32>, Range<i32>), rng: &mut impl Rng) {
+		self.active.retain(|episode| episode.end > now);
+		let model = match self.episodes { Some(model) if model.rate > 0. => model, _ => return };
+		while self.next_episode <= now as f64 {
+			let start = self.next_episode.ceil() as usize;
+			let duration = -(1. - rng.gen::<f64>()).ln() * model.mean_duration;
+			let center = Point2::new(rng.gen_range(field.0.clone()) as f32, rng.gen_range(field.1.clone()) as f32);
+			self.active.push(Episode { center, start, end: start + (duration.round() as usize).max(1) });
+			self.next_episode += model.interarrival(rng);
+		}
+	}
+	/// Forget every episode, used when the simulation restarts at tick 0
+	pub fn reset(&mut self) {
+		self.active.clear();
+		self.next_episode = 0.;
+	}
+	/// Load factor of a node at `now`
+	pub fn load(&self, node: &RouterNode, now: usize, field: &(Range<i32>, Range<i32>)) -> f64 {
+		let mut load = 1.;
+		if let Some(Diurnal { period, amplitude }) = self.diurnal {
+			let width = (field.0.end - field.0.start).max(1) as f64;
+			let longitude = (node.position.x as f64 - field.0.start as f64) / width;
+			let phase = now as f64 / period.max(1) as f64 + longitude;
+			load *= 1. + amplitude * 0.5 * (1. - (2. * std::f64::consts::PI * phase).cos());
+		}
+		for region in self.regions.iter().filter(|region| region.contains(&node.position)) { load *= region.multiplier; }
+		if let Some(model) = self.episodes {
+			for episode in &self.active {
+				if (episode.start..episode.end).contains(&now) && nalgebra::distance(&episode.center, &node.position) <= model.radius {
+					load *= 1. + model.severity;
+				}
+			}
+		}
+		load.max(1.)
+	}
+	/// Latency multiplier of the link between two nodes at `now`
+	pub fn multiplier(&self, src: &RouterNode, dest: &RouterNode, now: usize, field: &(Range<i32>, Range<i32>)) -> f64 {
+		(self.load(src, now, field) + self.load(dest, now, field)) / 2.
+	}
+}
+
+/// Whether session trackers and peer selection keep up with congestion, taken while measuring it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CongestionSample {
+	pub time: usize,
+	/// Mean load factor over all nodes
+	pub mean_load: f64,
+	pub active_episodes: usize,
+	/// Mean of |dist_avg - current latency| / current latency over direct sessions that have been pinged
+	pub tracker_error: f64,
+	/// Pings acknowledged since the previous sample, how often the averages see a new measurement
+	pub pings: usize,
+	/// Peer list entries added or removed since the previous sample
+	pub peer_changes: usize,
+	/// Total peer list entries
+	pub peers: usize,
+}
+
+impl<LM: LatencyModel> NetSim<Node, LM> {
+	fn congestion_sample(&self, last_peers: &mut BTreeMap<NetAddr, BTreeSet<NodeID>>, last_pings: &mut usize) -> CongestionSample {
+		let now = self.ticks();
+		let router = &self.router;
+		let mut sample = CongestionSample { time: now, ..Default::default() };
+		if let Some(congestion) = &router.congestion {
+			sample.active_episodes = congestion.active_episodes(now);
+			if !router.node_map.is_empty() {
+				sample.mean_load = router.node_map.values().map(|node| congestion.load(node, now, &router.field_dimensions)).sum::<f64>() / router.node_map.len() as f64;
+			}
+		} else { sample.mean_load = 1.; }
+
+		let (mut error_sum, mut tracked, mut pings) = (0., 0, 0);
+		let mut peers = BTreeMap::new();
+		for (&net_addr, node) in &self.nodes {
+			for remote in node.remotes.values() {
+				let session = match remote.session() { Ok(session) => session, Err(_) => continue };
+				pings += session.tracker.ping_count;
+				let (remote_addr, tracker) = match session.direct() { Ok(direct) => (direct.net_addr, &session.tracker), Err(_) => continue };
+				if tracker.ping_count == 0 { continue }
+				if let Some(latency) = router.expected_latency(net_addr, remote_addr).filter(|&latency| latency > 0) {
+					error_sum += (tracker.dist_avg as f64 - latency as f64).abs() / latency as f64;
+					tracked += 1;
+				}
+			}
+			let peer_ids: BTreeSet<NodeID> = node.peer_list.iter().filter_map(|(&node_idx, _)| Some(node.remote(node_idx).ok()?.node_id)).collect();
+			sample.peers += peer_ids.len();
+			let last = last_peers.get(&net_addr);
+			sample.peer_changes += last.map_or(peer_ids.len(), |last| last.symmetric_difference(&peer_ids).count());
+			peers.insert(net_addr, peer_ids);
+		}
+		if tracked > 0 { sample.tracker_error = error_sum / tracked as f64; }
+		sample.pings = pings.saturating_sub(*last_pings);
+		*last_pings = pings;
+		*last_peers = peers;
+		sample
+	}
+	/// Tick the network for `ticks`, sampling how well the overlay tracks congestion every `interval` ticks
+	pub fn measure_congestion(&mut self, ticks: usize, interval: usize, rng: &mut impl Rng) -> Result<Vec<CongestionSample>, InternetError> {
+		let interval = interval.max(1);
+		let (mut last_peers, mut last_pings) = (BTreeMap::new(), 0);
+		self.router.advance_congestion(rng);
+		self.congestion_sample(&mut last_peers, &mut last_pings);
+		let mut samples = Vec::new();
+		let end = self.ticks() + ticks;
+		while self.ticks() < end {
+			self.tick_churn(interval.min(end - self.ticks()), rng)?;
+			// Episodes otherwise only start when a packet is sent
+			self.router.advance_congestion(rng);
+			samples.push(self.congestion_sample(&mut last_peers, &mut last_pings));
+		}
+		Ok(samples)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::rng::SimRng;
+	use rand::SeedableRng;
+
+	#[test]
+	fn episodes_start_once_enabled() {
+		let mut rng = SimRng::seed_from_u64(3);
+		let field = (0..100, 0..100);
+		let mut congestion = Congestion::default();
+		let model = EpisodeModel { rate: 0.01, mean_duration: 1e6, radius: 10., severity: 1. };
+		// Episodes enabled late in a simulation don't make up for the time before
+		congestion.set_episodes(model, 1_000_000, &mut rng);
+		congestion.advance(1_000_000, &field, &mut rng);
+		assert_eq!(congestion.active_episodes(1_000_000), 0);
+		congestion.advance(1_010_000, &field, &mut rng);
+		assert!((50..200).contains(&congestion.active_episodes(1_010_000)));
+	}
+}
@@ -0,0 +1,390 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::io::BufRead;
+
+use rand::Rng;
+
+use super::{InternetError, NetAddr};
+use super::router::RouterNode;
+use super::topology::TopologyLatency;
+
+/// Default amount of uniform jitter added to geometric latencies
+pub const VARIANCE: isize = 2;
+
+/// Determines how long a packet takes to travel between two `RouterNode`s
+pub trait LatencyModel: Debug {
+	/// Called when a node is added to the router, before any latency is generated for it
+	fn add_node(&mut self, _node: &mut RouterNode, _rng: &mut impl Rng) {}
+	/// Called after a node's position changed
+	fn move_node(&mut self, _node: &mut RouterNode, _rng: &mut impl Rng) {}
+	/// Called when a node is removed from the router
+	fn remove_node(&mut self, _net_addr: NetAddr) {}
+	/// Forget every node registered through `add_node`
+	fn reset(&mut self) {}
+	/// Whether another node can be added, models with a fixed number of places for nodes refuse new ones once they are all taken
+	fn has_room(&self) -> bool { true }
+	/// Base (jitter-free) latency between two nodes, called for every packet so it should be cheap
+	fn distance(&self, src: &RouterNode, dest: &RouterNode) -> isize;
+	/// Per-packet variation added on top of the base latency
+	fn jitter(&self, distance: isize, rng: &mut impl Rng) -> isize;
+}
+
+/// Latency is the euclidean distance between node positions plus uniform jitter
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeometricLatency {
+	pub variance: isize,
+}
+impl Default for GeometricLatency {
+	fn default() -> Self { Self { variance: VARIANCE } }
+}
+impl LatencyModel for GeometricLatency {
+	fn distance(&self, src: &RouterNode, dest: &RouterNode) -> isize {
+		nalgebra::distance(&src.position, &dest.position) as isize
+	}
+	fn jitter(&self, _distance: isize, rng: &mut impl Rng) -> isize {
+		if self.variance <= 0 { return 0 }
+		rng.gen_range(-self.variance..self.variance)
+	}
+}
+
+/// Distribution that heavy-tailed jitter is drawn from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum HeavyTail {
+	/// Multiplies the base latency by `exp(N(0, sigma))`
+	LogNormal { sigma: f64 },
+	/// Adds `scale * (U^(-1/alpha) - 1)` ticks, lower `alpha` means heavier tail
+	Pareto { alpha: f64, scale: f64 },
+}
+
+/// Geometric base latency with occasional very large delays
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeavyTailedLatency {
+	pub tail: HeavyTail,
+}
+impl HeavyTailedLatency {
+	/// Fails unless `sigma` is finite and above 0
+	pub fn log_normal(sigma: f64) -> Result<Self, InternetError> {
+		Self::validate(&[("sigma", sigma)])?;
+		Ok(Self { tail: HeavyTail::LogNormal { sigma } })
+	}
+	/// Fails unless `alpha` and `scale` are finite and above 0
+	pub fn pareto(alpha: f64, scale: f64) -> Result<Self, InternetError> {
+		Self::validate(&[("alpha", alpha), ("scale", scale)])?;
+		Ok(Self { tail: HeavyTail::Pareto { alpha, scale } })
+	}
+	fn validate(parameters: &[(&str, f64)]) -> Result<(), InternetError> {
+		match parameters.iter().find(|&&(_, value)| !value.is_finite() || value <= 0.) {
+			Some((name, value)) => Err(InternetError::InvalidLatencyModel { reason: format!("{} must be finite and above 0, got {}", name, value) }),
+			None => Ok(()),
+		}
+	}
+}
+impl Default for HeavyTailedLatency {
+	fn default() -> Self { Self { tail: HeavyTail::LogNormal { sigma: 0.25 } } }
+}
+impl LatencyModel for HeavyTailedLatency {
+	fn distance(&self, src: &RouterNode, dest: &RouterNode) -> isize {
+		nalgebra::distance(&src.position, &dest.position) as isize
+	}
+	fn jitter(&self, distance: isize, rng: &mut impl Rng) -> isize {
+		match self.tail {
+			HeavyTail::LogNormal { sigma } => {
+				// Box-Muller transform for a standard normal sample
+				let (u1, u2): (f64, f64) = (1. - rng.gen::<f64>(), rng.gen());
+				let normal = (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos();
+				(distance as f64 * ((sigma * normal).exp() - 1.)) as isize
+			}
+			HeavyTail::Pareto { alpha, scale } => {
+				let uniform = 1. - rng.gen::<f64>(); // (0, 1]
+				(scale * (uniform.powf(-1. / alpha) - 1.)) as isize
+			}
+		}
+	}
+}
+
+/// Latencies are looked up in an N×N matrix of measured round-trip distances.
+/// Each node takes a row of its own, so at most N nodes can be in the network at once.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MatrixLatency {
+	/// Number of rows (and columns) in `matrix`
+	pub size: usize,
+	/// Row-major latency matrix, `matrix[src * size + dest]`
+	pub matrix: Vec<isize>,
+	/// Maps each node to its row in the matrix, rows are handed out in the order nodes are added
+	pub indices: BTreeMap<NetAddr, usize>,
+	/// Next row that has never been handed out
+	pub next_row: usize,
+	/// Rows released by removed nodes, reused before any new row is handed out
+	pub free_rows: Vec<usize>,
+	pub variance: isize,
+}
+impl MatrixLatency {
+	pub fn new(size: usize, matrix: Vec<isize>) -> Self {
+		assert_eq!(matrix.len(), size * size, "latency matrix must be square");
+		Self { size, matrix, indices: BTreeMap::new(), next_row: 0, free_rows: Vec::new(), variance: 0 }
+	}
+	pub fn get(&self, src: usize, dest: usize) -> isize { self.matrix[src * self.size + dest] }
+
+	/// Parse a pairwise RTT matrix (King / RIPE Atlas style).
+	/// * Fields may be separated by commas, semicolons or whitespace, lines starting with `#` are ignored
+	/// * A non-numeric header row and/or label column are skipped, see `MatrixLatency::layout`
+	/// * Empty, non-numeric or negative entries are treated as missing measurements
+	/// * RTTs are halved to one-way latency and multiplied by `ticks_per_unit`
+	pub fn from_reader(reader: impl BufRead, ticks_per_unit: f64) -> Result<Self, InternetError> {
+		let mut lines: Vec<Vec<String>> = Vec::new();
+		for line in reader.lines() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') { continue }
+			// Keep empty fields in delimited files, they mark missing measurements
+			let fields = if line.contains(|c| c == ',' || c == ';') {
+				line.split(|c| c == ',' || c == ';').map(|f| f.trim().to_owned()).collect()
+			} else { line.split_whitespace().map(str::to_owned).collect() };
+			lines.push(fields);
+		}
+		let (header, label_column) = Self::layout(&lines)?;
+		let rows: Vec<Vec<Option<f64>>> = lines[header..].iter().map(|fields| {
+			fields.iter().skip(label_column as usize).map(|f| f.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.)).collect()
+		}).collect();
+
+		let size = rows.len();
+
+		// Fill in missing measurements from the reverse direction, or else the mean of all known measurements (the zero diagonal aside)
+		let known: Vec<f64> = rows.iter().enumerate()
+			.flat_map(|(src, values)| values.iter().enumerate().filter(move |&(dest, _)| dest != src).filter_map(|(_, v)| *v))
+			.collect();
+		if known.is_empty() { return Err(InternetError::InvalidLatencyMatrix { reason: "matrix has no valid measurements".into() }) }
+		let mean = known.iter().sum::<f64>() / known.len() as f64;
+		let mut missing = 0;
+		let mut matrix = Vec::with_capacity(size * size);
+		for src in 0..size {
+			for dest in 0..size {
+				let rtt = if src == dest { 0. } else {
+					rows[src][dest].or(rows[dest][src]).unwrap_or_else(|| { missing += 1; mean })
+				};
+				matrix.push((rtt / 2. * ticks_per_unit).round() as isize);
+			}
+		}
+		if missing > 0 { log::warn!("MatrixLatency: {} of {} measurements missing, filled with mean RTT {:.2}", missing, size * (size - 1), mean); }
+		Ok(Self::new(size, matrix))
+	}
+	/// Work out whether a matrix has a header row and a label column from the shape of its rows, returns (header rows, label column).
+	/// Of the readings that line up into a square matrix, the one that throws away the fewest numbers wins, then the one that leaves the fewest gaps,
+	/// so a blank or `NA` entry in the first row or column isn't mistaken for a label.
+	fn layout(lines: &[Vec<String>]) -> Result<(usize, bool), InternetError> {
+		if lines.is_empty() { return Err(InternetError::InvalidLatencyMatrix { reason: "matrix has no rows".into() }) }
+		let is_number = |field: &String| field.parse::<f64>().is_ok();
+		// Only a row without any numbers can be a header
+		let headers = if lines[0].iter().any(is_number) { 0..=0 } else { 0..=1 };
+		let readings = headers.flat_map(|header| [(header, false), (header, true)]).filter_map(|(header, label_column)| {
+			let rows = &lines[header..];
+			let size = rows.len();
+			if size == 0 || rows.iter().any(|fields| fields.len() != size + label_column as usize) { return None }
+			let dropped = if label_column { rows.iter().filter(|fields| is_number(&fields[0])).count() } else { 0 };
+			let gaps: usize = rows.iter().enumerate().map(|(src, fields)| {
+				fields[label_column as usize..].iter().enumerate().filter(|&(dest, field)| dest != src && !is_number(field)).count()
+			}).sum();
+			Some(((dropped, gaps), (header, label_column)))
+		});
+		readings.min_by_key(|&(score, _)| score).map(|(_, layout)| layout).ok_or_else(|| {
+			let (shortest, longest) = lines.iter().fold((usize::MAX, 0), |(min, max), fields| (min.min(fields.len()), max.max(fields.len())));
+			InternetError::InvalidLatencyMatrix { reason: format!("{} rows of {} to {} columns don't form a square matrix, with or without a header row and label column", lines.len(), shortest, longest) }
+		})
+	}
+	/// Fraction of `samples` random triples (a, b, c) where `d(a,c) > d(a,b) + d(b,c)`
+	pub fn triangle_violations(&self, samples: usize, rng: &mut impl Rng) -> f64 {
+		if self.size < 3 || samples == 0 { return 0. }
+		let violations = (0..samples).filter(|_| {
+			let (a, b, c) = (rng.gen_range(0..self.size), rng.gen_range(0..self.size), rng.gen_range(0..self.size));
+			self.get(a, c) > self.get(a, b) + self.get(b, c)
+		}).count();
+		violations as f64 / samples as f64
+	}
+}
+impl LatencyModel for MatrixLatency {
+	fn add_node(&mut self, node: &mut RouterNode, _rng: &mut impl Rng) {
+		if self.indices.contains_key(&node.uuid) { return }
+		let row = match self.free_rows.pop() {
+			Some(row) => row,
+			None if self.next_row < self.size => { self.next_row += 1; self.next_row - 1 }
+			None => {
+				// Sharing a row would put two nodes at distance 0 from each other, `NetSim::add_node` refuses nodes before it comes to this
+				log::warn!("MatrixLatency: all {} rows are taken, NetAddr({}) falls back to geometric latency", self.size, node.uuid);
+				return
+			}
+		};
+		self.indices.insert(node.uuid, row);
+	}
+	fn remove_node(&mut self, net_addr: NetAddr) {
+		if let Some(row) = self.indices.remove(&net_addr) { self.free_rows.push(row); }
+	}
+	fn reset(&mut self) { self.indices.clear(); self.next_row = 0; self.free_rows.clear(); }
+	fn has_room(&self) -> bool { self.indices.len() < self.size }
+	fn distance(&self, src: &RouterNode, dest: &RouterNode) -> isize {
+		match (self.indices.get(&src.uuid), self.indices.get(&dest.uuid)) {
+			(Some(&src_idx), Some(&dest_idx)) => self.get(src_idx, dest_idx),
+			_ => nalgebra::distance(&src.position, &dest.position) as isize,
+		}
+	}
+	fn jitter(&self, _distance: isize, rng: &mut impl Rng) -> isize {
+		if self.variance <= 0 { return 0 }
+		rng.gen_range(-self.variance..self.variance)
+	}
+}
+
+/// Any of the built-in latency models, selectable at runtime
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LatencyType {
+	Geometric(GeometricLatency),
+	HeavyTailed(HeavyTailedLatency),
+	Matrix(MatrixLatency),
+	Topology(TopologyLatency),
+}
+impl Default for LatencyType {
+	fn default() -> Self { LatencyType::Geometric(GeometricLatency::default()) }
+}
+impl LatencyModel for LatencyType {
+	fn add_node(&mut self, node: &mut RouterNode, rng: &mut impl Rng) {
+		match self {
+			LatencyType::Geometric(model) => model.add_node(node, rng),
+			LatencyType::HeavyTailed(model) => model.add_node(node, rng),
+			LatencyType::Matrix(model) => model.add_node(node, rng),
+			LatencyType::Topology(model) => model.add_node(node, rng),
+		}
+	}
+	fn move_node(&mut self, node: &mut RouterNode, rng: &mut impl Rng) {
+		match self {
+			LatencyType::Geometric(model) => model.move_node(node, rng),
+			LatencyType::HeavyTailed(model) => model.move_node(node, rng),
+			LatencyType::Matrix(model) => model.move_node(node, rng),
+			LatencyType::Topology(model) => model.move_node(node, rng),
+		}
+	}
+	fn remove_node(&mut self, net_addr: NetAddr) {
+		match self {
+			LatencyType::Geometric(model) => model.remove_node(net_addr),
+			LatencyType::HeavyTailed(model) => model.remove_node(net_addr),
+			LatencyType::Matrix(model) => model.remove_node(net_addr),
+			LatencyType::Topology(model) => model.remove_node(net_addr),
+		}
+	}
+	fn reset(&mut self) {
+		match self {
+			LatencyType::Geometric(model) => model.reset(),
+			LatencyType::HeavyTailed(model) => model.reset(),
+			LatencyType::Matrix(model) => model.reset(),
+			LatencyType::Topology(model) => model.reset(),
+		}
+	}
+	fn has_room(&self) -> bool {
+		match self {
+			LatencyType::Geometric(model) => model.has_room(),
+			LatencyType::HeavyTailed(model) => model.has_room(),
+			LatencyType::Matrix(model) => model.has_room(),
+			LatencyType::Topology(model) => model.has_room(),
+		}
+	}
+	fn distance(&self, src: &RouterNode, dest: &RouterNode) -> isize {
+		match self {
+			LatencyType::Geometric(model) => model.distance(src, dest),
+			LatencyType::HeavyTailed(model) => model.distance(src, dest),
+			LatencyType::Matrix(model) => model.distance(src, dest),
+			LatencyType::Topology(model) => model.distance(src, dest),
+		}
+	}
+	fn jitter(&self, distance: isize, rng: &mut impl Rng) -> isize {
+		match self {
+			LatencyType::Geometric(model) => model.jitter(distance, rng),
+			LatencyType::HeavyTailed(model) => model.jitter(distance, rng),
+			LatencyType::Matrix(model) => model.jitter(distance, rng),
+			LatencyType::Topology(model) => model.jitter(distance, rng),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(text: &str) -> MatrixLatency { MatrixLatency::from_reader(text.as_bytes(), 1.).unwrap() }
+
+	#[test]
+	fn labelled_matrix() {
+		let matrix = parse("\
+			,a,b,c
+			a,0,20,40
+			b,20,0,60
+			c,40,60,0
+		");
+		assert_eq!(matrix.size, 3);
+		assert_eq!(matrix.matrix, vec![0, 10, 20, 10, 0, 30, 20, 30, 0]);
+		// Labels without a header row, even numeric ones
+		assert_eq!(parse("1 0 20\n2 20 0").matrix, vec![0, 10, 10, 0]);
+	}
+
+	#[test]
+	fn unlabelled_matrix() {
+		assert_eq!(parse("0 20 40\n20 0 60\n40 60 0").matrix, vec![0, 10, 20, 10, 0, 30, 20, 30, 0]);
+		// Blank, `-` or `NA` in the first column is a missing measurement, not a label
+		for blank in ["", "-", "NA"] {
+			let matrix = parse(&format!("{},20,40\n20,0,60\n40,60,0", blank));
+			assert_eq!(matrix.size, 3);
+			assert_eq!(matrix.matrix, vec![0, 10, 20, 10, 0, 30, 20, 30, 0]);
+			let matrix = parse(&format!("a,b,c\n{},20,40\n20,0,60\n40,60,0", blank));
+			assert_eq!(matrix.matrix, vec![0, 10, 20, 10, 0, 30, 20, 30, 0]);
+		}
+	}
+
+	#[test]
+	fn missing_entries() {
+		// Filled from the reverse direction, or else with the mean RTT of 40
+		let matrix = parse("\
+			,a,b,c
+			a,0,,40
+			b,20,0,NA
+			c,-1,60,0
+		");
+		assert_eq!(matrix.matrix, vec![0, 10, 20, 10, 0, 30, 20, 30, 0]);
+		let matrix = parse("0,20,\n20,0,\n,,0");
+		assert_eq!(matrix.get(0, 2), 10);
+		assert_eq!(matrix.get(2, 1), 10);
+	}
+
+	#[test]
+	fn matrix_rows_are_not_shared() {
+		let mut matrix = parse("0 20 40\n20 0 60\n40 60 0");
+		let mut rng = <crate::rng::SimRng as rand::SeedableRng>::seed_from_u64(0);
+		let mut nodes: Vec<RouterNode> = (0..4).map(|net_addr| RouterNode::random(net_addr, &(0..10, 0..10), &mut rng)).collect();
+		for node in &mut nodes[..3] {
+			assert!(matrix.has_room());
+			matrix.add_node(node, &mut rng);
+		}
+		assert!(!matrix.has_room());
+		assert_eq!(matrix.indices.values().copied().collect::<Vec<_>>(), vec![0, 1, 2]);
+		// A removed node's row goes to the next node
+		matrix.remove_node(1);
+		assert!(matrix.has_room());
+		matrix.add_node(&mut nodes[3], &mut rng);
+		assert_eq!(matrix.indices.get(&3), Some(&1));
+		assert_eq!(matrix.distance(&nodes[0], &nodes[3]), 10);
+	}
+
+	#[test]
+	fn heavy_tail_rejects_unusable_parameters() {
+		assert!(HeavyTailedLatency::log_normal(0.25).is_ok());
+		assert!(HeavyTailedLatency::pareto(1.5, 10.).is_ok());
+		for sigma in [0., -1., f64::NAN, f64::INFINITY] {
+			assert!(HeavyTailedLatency::log_normal(sigma).is_err(), "{}", sigma);
+		}
+		for (alpha, scale) in [(0., 10.), (-1., 10.), (f64::NAN, 10.), (1.5, 0.), (1.5, -10.), (1.5, f64::INFINITY)] {
+			assert!(HeavyTailedLatency::pareto(alpha, scale).is_err(), "{} {}", alpha, scale);
+		}
+	}
+
+	#[test]
+	fn malformed_matrices() {
+		assert!(MatrixLatency::from_reader("".as_bytes(), 1.).is_err());
+		assert!(MatrixLatency::from_reader("0 20 40\n20 0".as_bytes(), 1.).is_err());
+		assert!(MatrixLatency::from_reader("a,b\nNA,NA\nNA,NA".as_bytes(), 1.).is_err());
+	}
+}
@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+
+use nalgebra::Point2;
+use rand::Rng;
+
+use super::{CustomNode, InternetError, NetAddr, NetSim};
+use super::latency::LatencyModel;
+use crate::node::{Node, RouteCoord, NodeID};
+
+/// Default number of ticks between position updates of moving nodes
+pub const UPDATE_INTERVAL: usize = 100;
+
+/// Random waypoint movement: a node picks a random point on the field, travels there in a straight line,
+/// pauses for a while and picks the next point
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RandomWaypoint {
+	/// Fraction of nodes that move, nodes added later roll this too
+	pub fraction: f64,
+	/// Range speeds are drawn from, in field units per tick
+	pub min_speed: f32,
+	pub max_speed: f32,
+	/// Longest pause at a waypoint in ticks
+	pub max_pause: usize,
+}
+impl RandomWaypoint {
+	pub fn new(fraction: f64, min_speed: f32, max_speed: f32, max_pause: usize) -> Self { Self { fraction, min_speed, max_speed, max_pause } }
+}
+
+/// Where a moving node is headed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Waypoint {
+	target: Point2<f32>,
+	speed: f32,
+	/// The node waits at its current position until this time
+	pause_until: usize,
+}
+
+/// How often and when a node moved, kept to check whether the overlay caught up with the move
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoveRecord {
+	pub first_moved: usize,
+	pub last_moved: usize,
+	pub moves: usize,
+	/// The node's route coordinate right before it first moved
+	pub coord_before: Option<RouteCoord>,
+}
+
+/// Nodes changing position over simulated time, see `NetSim::tick`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Mobility {
+	pub model: Option<RandomWaypoint>,
+	/// Ticks between position updates of moving nodes
+	pub update_interval: usize,
+	/// Nodes moving under `model`
+	waypoints: BTreeMap<NetAddr, Waypoint>,
+	/// Scripted jumps to a position by time
+	teleports: BTreeMap<usize, Vec<(NetAddr, Point2<f32>)>>,
+	next_update: usize,
+	/// Every node that moved so far
+	pub moved: BTreeMap<NetAddr, MoveRecord>,
+}
+impl Mobility {
+	/// Time of the next position update or teleport, usize::MAX if nothing is going to move
+	pub fn next_event(&self) -> usize {
+		let update = if self.waypoints.is_empty() { usize::MAX } else { self.next_update };
+		update.min(self.teleports.keys().next().copied().unwrap_or(usize::MAX))
+	}
+	/// Number of nodes moving under the random waypoint model
+	pub fn moving(&self) -> usize { self.waypoints.len() }
+	/// Number of teleports that haven't happened yet
+	pub fn pending_teleports(&self) -> usize { self.teleports.values().map(Vec::len).sum() }
+	/// Roll whether a node moves under the random waypoint model
+	fn add_node(&mut self, net_addr: NetAddr, now: usize, rng: &mut impl Rng) {
+		let model = match &self.model { Some(model) => model, None => return };
+		if model.fraction > 0. && rng.gen_bool(model.fraction.min(1.)) {
+			// Start out paused so nodes don't all set off at the same time
+			self.waypoints.insert(net_addr, Waypoint { target: Point2::origin(), speed: 0., pause_until: now + rng.gen_range(0..=model.max_pause) });
+		}
+	}
+	fn remove_node(&mut self, net_addr: NetAddr) {
+		self.waypoints.remove(&net_addr);
+		self.moved.remove(&net_addr);
+	}
+}
+
+impl<CN: CustomNode, LM: LatencyModel> NetSim<CN, LM> {
+	/// Start moving a random fraction of the nodes, replaces any earlier model but keeps scheduled teleports
+	pub fn start_mobility(&mut self, model: RandomWaypoint, rng: &mut impl Rng) {
+		let now = self.ticks();
+		let mobility = self.mobility.get_or_insert_with(|| Mobility { update_interval: UPDATE_INTERVAL, ..Default::default() });
+		mobility.model = Some(model);
+		mobility.waypoints.clear();
+		mobility.next_update = now + mobility.update_interval.max(1);
+		for &net_addr in self.nodes.keys() { mobility.add_node(net_addr, now, rng); }
+	}
+	/// Stop random waypoint movement, scheduled teleports still happen
+	pub fn stop_mobility(&mut self) {
+		if let Some(mobility) = &mut self.mobility {
+			mobility.model = None;
+			mobility.waypoints.clear();
+		}
+	}
+	/// Schedule a node to jump to `position` at `time`
+	pub fn teleport(&mut self, net_addr: NetAddr, position: Point2<f32>, time: usize) -> Result<(), InternetError> {
+		if !self.nodes.contains_key(&net_addr) { return Err(InternetError::NoNodeError { net_addr }) }
+		let mobility = self.mobility.get_or_insert_with(|| Mobility { update_interval: UPDATE_INTERVAL, ..Default::default() });
+		mobility.teleports.entry(time.max(self.router.ticks)).or_default().push((net_addr, position));
+		Ok(())
+	}
+	pub(super) fn mobility_add_node(&mut self, net_addr: NetAddr, rng: &mut impl Rng) {
+		let now = self.ticks();
+		if let Some(mobility) = &mut self.mobility { mobility.add_node(net_addr, now, rng); }
+	}
+	pub(super) fn mobility_remove_node(&mut self, net_addr: NetAddr) {
+		if let Some(mobility) = &mut self.mobility { mobility.remove_node(net_addr); }
+	}
+	/// Run every teleport and position update that is due at the current time
+	pub(super) fn mobility_step(&mut self, rng: &mut impl Rng) {
+		let mut mobility = match self.mobility.take() { Some(mobility) => mobility, None => return };
+		let now = self.ticks();
+
+		let mut moves = Vec::new();
+		while let Some(entry) = mobility.teleports.first_entry() {
+			if *entry.key() > now { break }
+			moves.extend(entry.remove());
+		}
+
+		if let (Some(model), true) = (&mobility.model, mobility.next_update <= now) {
+			let elapsed = mobility.update_interval.max(1) as f32;
+			let field = &self.router.field_dimensions;
+			for (&net_addr, waypoint) in mobility.waypoints.iter_mut() {
+				if waypoint.pause_until > now { continue }
+				let position = match self.router.node_map.get(&net_addr) { Some(router_node) => router_node.position, None => continue };
+				if waypoint.speed <= 0. {
+					// Done pausing, head for a new waypoint
+					waypoint.target = Point2::new(rng.gen_range(field.0.clone()) as f32, rng.gen_range(field.1.clone()) as f32);
+					waypoint.speed = if model.max_speed > model.min_speed { rng.gen_range(model.min_speed..model.max_speed) } else { model.min_speed };
+				}
+				let to_target = waypoint.target - position;
+				let step = waypoint.speed * elapsed;
+				if to_target.norm() <= step {
+					moves.push((net_addr, waypoint.target));
+					waypoint.speed = 0.;
+					waypoint.pause_until = now + rng.gen_range(0..=model.max_pause);
+				} else {
+					moves.push((net_addr, position + to_target.normalize() * step));
+				}
+			}
+			mobility.next_update = now + mobility.update_interval.max(1);
+		}
+
+		for (net_addr, position) in moves {
+			let node = match self.nodes.get_mut(&net_addr) { Some(node) => node, None => continue };
+			if !self.router.move_node(net_addr, position, rng) { continue }
+			let record = mobility.moved.entry(net_addr).or_insert_with(|| MoveRecord { first_moved: now, last_moved: now, moves: 0, coord_before: node.route_coord() });
+			record.last_moved = now;
+			record.moves += 1;
+			// The oracle follows the node immediately, whether the node recalculates is up to it
+			let oracle = position.map(|s| s.floor() as i64);
+			node.set_deus_ex_data(Some(oracle));
+			self.capture_moved(net_addr, oracle);
+		}
+		self.mobility = Some(mobility);
+	}
+}
+
+/// Whether the overlay noticed that a node moved
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MobilityReport {
+	pub net_addr: NetAddr,
+	pub node_id: NodeID,
+	pub moves: usize,
+	pub last_moved: usize,
+	/// The node's route coordinate changed since it first moved
+	pub coord_updated: bool,
+	/// Distance between the node's route coordinate and the coordinate the oracle gives its current position
+	pub coord_error: f64,
+	/// The node's DHT entry matches its current route coordinate
+	pub dht_current: bool,
+	/// Peer list entries other nodes hold for this node
+	pub peer_entries: usize,
+	/// Of those, entries that match its current route coordinate
+	pub peer_entries_current: usize,
+}
+
+impl<LM: LatencyModel> NetSim<Node, LM> {
+	/// Check every node that moved: did its route coordinate, DHT entry and the coordinates its peers hold for it follow the move?
+	pub fn mobility_report(&self) -> Vec<MobilityReport> {
+		let moved = match &self.mobility { Some(mobility) => &mobility.moved, None => return Vec::new() };
+		// Coordinates other nodes hold for each node in their peer lists
+		let mut held: BTreeMap<NodeID, Vec<RouteCoord>> = BTreeMap::new();
+		for node in self.nodes.values() {
+			for (&node_idx, &coord) in node.peer_list.iter() {
+				if let Ok(remote) = node.remote(node_idx) { held.entry(remote.node_id).or_default().push(coord); }
+			}
+		}
+		moved.iter().filter_map(|(&net_addr, record)| {
+			let node = self.nodes.get(&net_addr)?;
+			let oracle = self.router.node_map.get(&net_addr)?.latency_position().map(|s| s.floor() as i64);
+			let coord_error = node.route_coord.map_or(f64::INFINITY, |coord| {
+				let diff = (coord - oracle).map(|d| d as f64);
+				diff.norm()
+			});
+			let held = held.get(&node.node_id).map_or(&[][..], Vec::as_slice);
+			Some(MobilityReport {
+				net_addr,
+				node_id: node.node_id,
+				moves: record.moves,
+				last_moved: record.last_moved,
+				coord_updated: node.route_coord != record.coord_before,
+				coord_error,
+				dht_current: node.route_coord.is_some() && self.route_coord_dht.get(&node.node_id) == node.route_coord.as_ref(),
+				peer_entries: held.len(),
+				peer_entries_current: held.iter().filter(|&&coord| Some(coord) == node.route_coord).count(),
+			})
+		}).collect()
+	}
+}
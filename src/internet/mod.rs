@@ -1,6 +1,6 @@
 //#![allow(dead_code)]
 
-use std::{collections::HashMap, fmt::Debug, hash::Hash};
+use std::{collections::{BTreeMap, HashMap}, fmt::Debug, hash::Hash};
 use std::any::Any;
 use std::ops::Range;
 
@@ -12,28 +12,103 @@ use smallvec::SmallVec;
 
 mod router;
 use router::NetSimRouter;
+pub use router::{RouterNode, RouterStats};
+pub mod latency;
+pub mod conditions;
+pub mod churn;
+pub mod partition;
+pub mod nat;
+pub mod topology;
+pub mod mobility;
+pub mod congestion;
+pub mod access;
+pub mod snapshot;
+pub mod checkpoint;
+pub mod capture;
+use churn::Churn;
+use mobility::Mobility;
+use checkpoint::Checkpoints;
+use capture::Capture;
+mod scheduler;
+use scheduler::Event;
+mod pool;
+use pool::TickPool;
+use latency::{LatencyModel, LatencyType, MatrixLatency};
 
 use crate::node::{Node, RouteCoord};
+use crate::rng::SimRng;
 
 pub const FIELD_DIMENSIONS: (Range<i32>, Range<i32>) = (-320..320, -130..130);
+/// Minimum number of nodes ticking at the same time before ticks are spread across threads
+const PARALLEL_THRESHOLD: usize = 64;
 
 #[derive(Error, Debug)]
 pub enum InternetError {
 	#[error("There is no node for this NetAddr: {net_addr}")]
 	NoNodeError { net_addr: NetAddr },
+	#[error("There is already a node at NetAddr: {net_addr}")]
+	AddrInUse { net_addr: NetAddr },
+	#[error("Invalid latency model: {reason}")]
+	InvalidLatencyModel { reason: String },
+	#[error("The latency model has no room for NetAddr {net_addr}, a latency matrix holds as many nodes as it has rows")]
+	LatencyModelFull { net_addr: NetAddr },
+	#[error("Invalid latency matrix: {reason}")]
+	InvalidLatencyMatrix { reason: String },
+	#[error("Invalid link conditions: {reason}")]
+	InvalidLinkConditions { reason: String },
+	#[error("Invalid bandwidth: {reason}")]
+	InvalidBandwidth { reason: String },
+	#[error("Invalid churn model: {reason}")]
+	InvalidChurnModel { reason: String },
+	#[error("Invalid topology: {reason}")]
+	InvalidTopology { reason: String },
+	#[error("Failed to read file")]
+	IoError(#[from] std::io::Error),
+	#[error("Failed to save or load snapshot")]
+	SnapshotError(#[from] bincode::Error),
+	#[error("Failed to convert snapshot to or from JSON")]
+	JsonError(#[from] serde_json::Error),
+	#[error("Invalid snapshot: {reason}")]
+	InvalidSnapshot { reason: String },
+	#[error("No checkpoint at or before tick {time}")]
+	NoCheckpoint { time: usize },
+	#[error("Cannot rewind to tick {time}, the simulation is at tick {now}")]
+	RewindAhead { time: usize, now: usize },
+	#[error("Invalid capture log: {reason}")]
+	InvalidCapture { reason: String },
 }
 
-#[derive(Debug)]
+#[derive(Derivative, Serialize, Deserialize)]
+#[derivative(Debug, Clone(bound = ""))]
+#[serde(bound = "")]
 pub enum NetSimRequest<CN: CustomNode + ?Sized> {
 	RouteCoordDHTRead(CN::CustomNodeUUID),
 	RouteCoordDHTWrite(CN::CustomNodeUUID, RouteCoord),
 	RouteCoordDHTReadResponse(CN::CustomNodeUUID, Option<RouteCoord>),
 	RouteCoordDHTWriteResponse(Option<(CN::CustomNodeUUID, RouteCoord)>),
+	RouteCoordDHTRemove(CN::CustomNodeUUID),
+	RouteCoordDHTRemoveResponse(Option<(CN::CustomNodeUUID, RouteCoord)>),
 	RandomNodeRequest(u32),
 	RandomNodeResponse(u32, Option<CN::CustomNodeUUID>),
 }
+impl<CN: CustomNode + ?Sized> NetSimRequest<CN> {
+	pub fn name(&self) -> &'static str {
+		match self {
+			NetSimRequest::RouteCoordDHTRead(..) => "RouteCoordDHTRead",
+			NetSimRequest::RouteCoordDHTWrite(..) => "RouteCoordDHTWrite",
+			NetSimRequest::RouteCoordDHTReadResponse(..) => "RouteCoordDHTReadResponse",
+			NetSimRequest::RouteCoordDHTWriteResponse(..) => "RouteCoordDHTWriteResponse",
+			NetSimRequest::RouteCoordDHTRemove(..) => "RouteCoordDHTRemove",
+			NetSimRequest::RouteCoordDHTRemoveResponse(..) => "RouteCoordDHTRemoveResponse",
+			NetSimRequest::RandomNodeRequest(..) => "RandomNodeRequest",
+			NetSimRequest::RandomNodeResponse(..) => "RandomNodeResponse",
+		}
+	}
+}
 
-#[derive(Default, Debug)]
+#[derive(Derivative, Serialize, Deserialize)]
+#[derivative(Default(bound = ""), Debug, Clone(bound = ""))]
+#[serde(bound = "")]
 pub struct NetSimPacket<CN: CustomNode + ?Sized> {
 	pub dest_addr: NetAddr,
 	pub data: Vec<u8>,
@@ -44,88 +119,347 @@ impl<CN: CustomNode> NetSimPacket<CN> {
 	pub fn gen_request(dest_addr: NetAddr, request: NetSimRequest<CN>) -> Self { Self { dest_addr, data: vec![], src_addr: dest_addr, request: Some(request) } }
 }
 
-pub type NetAddr = u128;
-pub type NetSimPacketVec<CN> = SmallVec<[NetSimPacket<CN>; 32]>;
+/// Addresses are leased in order from 0 and never reused, 32 bits keep the many maps keyed by address small
+pub type NetAddr = u32;
+/// Most nodes send or receive only a few packets per tick, keep the inline buffer small since one exists per ready node
+pub type NetSimPacketVec<CN> = SmallVec<[NetSimPacket<CN>; 4]>;
 
-pub trait CustomNode: Debug + Default {
+pub trait CustomNode: Debug + Default + Send + 'static {
 	type CustomNodeAction;
-	type CustomNodeUUID: Debug + Hash + Eq + Clone + serde::Serialize + serde::de::DeserializeOwned;
+	type CustomNodeUUID: Debug + Hash + Eq + Ord + Clone + Send + serde::Serialize + serde::de::DeserializeOwned;
 	fn net_addr(&self) -> NetAddr;
 	fn unique_id(&self) -> Self::CustomNodeUUID;
 	fn tick(&mut self, incoming: NetSimPacketVec<Self>) -> NetSimPacketVec<Self>;
+	/// Advance the node's clock by a number of ticks during which it was not ticked
+	fn skip_ticks(&mut self, ticks: usize);
+	/// Number of ticks until this node needs to be ticked again even if no packets arrive, None if it can sleep until a packet arrives
+	fn next_wakeup(&self) -> Option<usize>;
 	fn action(&mut self, action: Self::CustomNodeAction);
 	fn as_any(&self) -> &dyn Any;
+	/// Seed the node's random number generator, called when the node is added to a `NetSim`
+	fn seed_rng(&mut self, seed: u64);
+	/// Tell the node its true position in the router, nodes should only use it as an explicit baseline
 	fn set_deus_ex_data(&mut self, data: Option<RouteCoord>);
+	/// The node's current route coordinate, None if it hasn't calculated one
+	fn route_coord(&self) -> Option<RouteCoord>;
+	/// Names of the variants a packet's payload decodes to, outermost first, used to label and filter captured packets
+	fn packet_variants(_data: &[u8]) -> Vec<&'static str> where Self: Sized { Vec::new() }
 }
 
+/// Counts requests made to the simulated route coordinate DHT
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DhtStats {
+	pub reads: usize,
+	/// Reads that found a route coordinate
+	pub hits: usize,
+	pub writes: usize,
+	/// Entries withdrawn by nodes leaving the network
+	pub removes: usize,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct NetSim<CN: CustomNode> {
-	pub nodes: HashMap<NetAddr, CN>,
-	pub router: NetSimRouter<CN>,
-	route_coord_dht: HashMap<CN::CustomNodeUUID, RouteCoord>,
+pub struct NetSim<CN: CustomNode, LM: LatencyModel = LatencyType> {
+	/// Every node's random number generator is derived from this seed
+	pub seed: u64,
+	pub nodes: BTreeMap<NetAddr, CN>,
+	pub router: NetSimRouter<CN, LM>,
+	/// Lowest NetAddr that has never been handed out, addresses are not reused so stale packets can't reach a new node
+	next_addr: NetAddr,
+	route_coord_dht: BTreeMap<CN::CustomNodeUUID, RouteCoord>,
+	pub dht_stats: DhtStats,
+	/// Nodes joining and leaving while the simulation runs, see `tick_churn`
+	pub churn: Option<Churn>,
+	/// Nodes moving around while the simulation runs
+	pub mobility: Option<Mobility>,
+	/// Simulation time each node's clock has caught up to
+	clocks: BTreeMap<NetAddr, usize>,
+	/// Time each node is scheduled to be woken up at
+	wakeups: BTreeMap<NetAddr, usize>,
+	/// Number of threads used to tick nodes, 1 or less ticks nodes sequentially
+	#[serde(skip)]
+	pub threads: usize,
+	/// Worker threads ticking nodes when `threads` is above 1, respawned when `threads` changes
+	#[serde(skip)]
+	pool: Option<TickPool<CN>>,
+	/// Checkpoints to rewind to, kept in memory only
+	#[serde(skip)]
+	pub checkpoints: Checkpoints,
+	/// Node ticks being recorded to a capture log
+	#[serde(skip)]
+	pub capture: Option<Capture<CN>>,
+}
+impl<CN: CustomNode, LM: LatencyModel + Default> NetSim<CN, LM> {
+	pub fn new() -> NetSim<CN, LM> { Self::with_latency_model(LM::default()) }
 }
-impl<CN: CustomNode> NetSim<CN> {
-	pub fn new() -> NetSim<CN> {
+impl<CN: CustomNode, LM: LatencyModel> NetSim<CN, LM> {
+	pub fn with_latency_model(latency_model: LM) -> NetSim<CN, LM> {
 		NetSim {
-			nodes: HashMap::new(),
-			router: NetSimRouter::new(FIELD_DIMENSIONS),
-			route_coord_dht: HashMap::new(),
+			seed: 0,
+			nodes: BTreeMap::new(),
+			router: NetSimRouter::new(FIELD_DIMENSIONS, latency_model),
+			next_addr: 0,
+			route_coord_dht: BTreeMap::new(),
+			dht_stats: DhtStats::default(),
+			churn: None,
+			mobility: None,
+			clocks: BTreeMap::new(),
+			wakeups: BTreeMap::new(),
+			threads: 1,
+			pool: None,
+			checkpoints: Checkpoints::default(),
+			capture: None,
 		}
 	}
-	pub fn lease(&self) -> NetAddr { self.nodes.len() as NetAddr }
-	pub fn add_node(&mut self, node: CN, rng: &mut impl Rng) {
-		self.router.add_node(node.net_addr(), rng);
-		self.nodes.insert(node.net_addr(), node);
+	/// Remove every node from the network and stop churn and mobility, keeping the latency model.
+	/// Checkpoints are dropped too, time starts over.
+	pub fn clear(&mut self) {
+		self.checkpoints.clear();
+		self.nodes.clear();
+		self.route_coord_dht.clear();
+		self.dht_stats = DhtStats::default();
+		self.churn = None;
+		self.mobility = None;
+		self.clocks.clear();
+		self.wakeups.clear();
+		self.router.clear();
+		self.next_addr = 0;
+	}
+	/// Current simulation time
+	pub fn ticks(&self) -> usize { self.router.ticks }
+	/// Allocate a NetAddr that no node has ever used
+	pub fn lease(&mut self) -> NetAddr {
+		let net_addr = self.next_addr;
+		self.next_addr += 1;
+		net_addr
+	}
+	/// Change the seed every node's random number generator is derived from, nodes already in the network are re-seeded as if they had just been added
+	pub fn reseed(&mut self, seed: u64) {
+		self.seed = seed;
+		for (&net_addr, node) in self.nodes.iter_mut() { node.seed_rng(SimRng::derive_seed(seed, net_addr as u64)); }
+	}
+	pub fn add_node(&mut self, mut node: CN, rng: &mut impl Rng) -> Result<(), InternetError> {
+		let net_addr = node.net_addr();
+		if self.nodes.contains_key(&net_addr) { return Err(InternetError::AddrInUse { net_addr }) }
+		if !self.router.latency_model.has_room() { return Err(InternetError::LatencyModelFull { net_addr }) }
+		// Nodes may pick their own address, make sure it is never leased to anyone else
+		self.next_addr = self.next_addr.max(net_addr + 1);
+		node.seed_rng(SimRng::derive_seed(self.seed, net_addr as u64));
+		self.router.add_node(net_addr, rng);
+		self.mobility_add_node(net_addr, rng);
+		self.nodes.insert(net_addr, node);
+		self.clocks.insert(net_addr, self.router.ticks);
+		self.wake(net_addr, self.router.ticks);
+		self.capture_node_state(net_addr);
+		Ok(())
+	}
+	/// Remove a node and everything the simulator knows about it: its router state, packets in flight to it and its DHT entry
+	pub fn del_node(&mut self, net_addr: NetAddr) -> Result<CN, InternetError> {
+		let node = self.crash_node(net_addr)?;
+		self.route_coord_dht.remove(&node.unique_id());
+		Ok(node)
+	}
+	/// Remove a node without it withdrawing from the network, its DHT entry stays behind until overwritten
+	pub fn crash_node(&mut self, net_addr: NetAddr) -> Result<CN, InternetError> {
+		let node = self.nodes.remove(&net_addr).ok_or(InternetError::NoNodeError { net_addr })?;
+		self.clocks.remove(&net_addr);
+		self.wakeups.remove(&net_addr);
+		self.router.remove_node(net_addr);
+		self.mobility_remove_node(net_addr);
+		Ok(node)
+	}
+	/// Mutable access to a node, the node is woken up on the next tick in case it was given something to do
+	pub fn node_mut(&mut self, net_addr: NetAddr) -> Result<&mut CN, InternetError> {
+		if !self.nodes.contains_key(&net_addr) { return Err(InternetError::NoNodeError { net_addr }) }
+		self.wake(net_addr, self.router.ticks);
+		Ok(self.nodes.get_mut(&net_addr).unwrap())
 	}
-	pub fn del_node(&mut self, net_addr: NetAddr) { self.nodes.remove(&net_addr); }
-	pub fn node_mut(&mut self, net_addr: NetAddr) -> Result<&mut CN, InternetError> { self.nodes.get_mut(&net_addr).ok_or(InternetError::NoNodeError { net_addr }) }
 	pub fn node(&self, net_addr: NetAddr) -> Result<&CN, InternetError> { self.nodes.get(&net_addr).ok_or(InternetError::NoNodeError { net_addr }) }
+	/// Fraction of live nodes that have a route coordinate in the DHT
+	pub fn dht_coverage(&self) -> f64 {
+		if self.nodes.is_empty() { return 0. }
+		self.nodes.values().filter(|node| self.route_coord_dht.contains_key(&node.unique_id())).count() as f64 / self.nodes.len() as f64
+	}
+	/// Number of DHT entries whose node is no longer in the network
+	pub fn stale_dht_entries(&self) -> usize {
+		let live: std::collections::BTreeSet<CN::CustomNodeUUID> = self.nodes.values().map(CN::unique_id).collect();
+		self.route_coord_dht.keys().filter(|id| !live.contains(id)).count()
+	}
+	/// Schedule a node to be ticked at `time`, unless it is already scheduled earlier
+	fn wake(&mut self, net_addr: NetAddr, time: usize) {
+		if self.wakeups.get(&net_addr).map_or(true, |&scheduled| time < scheduled || scheduled < self.router.ticks) {
+			self.wakeups.insert(net_addr, time);
+			self.router.events.push(time, Event::Wake(net_addr));
+		}
+	}
+	/// Run the simulation forward by `ticks`, jumping straight between times at which something happens
 	pub fn tick(&mut self, ticks: usize, rng: &mut impl Rng) {
-		//let packets_tmp = Vec::new();
-		for _ in 0..ticks {
-			for (&node_net_addr, node) in self.nodes.iter_mut() {
-				// Get Packets going to node
-				let incoming_packets = self.router.tick_node(node_net_addr);
-				// Get packets coming from node
-				let mut outgoing_packets = node.tick(incoming_packets);
-
-				// Make outgoing packets have the correct return address or parse request
-				for packet in &mut outgoing_packets {
-					packet.src_addr = node_net_addr;
-					if let Some(request) = &packet.request {
-						log::debug!("NetAddr({:?}) Requested NetSimRequest::{:?}", node_net_addr, request);
-						packet.request = Some(match *request {
-							NetSimRequest::RouteCoordDHTRead(ref node_id) => {
-								let node_id = node_id.clone();
-								packet.dest_addr = packet.src_addr;
-								let route = self.route_coord_dht.get(&node_id).map(|r|r.clone());
-								NetSimRequest::RouteCoordDHTReadResponse(node_id, route)
-							}
-							NetSimRequest::RouteCoordDHTWrite(ref node_id, route_coord) => {
-								packet.dest_addr = packet.src_addr;
-								let old_route = self.route_coord_dht.insert(node_id.clone(), route_coord);
-								NetSimRequest::RouteCoordDHTWriteResponse( old_route.map(|r|(node_id.clone(), r) ))
-							}
-							NetSimRequest::RandomNodeRequest(unique_id) => {
-								use rand::prelude::IteratorRandom;
-								let id = self.route_coord_dht.iter().choose(rng).map(|(id,_)|id.clone());
-								NetSimRequest::RandomNodeResponse(unique_id, id)
-							}
-							_ => { log::error!("Invalid NetSimRequest variant"); unimplemented!() },
-						});
+		let end = self.router.ticks + ticks;
+		// Nodes move between events, a move takes effect for every packet sent after it
+		while let Some(time) = self.mobility.as_ref().map(|mobility| mobility.next_event().max(self.router.ticks)).filter(|&time| time < end) {
+			self.run_events(time, rng);
+			self.router.ticks = time;
+			self.mobility_step(rng);
+		}
+		self.run_events(end, rng);
+		self.router.ticks = end;
+	}
+	/// Process every event scheduled before `end`
+	fn run_events(&mut self, end: usize, rng: &mut impl Rng) {
+		while let Some(time) = self.router.events.peek_time().filter(|&time| time < end) {
+			self.router.ticks = time;
+
+			// Collect everything that happens at this time, grouped by node in NetAddr order
+			let mut ready: BTreeMap<NetAddr, NetSimPacketVec<CN>> = BTreeMap::new();
+			while let Some(event) = self.router.events.pop_at(time) {
+				self.router.stats.events += 1;
+				match event {
+					Event::Deliver(packet, downlinked) => {
+						if let Some(packet) = self.router.deliver(packet, downlinked) {
+							ready.entry(packet.dest_addr).or_default().push(packet);
+						}
+					}
+					Event::Wake(net_addr) => {
+						// Ignore wakeups that were superseded by an earlier one
+						if self.wakeups.get(&net_addr) == Some(&time) {
+							self.wakeups.remove(&net_addr);
+							ready.entry(net_addr).or_default();
+						}
 					}
 				}
-				// Send packets through the router
-				self.router.add_packets(outgoing_packets, rng);
-				if let Some(rn) = self.router.node_map.get(&node_net_addr) {
-					let cheat_coord = rn.position.clone().map(|s|s.floor() as i64);
-					node.set_deus_ex_data( Some(cheat_coord) ) }
+			}
+
+			self.tick_ready(ready, rng);
+		}
+	}
+	/// Tick every node that has something to do, then route everything they sent in NetAddr order
+	fn tick_ready(&mut self, ready: BTreeMap<NetAddr, NetSimPacketVec<CN>>, rng: &mut impl Rng) {
+		let mut captured = self.capture_received(&ready);
+		let sent = if self.threads > 1 && ready.len() >= PARALLEL_THRESHOLD {
+			self.tick_nodes_parallel(ready)
+		} else { self.tick_nodes(ready) };
+		if let Some(captured) = &mut captured { Self::capture_sent(captured, &sent); }
+		for (net_addr, outgoing) in sent {
+			self.route_outgoing(net_addr, outgoing, rng);
+		}
+		if let Some(captured) = captured { self.capture_ticks(captured); }
+	}
+	/// Tick a node at the current time without waiting for its next wakeup, so that an action it was just given is carried out right away.
+	/// Its clock gets a tick ahead if it is ticked again at the same time, meant for nodes that are removed right after.
+	fn tick_node_now(&mut self, net_addr: NetAddr, rng: &mut impl Rng) -> Result<(), InternetError> {
+		if !self.nodes.contains_key(&net_addr) { return Err(InternetError::NoNodeError { net_addr }) }
+		self.tick_ready(BTreeMap::from([(net_addr, NetSimPacketVec::new())]), rng);
+		Ok(())
+	}
+	/// Catch a node's clock up on the ticks it slept through, returns the number of ticks to skip
+	fn sync_clock(&mut self, net_addr: NetAddr) -> usize {
+		let time = self.router.ticks;
+		let clock = self.clocks.entry(net_addr).or_insert(time);
+		let skipped = time.saturating_sub(*clock);
+		*clock = time + 1;
+		skipped
+	}
+	fn tick_nodes(&mut self, ready: BTreeMap<NetAddr, NetSimPacketVec<CN>>) -> Vec<(NetAddr, NetSimPacketVec<CN>)> {
+		let mut sent = Vec::with_capacity(ready.len());
+		for (net_addr, incoming) in ready {
+			if !self.nodes.contains_key(&net_addr) {
+				if !incoming.is_empty() { log::trace!("Dropped {} packets addressed to NetAddr({}), there is no node there", incoming.len(), net_addr); }
+				self.router.stats.dead_dropped += incoming.len();
+				continue;
+			}
+			let skipped = self.sync_clock(net_addr);
+			let node = self.nodes.get_mut(&net_addr).unwrap();
+			if skipped > 0 { node.skip_ticks(skipped); }
+			sent.push((net_addr, node.tick(incoming)));
+		}
+		sent
+	}
+	/// Same as `tick_nodes`, but nodes are split into contiguous NetAddr ranges that are ticked on the worker pool's threads.
+	/// Nodes only touch their own state while ticking, so the result is identical to ticking sequentially.
+	fn tick_nodes_parallel(&mut self, ready: BTreeMap<NetAddr, NetSimPacketVec<CN>>) -> Vec<(NetAddr, NetSimPacketVec<CN>)> {
+		// Take ready nodes out of the node map so each worker can own its share
+		let mut jobs = Vec::with_capacity(ready.len());
+		for (net_addr, incoming) in ready {
+			if !self.nodes.contains_key(&net_addr) {
+				if !incoming.is_empty() { log::trace!("Dropped {} packets addressed to NetAddr({}), there is no node there", incoming.len(), net_addr); }
+				self.router.stats.dead_dropped += incoming.len();
+				continue;
+			}
+			let skipped = self.sync_clock(net_addr);
+			let node = self.nodes.remove(&net_addr).unwrap();
+			jobs.push((net_addr, node, incoming, skipped));
+		}
+
+		if self.pool.as_ref().map_or(true, |pool| pool.threads() != self.threads) {
+			self.pool = None;
+			self.pool = Some(TickPool::new(self.threads));
+		}
+		let ticked = self.pool.as_ref().unwrap().tick(jobs);
+
+		// Put nodes back and return outgoing packets in NetAddr order
+		ticked.into_iter().map(|(net_addr, node, outgoing)| {
+			self.nodes.insert(net_addr, node);
+			(net_addr, outgoing)
+		}).collect()
+	}
+	/// Make outgoing packets have the correct return address or parse request, then send them through the router
+	fn route_outgoing(&mut self, node_net_addr: NetAddr, mut outgoing_packets: NetSimPacketVec<CN>, rng: &mut impl Rng) {
+		for packet in &mut outgoing_packets {
+			packet.src_addr = node_net_addr;
+			if let Some(request) = &packet.request {
+				log::debug!("NetAddr({:?}) Requested NetSimRequest::{:?}", node_net_addr, request);
+				packet.request = Some(match *request {
+					NetSimRequest::RouteCoordDHTRead(ref node_id) => {
+						let node_id = node_id.clone();
+						packet.dest_addr = packet.src_addr;
+						let route = self.route_coord_dht.get(&node_id).map(|r|r.clone());
+						self.dht_stats.reads += 1;
+						if route.is_some() { self.dht_stats.hits += 1; }
+						NetSimRequest::RouteCoordDHTReadResponse(node_id, route)
+					}
+					NetSimRequest::RouteCoordDHTWrite(ref node_id, route_coord) => {
+						packet.dest_addr = packet.src_addr;
+						let old_route = self.route_coord_dht.insert(node_id.clone(), route_coord);
+						self.dht_stats.writes += 1;
+						NetSimRequest::RouteCoordDHTWriteResponse( old_route.map(|r|(node_id.clone(), r) ))
+					}
+					NetSimRequest::RouteCoordDHTRemove(ref node_id) => {
+						packet.dest_addr = packet.src_addr;
+						let old_route = self.route_coord_dht.remove(node_id);
+						if old_route.is_some() { self.dht_stats.removes += 1; }
+						NetSimRequest::RouteCoordDHTRemoveResponse( old_route.map(|r|(node_id.clone(), r) ))
+					}
+					NetSimRequest::RandomNodeRequest(unique_id) => {
+						use rand::prelude::IteratorRandom;
+						let id = self.route_coord_dht.iter().choose(rng).map(|(id,_)|id.clone());
+						NetSimRequest::RandomNodeResponse(unique_id, id)
+					}
+					_ => { log::error!("Invalid NetSimRequest variant"); unimplemented!() },
+				});
+			}
+		}
+		// Send packets through the router
+		self.router.add_packets(outgoing_packets, rng);
+		if let Some(node) = self.nodes.get_mut(&node_net_addr) {
+			if let Some(rn) = self.router.node_map.get(&node_net_addr) {
+				let cheat_coord = rn.latency_position().map(|s|s.floor() as i64);
+				node.set_deus_ex_data( Some(cheat_coord) ) }
+			if let Some(wakeup) = node.next_wakeup() {
+				let time = self.router.ticks + wakeup.max(1);
+				self.wake(node_net_addr, time);
 			}
 		}
 	}
 }
 
+impl<CN: CustomNode> NetSim<CN, LatencyType> {
+	/// Create an empty network whose latencies are read from a pairwise RTT matrix file, see `MatrixLatency::from_reader`
+	pub fn from_latency_matrix(path: impl AsRef<std::path::Path>, ticks_per_unit: f64) -> Result<Self, InternetError> {
+		let file = std::io::BufReader::new(std::fs::File::open(path)?);
+		let matrix = MatrixLatency::from_reader(file, ticks_per_unit)?;
+		Ok(Self::with_latency_model(LatencyType::Matrix(matrix)))
+	}
+}
+
 use crate::plot::GraphPlottable;
 impl GraphPlottable for NetSim<Node> {
 	fn gen_graph(&self) -> Graph<(String, Point2<i32>), RGBColor> {
@@ -138,7 +472,7 @@ impl GraphPlottable for NetSim<Node> {
 			Element::Node {
 				weight: (
 					net_addr.to_string(),
-					lc.position.map(|i|i as i32),
+					lc.latency_position().map(|i|i as i32),
 				)
 			}
 		}).collect();
@@ -154,12 +488,13 @@ impl GraphPlottable for NetSim<Node> {
 						(d.net_addr, color)
 					})
 				}).flatten()
-			}).map(move |(remote_net_addr, color)|{
-				Element::Edge {
-					source: node_idx_map[&net_addr],
-					target: node_idx_map[&remote_net_addr],
+			}).filter_map(move |(remote_net_addr, color)|{
+				// Sessions to deleted nodes linger until they expire, their endpoint is no longer in the graph
+				Some(Element::Edge {
+					source: *node_idx_map.get(net_addr)?,
+					target: *node_idx_map.get(&remote_net_addr)?,
 					weight: color,
-				}
+				})
 			})
 		}).flatten();
 		let graph = Graph::from_elements(nodes.into_iter().chain(edges));
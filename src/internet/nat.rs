@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+
+use super::NetAddr;
+
+/// Default number of ticks a NAT mapping stays open after the last outgoing packet through it
+pub const MAPPING_TIMEOUT: usize = 30000;
+
+/// How a NAT decides which inbound packets to let through.
+/// Ports aren't simulated, so the types are told apart by which remotes know a working external endpoint for the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NatType {
+	/// One external endpoint for every destination, anyone can reach it while any mapping is open
+	FullCone,
+	/// One external endpoint for every destination, only remotes the node has sent to may use it
+	Restricted,
+	/// A new external endpoint per destination. A remote can only answer once a packet through its mapping has reached it,
+	/// so endpoints learned from third parties and simultaneous opens between two symmetric NATs don't work.
+	Symmetric,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Mapping {
+	expires: usize,
+	/// A packet through this mapping reached the remote, so the remote knows its external endpoint
+	confirmed: bool,
+}
+
+/// NAT in front of a node, tracks the mappings opened by outgoing packets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Nat {
+	pub nat_type: NatType,
+	/// Ticks a mapping stays open after the last outgoing packet through it
+	pub timeout: usize,
+	/// Open mappings by remote NetAddr
+	mappings: BTreeMap<NetAddr, Mapping>,
+}
+impl Nat {
+	pub fn new(nat_type: NatType, timeout: usize) -> Self { Self { nat_type, timeout, mappings: BTreeMap::new() } }
+	/// Open or refresh the mapping for a packet sent to `remote`
+	pub fn outbound(&mut self, remote: NetAddr, now: usize) {
+		self.mappings.retain(|_, mapping| mapping.expires > now);
+		let mapping = self.mappings.entry(remote).or_insert(Mapping { expires: now, confirmed: false });
+		mapping.expires = now + self.timeout;
+	}
+	/// A packet sent to `remote` arrived there
+	pub fn confirm(&mut self, remote: NetAddr, now: usize) {
+		if let Some(mapping) = self.mappings.get_mut(&remote).filter(|mapping| mapping.expires > now) { mapping.confirmed = true; }
+	}
+	/// Whether a packet arriving from `remote` is let through to the node
+	pub fn allows(&self, remote: NetAddr, now: usize) -> bool {
+		let open = |mapping: &Mapping| mapping.expires > now;
+		match self.nat_type {
+			NatType::FullCone => self.mappings.values().any(open),
+			NatType::Restricted => self.mappings.get(&remote).map_or(false, open),
+			NatType::Symmetric => self.mappings.get(&remote).map_or(false, |mapping| open(mapping) && mapping.confirmed),
+		}
+	}
+	/// Number of mappings that haven't timed out
+	pub fn open_mappings(&self, now: usize) -> usize { self.mappings.values().filter(|mapping| mapping.expires > now).count() }
+	pub fn clear(&mut self) { self.mappings.clear(); }
+}
+
+/// Puts a random fraction of the nodes added to the router behind a NAT
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct NatProfile {
+	/// Fraction of nodes behind a NAT
+	pub fraction: f64,
+	pub nat_type: NatType,
+	pub timeout: usize,
+}
+impl NatProfile {
+	pub fn new(fraction: f64, nat_type: NatType, timeout: usize) -> Self { Self { fraction, nat_type, timeout } }
+	/// Roll whether a new node sits behind a NAT
+	pub fn roll(&self, rng: &mut impl rand::Rng) -> Option<Nat> {
+		(self.fraction > 0. && rng.gen_bool(self.fraction.min(1.))).then(|| Nat::new(self.nat_type, self.timeout))
+	}
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeSet;
+
+use nalgebra::Point2;
+use rand::Rng;
+
+use super::{NetAddr, NetSim};
+use super::router::RouterNode;
+use super::latency::LatencyModel;
+use crate::node::Node;
+
+/// Which links a `Fault` cuts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FaultScope {
+	/// Cut every link crossing the vertical line at `x`
+	Split { x: f32 },
+	/// Cut every link between nodes inside the rectangle and nodes outside it
+	Region { min: Point2<f32>, max: Point2<f32> },
+	/// Cut every link between these nodes and the rest of the network
+	Isolate(BTreeSet<NetAddr>),
+	/// Cut every link between the two groups, links within a group and to other nodes still work
+	Between(BTreeSet<NetAddr>, BTreeSet<NetAddr>),
+	/// Cut specific links, in both directions
+	Links(BTreeSet<(NetAddr, NetAddr)>),
+}
+impl FaultScope {
+	/// Whether packets between the two nodes are cut, ignoring when the fault is active
+	pub fn cuts(&self, src: &RouterNode, dest: &RouterNode) -> bool {
+		match self {
+			FaultScope::Split { x } => (src.position.x < *x) != (dest.position.x < *x),
+			FaultScope::Region { min, max } => {
+				let inside = |node: &RouterNode| (min.x..max.x).contains(&node.position.x) && (min.y..max.y).contains(&node.position.y);
+				inside(src) != inside(dest)
+			}
+			FaultScope::Isolate(addrs) => addrs.contains(&src.uuid) != addrs.contains(&dest.uuid),
+			FaultScope::Between(a, b) => (a.contains(&src.uuid) && b.contains(&dest.uuid)) || (b.contains(&src.uuid) && a.contains(&dest.uuid)),
+			FaultScope::Links(links) => links.contains(&(src.uuid, dest.uuid)) || links.contains(&(dest.uuid, src.uuid)),
+		}
+	}
+}
+
+/// Connectivity failure that the router enforces during a time window
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fault {
+	pub scope: FaultScope,
+	/// First tick the fault is active
+	pub start: usize,
+	/// Tick the fault heals at
+	pub end: usize,
+}
+impl Fault {
+	pub fn new(scope: FaultScope, start: usize, end: usize) -> Self { Self { scope, start, end } }
+	pub fn is_active(&self, time: usize) -> bool { (self.start..self.end).contains(&time) }
+}
+
+/// How much of the overlay spans a fault's cut, taken while measuring recovery
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PartitionSample {
+	pub time: usize,
+	/// Whether the fault was active at `time`
+	pub active: bool,
+	/// Peer list entries over all nodes
+	pub peers: usize,
+	/// Peer list entries that cross the cut
+	pub cut_peers: usize,
+	/// `direct_sorted` entries over all nodes
+	pub directs: usize,
+	/// `direct_sorted` entries that cross the cut
+	pub cut_directs: usize,
+	/// Fraction of live nodes that have a route coordinate in the DHT
+	pub dht_coverage: f64,
+	/// Greedy routing success between random node pairs on opposite sides of the cut
+	pub cut_routing_success: f64,
+}
+
+impl<LM: LatencyModel> NetSim<Node, LM> {
+	/// Count how much of the overlay crosses `scope`, recovery shows up as cut peers and directs coming back after the fault ends
+	pub fn partition_sample(&self, fault: &Fault, routing_samples: usize, rng: &mut impl Rng) -> PartitionSample {
+		let router_nodes = &self.router.node_map;
+		let crosses = |a: NetAddr, b: NetAddr| match (router_nodes.get(&a), router_nodes.get(&b)) {
+			(Some(a), Some(b)) => fault.scope.cuts(a, b),
+			_ => false,
+		};
+		let mut sample = PartitionSample { time: self.ticks(), active: fault.is_active(self.ticks()), dht_coverage: self.dht_coverage(), ..Default::default() };
+		for (&net_addr, node) in &self.nodes {
+			let remote_addr = |node_idx| Some(node.remote(node_idx).ok()?.session().ok()?.direct().ok()?.net_addr);
+			for (&node_idx, _) in node.peer_list.iter() {
+				sample.peers += 1;
+				if remote_addr(node_idx).map_or(false, |addr| crosses(net_addr, addr)) { sample.cut_peers += 1; }
+			}
+			for &(_, node_idx) in node.direct_sorted.iter() {
+				sample.directs += 1;
+				if remote_addr(node_idx).map_or(false, |addr| crosses(net_addr, addr)) { sample.cut_directs += 1; }
+			}
+		}
+
+		let routable: Vec<NetAddr> = self.nodes.iter().filter(|(_, node)| node.route_coord.is_some()).map(|(&net_addr, _)| net_addr).collect();
+		let pairs: Vec<(NetAddr, NetAddr)> = (0..routing_samples * 10).filter_map(|_| {
+			if routable.len() < 2 { return None }
+			let (src, dest) = (routable[rng.gen_range(0..routable.len())], routable[rng.gen_range(0..routable.len())]);
+			crosses(src, dest).then(|| (src, dest))
+		}).take(routing_samples).collect();
+		if !pairs.is_empty() {
+			sample.cut_routing_success = pairs.iter().filter(|&&(src, dest)| self.greedy_route(src, dest)).count() as f64 / pairs.len() as f64;
+		}
+		sample
+	}
+	/// Tick the network for `ticks`, sampling how the overlay spans `fault` every `interval` ticks
+	pub fn measure_partition(&mut self, fault: &Fault, ticks: usize, interval: usize, routing_samples: usize, rng: &mut impl Rng) -> Result<Vec<PartitionSample>, super::InternetError> {
+		let interval = interval.max(1);
+		let mut samples = vec![self.partition_sample(fault, routing_samples, rng)];
+		let end = self.ticks() + ticks;
+		while self.ticks() < end {
+			self.tick_churn(interval.min(end - self.ticks()), rng)?;
+			samples.push(self.partition_sample(fault, routing_samples, rng));
+		}
+		Ok(samples)
+	}
+}
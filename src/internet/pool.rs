@@ -0,0 +1,125 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use super::{CustomNode, NetAddr, NetSimPacketVec};
+
+/// A node taken out of the network to be ticked, with the packets it received and the number of ticks it slept through
+pub(super) type Job<CN> = (NetAddr, CN, NetSimPacketVec<CN>, usize);
+/// A ticked node with the packets it sent
+pub(super) type Ticked<CN> = (NetAddr, CN, NetSimPacketVec<CN>);
+/// Jobs given to a worker, with the chunk's index
+type Chunk<CN> = (usize, Vec<Job<CN>>);
+/// Index of a chunk of jobs, and either its ticked nodes or the panic of the node that failed to tick
+type ChunkResult<CN> = (usize, thread::Result<Vec<Ticked<CN>>>);
+
+/// Worker threads that tick nodes, kept alive between event times so that ticking in parallel doesn't spawn threads every time
+pub(super) struct TickPool<CN: CustomNode> {
+	workers: Vec<(Sender<Chunk<CN>>, JoinHandle<()>)>,
+	results: Receiver<ChunkResult<CN>>,
+}
+impl<CN: CustomNode> TickPool<CN> {
+	pub fn new(threads: usize) -> Self {
+		let (result_sender, results) = channel();
+		let workers = (0..threads.max(1)).map(|worker| {
+			let (job_sender, jobs) = channel::<Chunk<CN>>();
+			let result_sender = result_sender.clone();
+			let handle = thread::Builder::new().name(format!("node-tick-{}", worker)).spawn(move || {
+				// Runs until the pool is dropped and the job channel closes
+				for (chunk, jobs) in jobs {
+					let ticked = panic::catch_unwind(AssertUnwindSafe(|| {
+						jobs.into_iter().map(|(net_addr, mut node, incoming, skipped)| {
+							if skipped > 0 { node.skip_ticks(skipped); }
+							let outgoing = node.tick(incoming);
+							(net_addr, node, outgoing)
+						}).collect()
+					}));
+					if result_sender.send((chunk, ticked)).is_err() { break }
+				}
+			}).expect("failed to spawn node tick thread");
+			(job_sender, handle)
+		}).collect();
+		Self { workers, results }
+	}
+	pub fn threads(&self) -> usize { self.workers.len() }
+	/// Split `jobs` into contiguous chunks, one per worker, and tick them. Ticked nodes come back in the same order as `jobs`.
+	/// A panic while ticking a node is resumed on the calling thread once every worker is done, the pool can be used again afterwards.
+	pub fn tick(&self, mut jobs: Vec<Job<CN>>) -> Vec<Ticked<CN>> {
+		let chunk_size = jobs.len().div_ceil(self.threads()).max(1);
+		let mut chunks = 0;
+		while !jobs.is_empty() {
+			let rest = jobs.split_off(chunk_size.min(jobs.len()));
+			self.workers[chunks].0.send((chunks, jobs)).expect("node tick thread exited");
+			jobs = rest;
+			chunks += 1;
+		}
+		// Wait for every chunk before resuming a panic, so no result is left in the channel for the next call to pick up
+		let mut results: Vec<ChunkResult<CN>> = (0..chunks).map(|_| self.results.recv().expect("node tick thread exited")).collect();
+		results.sort_unstable_by_key(|&(chunk, _)| chunk);
+		let mut ticked = Vec::new();
+		for (_, result) in results {
+			match result {
+				Ok(chunk) => ticked.extend(chunk),
+				Err(panic) => panic::resume_unwind(panic),
+			}
+		}
+		ticked
+	}
+}
+impl<CN: CustomNode> Drop for TickPool<CN> {
+	fn drop(&mut self) {
+		for (job_sender, handle) in self.workers.drain(..) {
+			drop(job_sender);
+			let _ = handle.join();
+		}
+	}
+}
+impl<CN: CustomNode> std::fmt::Debug for TickPool<CN> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TickPool").field("threads", &self.threads()).finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::any::Any;
+
+	use super::*;
+	use crate::node::RouteCoord;
+
+	/// Counts its ticks, panics when ticked at NetAddr 3
+	#[derive(Debug, Default)]
+	struct Counter { net_addr: NetAddr, ticks: usize }
+	impl CustomNode for Counter {
+		type CustomNodeAction = ();
+		type CustomNodeUUID = NetAddr;
+		fn net_addr(&self) -> NetAddr { self.net_addr }
+		fn unique_id(&self) -> NetAddr { self.net_addr }
+		fn tick(&mut self, _incoming: NetSimPacketVec<Self>) -> NetSimPacketVec<Self> {
+			assert_ne!(self.net_addr, 3, "node 3 always fails");
+			self.ticks += 1;
+			NetSimPacketVec::new()
+		}
+		fn skip_ticks(&mut self, ticks: usize) { self.ticks += ticks; }
+		fn next_wakeup(&self) -> Option<usize> { None }
+		fn action(&mut self, _action: ()) {}
+		fn as_any(&self) -> &dyn Any { self }
+		fn seed_rng(&mut self, _seed: u64) {}
+		fn set_deus_ex_data(&mut self, _data: Option<RouteCoord>) {}
+		fn route_coord(&self) -> Option<RouteCoord> { None }
+	}
+	fn jobs(net_addrs: impl Iterator<Item = NetAddr>) -> Vec<Job<Counter>> {
+		net_addrs.map(|net_addr| (net_addr, Counter { net_addr, ticks: 0 }, NetSimPacketVec::new(), 0)).collect()
+	}
+
+	#[test]
+	fn panic_leaves_nothing_behind() {
+		let pool = TickPool::<Counter>::new(4);
+		for _ in 0..20 {
+			assert!(panic::catch_unwind(AssertUnwindSafe(|| pool.tick(jobs(0..16)))).is_err());
+			// Only this call's nodes come back, in order
+			let ticked = pool.tick(jobs(4..12));
+			assert_eq!(ticked.iter().map(|(net_addr, node, _)| (*net_addr, node.ticks)).collect::<Vec<_>>(), (4..12).map(|net_addr| (net_addr, 1)).collect::<Vec<_>>());
+		}
+	}
+}
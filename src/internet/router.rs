@@ -1,87 +1,319 @@
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::ops::Range;
 
-const VARIANCE: isize = 2;
 use nalgebra::Point2;
 use rand::Rng;
 
 use super::{CustomNode, NetAddr, NetSimPacket, NetSimPacketVec};
+use super::scheduler::{Event, EventQueue};
+use super::latency::LatencyModel;
+use super::conditions::{LinkConditions, Bandwidth, LinkQueue};
+use super::partition::Fault;
+use super::nat::{Nat, NatProfile};
+use super::topology::Attachment;
+use super::congestion::Congestion;
+use super::access::{AccessLink, ClassMix};
 
-/* // Network Sim structuring calculators
-pub trait LatencyCalculator: Default {
-	fn new(rng: &mut impl rand::Rng) -> Self;
-	fn generate(&self, other: &Self, rng: &mut impl rand::Rng) -> usize;
-} */
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RouterNode {
 	pub uuid: NetAddr,
-	pub variance: isize,
+	/// Where the node sits on the field
 	pub position: Point2<f32>,
-	pub distance_cache: HashMap<NetAddr, isize>,
+	/// Router the node is plugged into, set by `TopologyLatency`
+	pub attachment: Option<Attachment>,
+	/// Overrides the network-wide link conditions for packets sent or received by this node
+	pub conditions: Option<LinkConditions>,
+	/// Overrides link conditions for packets sent from this node to specific destinations
+	pub link_conditions: BTreeMap<NetAddr, LinkConditions>,
+	/// Overrides the network-wide bandwidth of this node
+	pub bandwidth: Option<Bandwidth>,
+	pub uplink: LinkQueue,
+	pub downlink: LinkQueue,
+	/// NAT the node sits behind, None if it is publicly reachable
+	pub nat: Option<Nat>,
+	/// Last-mile link to the internet, None adds no delay, jitter or loss
+	pub access: Option<AccessLink>,
 }
 impl RouterNode {
-	fn random(uuid: NetAddr, range: &(Range<i32>, Range<i32>), rng: &mut impl Rng) -> Self {
+	/// Where the node sits as far as latency is concerned: at its attachment router under topology-based latency models, otherwise at its position
+	pub fn latency_position(&self) -> Point2<f32> { self.attachment.map_or(self.position, |attachment| attachment.position) }
+	pub(super) fn random(uuid: NetAddr, range: &(Range<i32>, Range<i32>), rng: &mut impl Rng) -> Self {
 		// let radius = AREA/2;
 		Self {
 			uuid,
-			variance: VARIANCE,
 			position: Point2::new(rng.gen_range(range.0.clone()), rng.gen_range(range.1.clone())).map(|d|d as f32),
-			distance_cache: HashMap::new(),
+			attachment: None,
+			conditions: None,
+			link_conditions: BTreeMap::new(),
+			bandwidth: None,
+			uplink: LinkQueue::default(),
+			downlink: LinkQueue::default(),
+			nat: None,
+			access: None,
 		}
 	}
-	fn generate(&mut self, other_uuid: NetAddr, other_position: Point2<f32>, rng: &mut impl Rng) -> isize {
-		let dist = *self.distance_cache.entry(other_uuid).or_insert(nalgebra::distance(&self.position, &other_position) as isize);
-		dist as isize + rng.gen_range(-self.variance..self.variance)
-	}
+}
+
+/// Counts what happened to packets passing through the router
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RouterStats {
+	pub sent: usize,
+	/// Total bytes of packet data sent
+	pub bytes_sent: usize,
+	pub delivered: usize,
+	pub lost: usize,
+	pub duplicated: usize,
+	pub reordered: usize,
+	/// Packets dropped because a node's uplink or downlink buffer was full
+	pub queue_dropped: usize,
+	/// Sum of ticks packets spent waiting in uplink and downlink queues
+	pub queue_delay: usize,
+	/// Packets dropped because a fault cut the link between sender and receiver
+	pub partitioned: usize,
+	/// Unsolicited packets dropped by the destination's NAT
+	pub nat_dropped: usize,
+	/// Packets dropped because there is no node at their destination address (it never existed or was removed while they were in flight)
+	pub dead_dropped: usize,
+	/// Number of scheduler events processed
+	pub events: usize,
 }
 
 /// Internet router
 #[derive(Debug, Serialize, Deserialize)]
-pub struct NetSimRouter<CN: CustomNode> {
+pub struct NetSimRouter<CN: CustomNode, LM: LatencyModel> {
 	pub field_dimensions: (Range<i32>, Range<i32>),
-	/// Map linking Node pairs to speed between them (supports differing 2-way speeds)
-	pub node_map: HashMap<NetAddr, RouterNode>,
-	/// Map linking destination `Node`s to inbound packets
-	#[serde(skip)]
-	pub packet_map: HashMap<NetAddr, Vec<(NetSimPacket<CN>, isize)>>,
+	/// Decides how long packets take to travel between nodes
+	pub latency_model: LM,
+	/// Network-wide loss, duplication and reordering probabilities
+	pub conditions: LinkConditions,
+	/// Network-wide bandwidth of every node, None is infinitely fast
+	pub bandwidth: Option<Bandwidth>,
+	/// Partitions and link failures, each cuts packets during its time window
+	pub faults: Vec<Fault>,
+	/// Decides which nodes added to the router sit behind a NAT, None puts every node on the public internet
+	pub nat_profile: Option<NatProfile>,
+	/// Time-varying load stretching base latencies, None keeps them constant
+	pub congestion: Option<Congestion>,
+	/// Decides which class of access link nodes added to the router get, None gives them none
+	pub access_mix: Option<ClassMix>,
+	pub stats: RouterStats,
+	/// Current simulation time
+	pub ticks: usize,
+	/// Per-node router state, storage is linear in the number of nodes
+	pub node_map: BTreeMap<NetAddr, RouterNode>,
+	/// Time-ordered queue of packet deliveries and node wakeups, saved with the rest of the router so in-flight packets survive a snapshot
+	pub events: EventQueue<CN>,
 }
-impl<CN: CustomNode> NetSimRouter<CN> {
-	pub fn new(field_dimensions: (Range<i32>, Range<i32>)) -> Self {
+impl<CN: CustomNode, LM: LatencyModel> NetSimRouter<CN, LM> {
+	pub fn new(field_dimensions: (Range<i32>, Range<i32>), latency_model: LM) -> Self {
 		Self {
 			field_dimensions,
+			latency_model,
+			conditions: Default::default(),
+			bandwidth: None,
+			faults: Vec::new(),
+			nat_profile: None,
+			congestion: None,
+			access_mix: None,
+			stats: Default::default(),
+			ticks: 0,
 			node_map: Default::default(),
-			packet_map: Default::default(),
+			events: Default::default(),
 		}
 	}
 	pub fn add_node(&mut self, net_addr: NetAddr, rng: &mut impl Rng) {
-		self.node_map.entry(net_addr).or_insert(RouterNode::random(net_addr, &self.field_dimensions, rng));
+		if self.node_map.contains_key(&net_addr) { return }
+		let mut router_node = RouterNode::random(net_addr, &self.field_dimensions, rng);
+		router_node.nat = self.nat_profile.and_then(|profile| profile.roll(rng));
+		router_node.access = self.access_mix.and_then(|mix| mix.roll(rng));
+		self.latency_model.add_node(&mut router_node, rng);
+		self.node_map.insert(net_addr, router_node);
+	}
+	/// Move a node to `position`, returns false if the node isn't in the router.
+	/// Latencies are computed per packet, so packets sent after the move see the new position.
+	pub fn move_node(&mut self, net_addr: NetAddr, position: Point2<f32>, rng: &mut impl Rng) -> bool {
+		let router_node = match self.node_map.get_mut(&net_addr) { Some(router_node) => router_node, None => return false };
+		router_node.position = position;
+		self.latency_model.move_node(router_node, rng);
+		true
+	}
+	/// Remove a node along with every packet still in flight to it and every link override pointing at it
+	pub fn remove_node(&mut self, net_addr: NetAddr) -> Option<RouterNode> {
+		let router_node = self.node_map.remove(&net_addr)?;
+		self.latency_model.remove_node(net_addr);
+		for other in self.node_map.values_mut() { other.link_conditions.remove(&net_addr); }
+		let mut dropped = 0;
+		self.events.retain(|event| match event {
+			Event::Deliver(packet, _) if packet.dest_addr == net_addr => { dropped += 1; false }
+			Event::Wake(addr) => *addr != net_addr,
+			_ => true,
+		});
+		log::debug!("Router: removed NetAddr({}), dropped {} in-flight packets", net_addr, dropped);
+		self.stats.dead_dropped += dropped;
+		Some(router_node)
+	}
+	/// Remove all nodes and in-flight packets
+	pub fn clear(&mut self) {
+		self.node_map.clear();
+		self.events.clear();
+		self.faults.clear();
+		if let Some(congestion) = &mut self.congestion { congestion.reset(); }
+		self.latency_model.reset();
+		self.stats = Default::default();
+		self.ticks = 0;
+	}
+	/// Replace the latency model, re-registering every node
+	pub fn set_latency_model(&mut self, latency_model: LM, rng: &mut impl Rng) {
+		self.latency_model = latency_model;
+		for router_node in self.node_map.values_mut() {
+			// Attachments belong to the previous model
+			router_node.attachment = None;
+			self.latency_model.add_node(router_node, rng);
+		}
+	}
+	/// Jitter-free latency from `src_addr` to `dest_addr` at the current time, including congestion and both access links. None if either node isn't in the router.
+	/// Base distances are computed on the fly rather than cached, so memory stays linear in the number of nodes.
+	pub fn expected_latency(&self, src_addr: NetAddr, dest_addr: NetAddr) -> Option<isize> {
+		let (src, dest) = (self.node_map.get(&src_addr)?, self.node_map.get(&dest_addr)?);
+		let distance = self.latency_model.distance(src, dest);
+		let distance = match &self.congestion {
+			Some(congestion) => (distance as f64 * congestion.multiplier(src, dest, self.ticks, &self.field_dimensions)).round() as isize,
+			None => distance,
+		};
+		Some(distance + src.access.map_or(0, |link| link.delay) + dest.access.map_or(0, |link| link.delay))
+	}
+	/// Generate latency for a packet sent from `src_addr` to `dest_addr`, None if either node isn't in the router
+	pub fn latency(&self, src_addr: NetAddr, dest_addr: NetAddr, rng: &mut impl Rng) -> Option<isize> {
+		let distance = self.expected_latency(src_addr, dest_addr)?;
+		let access_jitter: isize = [src_addr, dest_addr].iter()
+			.filter_map(|net_addr| self.node_map.get(net_addr)?.access)
+			.map(|link| link.roll_jitter(rng)).sum();
+		Some(distance + self.latency_model.jitter(distance, rng) + access_jitter)
+	}
+	/// Whether a packet is lost on the sender's or receiver's access link
+	fn roll_access_loss(&self, src_addr: NetAddr, dest_addr: NetAddr, rng: &mut impl Rng) -> bool {
+		[src_addr, dest_addr].iter().filter_map(|net_addr| self.node_map.get(net_addr)?.access).any(|link| link.roll_loss(rng))
+	}
+	/// Link conditions for packets from `src_addr` to `dest_addr`, most specific override wins: link, source node, destination node, network
+	pub fn link_conditions(&self, src_addr: NetAddr, dest_addr: NetAddr) -> LinkConditions {
+		let src = self.node_map.get(&src_addr);
+		let dest = self.node_map.get(&dest_addr);
+		src.and_then(|n| n.link_conditions.get(&dest_addr).cloned())
+			.or_else(|| src.and_then(|n| n.conditions))
+			.or_else(|| dest.and_then(|n| n.conditions))
+			.unwrap_or(self.conditions)
+	}
+	/// Whether an active fault currently cuts packets from `src_addr` to `dest_addr`
+	pub fn is_cut(&self, src_addr: NetAddr, dest_addr: NetAddr) -> bool {
+		let mut active = self.faults.iter().filter(|fault| fault.is_active(self.ticks));
+		match (self.node_map.get(&src_addr), self.node_map.get(&dest_addr)) {
+			(Some(src), Some(dest)) => active.any(|fault| fault.scope.cuts(src, dest)),
+			_ => false,
+		}
+	}
+	/// Whether the NAT in front of `dest_addr` (if any) lets a packet from `src_addr` through
+	pub fn nat_allows(&self, src_addr: NetAddr, dest_addr: NetAddr) -> bool {
+		self.node_map.get(&dest_addr).and_then(|n| n.nat.as_ref()).map_or(true, |nat| nat.allows(src_addr, self.ticks))
+	}
+	pub fn node_bandwidth(&self, net_addr: NetAddr) -> Option<Bandwidth> {
+		self.node_map.get(&net_addr).and_then(|n| n.bandwidth).or(self.bandwidth)
+	}
+	/// Start the congestion episodes due by now
+	pub fn advance_congestion(&mut self, rng: &mut impl Rng) {
+		if let Some(congestion) = &mut self.congestion { congestion.advance(self.ticks, &self.field_dimensions, rng); }
 	}
 	pub fn add_packets(&mut self, packets: NetSimPacketVec<CN>, rng: &mut impl Rng) {
+		self.advance_congestion(rng);
 		for packet in packets {
-			let dest = self.node_map.entry(packet.dest_addr).or_insert(RouterNode::random(packet.dest_addr, &self.field_dimensions, rng));
-			let (dest_uuid, dest_position) = (dest.uuid, dest.position);
-			let src = self.node_map.entry(packet.src_addr).or_insert(RouterNode::random(packet.src_addr, &self.field_dimensions, rng));
-			
-			// Calculate latency
-			let latency = src.generate(dest_uuid, dest_position, rng);
+			self.stats.sent += 1;
+			self.stats.bytes_sent += packet.data.len();
+			// Calculate latency, there is nowhere to send packets addressed to a NetAddr without a node
+			let mut latency = match self.latency(packet.src_addr, packet.dest_addr, rng) {
+				Some(latency) => latency,
+				None => {
+					self.stats.dead_dropped += 1;
+					log::trace!("Router: dropped packet NetAddr({}) -> NetAddr({}), there is no node there", packet.src_addr, packet.dest_addr);
+					continue;
+				}
+			};
 
-			// Add packet to packet stream
-			if let Some(packet_stream) = self.packet_map.get_mut(&packet.dest_addr) {
-				packet_stream.push((packet, latency));
-			} else {
-				self.packet_map
-					.insert(packet.dest_addr, vec![(packet, latency)]);
+			// Simulator requests don't travel over the internet, so they are never impaired
+			if packet.request.is_none() {
+				// Packets leaving a NAT open a mapping for replies
+				if let Some(nat) = self.node_map.get_mut(&packet.src_addr).and_then(|n| n.nat.as_mut()) { nat.outbound(packet.dest_addr, self.ticks); }
+				if self.is_cut(packet.src_addr, packet.dest_addr) {
+					self.stats.partitioned += 1;
+					log::trace!("Router: partitioned packet NetAddr({}) -> NetAddr({})", packet.src_addr, packet.dest_addr);
+					continue;
+				}
+				let conditions = self.link_conditions(packet.src_addr, packet.dest_addr);
+				if conditions.roll_loss(rng) || self.roll_access_loss(packet.src_addr, packet.dest_addr, rng) {
+					self.stats.lost += 1;
+					log::trace!("Router: lost packet NetAddr({}) -> NetAddr({})", packet.src_addr, packet.dest_addr);
+					continue;
+				}
+				if conditions.roll_duplication(rng) {
+					self.stats.duplicated += 1;
+					let duplicate = NetSimPacket { dest_addr: packet.dest_addr, data: packet.data.clone(), src_addr: packet.src_addr, request: None };
+					let duplicate_latency = self.latency(packet.src_addr, packet.dest_addr, rng).unwrap_or(latency);
+					self.queue_packet(duplicate, duplicate_latency);
+				}
+				if let Some(delay) = conditions.roll_reordering(rng) {
+					self.stats.reordered += 1;
+					latency += delay;
+				}
+				// Wait for the packet to make it out of the sender's uplink
+				if let Some(bandwidth) = self.node_bandwidth(packet.src_addr) {
+					let src = self.node_map.get_mut(&packet.src_addr).unwrap();
+					match src.uplink.enqueue(self.ticks, packet.data.len(), bandwidth.uplink, bandwidth.buffer) {
+						Some(delay) => { latency += delay; self.stats.queue_delay += delay as usize; }
+						None => { self.stats.queue_dropped += 1; continue; }
+					}
+				}
 			}
+			self.queue_packet(packet, latency);
 		}
 	}
-	pub fn tick_node(&mut self, destination: NetAddr) -> NetSimPacketVec<CN> {
-		if let Some(packets) = self.packet_map.get_mut(&destination) {
-			packets.iter_mut().for_each(|item| item.1 -= 1); // Decrement ticks
-			// Filter out packets that should be passed
-			packets.drain_filter(|x| x.1 <= 0).map(|x| x.0).collect()
-		} else {
-			return NetSimPacketVec::new();
+	fn queue_packet(&mut self, packet: NetSimPacket<CN>, latency: isize) {
+		// Packets that skip the internet (simulator requests) or nodes with infinite bandwidth don't need to be queued on arrival
+		let downlinked = packet.request.is_some() || self.node_bandwidth(packet.dest_addr).is_none();
+		// Packets always take at least one tick to arrive
+		let arrival = self.ticks + latency.max(1) as usize;
+		self.events.push(arrival, Event::Deliver(packet, downlinked));
+	}
+	/// Called when a packet's Deliver event fires, returns the packet if it should be handed to its destination now
+	pub fn deliver(&mut self, packet: NetSimPacket<CN>, downlinked: bool) -> Option<NetSimPacket<CN>> {
+		if !self.node_map.contains_key(&packet.dest_addr) {
+			self.stats.dead_dropped += 1;
+			return None;
 		}
+		// Links can be cut while packets are in flight
+		if packet.request.is_none() && self.is_cut(packet.src_addr, packet.dest_addr) {
+			self.stats.partitioned += 1;
+			return None;
+		}
+		if packet.request.is_none() && !self.nat_allows(packet.src_addr, packet.dest_addr) {
+			self.stats.nat_dropped += 1;
+			log::trace!("Router: NAT of NetAddr({}) dropped unsolicited packet from NetAddr({})", packet.dest_addr, packet.src_addr);
+			return None;
+		}
+		// Packets that arrive at a busy downlink wait in its queue
+		if !downlinked {
+			if let (Some(bandwidth), Some(dest)) = (self.node_bandwidth(packet.dest_addr), self.node_map.get_mut(&packet.dest_addr)) {
+				match dest.downlink.enqueue(self.ticks, packet.data.len(), bandwidth.downlink, bandwidth.buffer) {
+					Some(0) => {},
+					Some(delay) => {
+						self.stats.queue_delay += delay as usize;
+						self.events.push(self.ticks + delay as usize, Event::Deliver(packet, true));
+						return None;
+					}
+					None => { self.stats.queue_dropped += 1; return None; }
+				}
+			}
+		}
+		self.stats.delivered += 1;
+		if let Some(nat) = self.node_map.get_mut(&packet.src_addr).and_then(|n| n.nat.as_mut()) { nat.confirm(packet.dest_addr, self.ticks); }
+		Some(packet)
 	}
-}
\ No newline at end of file
+}
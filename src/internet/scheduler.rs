@@ -0,0 +1,68 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use super::{CustomNode, NetAddr, NetSimPacket};
+
+/// Something that happens at a specific point in simulated time
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub enum Event<CN: CustomNode> {
+	/// Packet arrives at its destination, bool is true once the packet has passed the destination's downlink queue
+	Deliver(NetSimPacket<CN>, bool),
+	/// Node asked to be ticked even if no packets arrive for it
+	Wake(NetAddr),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+struct ScheduledEvent<CN: CustomNode> {
+	time: usize,
+	/// Insertion order, breaks ties between events at the same time
+	seq: u64,
+	event: Event<CN>,
+}
+// Reversed so that BinaryHeap pops the earliest event first
+impl<CN: CustomNode> Ord for ScheduledEvent<CN> {
+	fn cmp(&self, other: &Self) -> Ordering {
+		other.time.cmp(&self.time).then_with(|| other.seq.cmp(&self.seq))
+	}
+}
+impl<CN: CustomNode> PartialOrd for ScheduledEvent<CN> {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl<CN: CustomNode> PartialEq for ScheduledEvent<CN> {
+	fn eq(&self, other: &Self) -> bool { self.time == other.time && self.seq == other.seq }
+}
+impl<CN: CustomNode> Eq for ScheduledEvent<CN> {}
+
+/// Global time-ordered queue of everything that is going to happen in the simulation
+#[derive(Derivative, Serialize, Deserialize)]
+#[derivative(Debug, Default(bound=""))]
+#[serde(bound = "")]
+pub struct EventQueue<CN: CustomNode> {
+	#[derivative(Debug="ignore")]
+	queue: BinaryHeap<ScheduledEvent<CN>>,
+	next_seq: u64,
+}
+impl<CN: CustomNode> EventQueue<CN> {
+	pub fn push(&mut self, time: usize, event: Event<CN>) {
+		self.queue.push(ScheduledEvent { time, seq: self.next_seq, event });
+		self.next_seq += 1;
+	}
+	/// Time of the next event, if there is one
+	pub fn peek_time(&self) -> Option<usize> { self.queue.peek().map(|e| e.time) }
+	/// Pop the next event only if it is scheduled for `time`
+	pub fn pop_at(&mut self, time: usize) -> Option<Event<CN>> {
+		if self.peek_time() == Some(time) { self.queue.pop().map(|e| e.event) } else { None }
+	}
+	/// Keep only the events for which `keep` returns true, returns the number of events removed
+	pub fn retain(&mut self, mut keep: impl FnMut(&Event<CN>) -> bool) -> usize {
+		let before = self.queue.len();
+		let events: Vec<_> = std::mem::take(&mut self.queue).into_vec().into_iter().filter(|e| keep(&e.event)).collect();
+		self.queue = BinaryHeap::from(events);
+		before - self.queue.len()
+	}
+	pub fn len(&self) -> usize { self.queue.len() }
+	pub fn is_empty(&self) -> bool { self.queue.is_empty() }
+	pub fn clear(&mut self) { self.queue.clear(); }
+}
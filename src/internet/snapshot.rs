@@ -0,0 +1,539 @@
+use std::io::{Read, Write};
+use std::ops::Range;
+
+use serde::{Deserialize, Deserializer, Serialize, de::{DeserializeOwned, MapAccess, SeqAccess, Visitor}};
+use serde_json::{json, Map, Value};
+
+use super::{CustomNode, InternetError, NetSim};
+use super::latency::LatencyModel;
+use crate::rng::SimRng;
+
+/// First bytes of a binary snapshot. Files saved before snapshots were versioned don't start with them and can't be loaded.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DBRSNAP\0";
+/// Layout version of the snapshot body.
+/// Bump it whenever a serialized struct changes and add a `Migration` from the previous version to `MIGRATIONS`.
+pub const SNAPSHOT_VERSION: u32 = 10;
+
+/// Rewrites the body of a snapshot from version `from` to version `from + 1`.
+/// The body is `{"net": NetSim, "rng": SimRng}` as JSON, e.g. a field added with a default becomes
+/// `body["net"]["router"].as_object_mut()?.insert("new_field".into(), json!(0))`.
+struct Migration {
+	from: u32,
+	description: &'static str,
+	migrate: fn(&mut Value) -> Result<(), String>,
+}
+/// Every migration, in order of `from`
+const MIGRATIONS: &[Migration] = &[
+	Migration { from: 1, description: "nodes have a coordinate estimator, older nodes keep using the oracle", migrate: add_oracle_estimator },
+	Migration { from: 2, description: "sessions are kept alive with pings and time out", migrate: keep_sessions_alive },
+	Migration { from: 3, description: "nodes can leave gracefully, churn measures what departures leave behind", migrate: count_departure_costs },
+	Migration { from: 4, description: "nodes proxy routed sessions, routed connections take a random offset", migrate: add_routed_sessions },
+	Migration { from: 5, description: "remotes at the same distance are all kept sorted", migrate: sort_directs_by_distance_and_index },
+	Migration { from: 6, description: "placed nodes from before periodic refinement refine their route coordinate", migrate: schedule_refinement },
+	Migration { from: 7, description: "nodes attached to a topology remember where their router is", migrate: locate_attachments },
+	Migration { from: 8, description: "remote state is stored compactly: inline ping windows, packet times by kind and the route map as an edge list", migrate: compact_remote_state },
+	Migration { from: 9, description: "idle nodes refine and ping less often, remotes are only notified of changes", migrate: back_off_idle_nodes },
+];
+
+fn add_oracle_estimator(body: &mut Value) -> Result<(), String> {
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		node.as_object_mut().ok_or("node is not an object")?.insert("estimator".to_owned(), Value::from("Oracle"));
+	}
+	Ok(())
+}
+
+fn keep_sessions_alive(body: &mut Value) -> Result<(), String> {
+	// Idle nodes' clocks lag behind, they catch up to the simulation's time once they are woken up
+	let now = body.pointer("/net/router/ticks").cloned().ok_or("router has no ticks")?;
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		let ticks = node.get("ticks").cloned().ok_or("node has no ticks")?;
+		let mut keepalives = Vec::new();
+		for slot in node.get_mut("remotes").and_then(Value::as_array_mut).ok_or("node has no remotes")? {
+			let session = match slot.pointer_mut("/value/session") { Some(session) if !session.is_null() => session, _ => continue };
+			// Every session counts as just heard from, direct ones are pinged the next time the node is ticked
+			session.get_mut("tracker").and_then(Value::as_object_mut).ok_or("session has no tracker")?.insert("last_heard".to_owned(), now.clone());
+			if session.pointer("/session_type/Direct").is_some() {
+				keepalives.push(json!({ "Condition": [{ "RunAt": ticks }, { "Keepalive": session["session_id"] }] }));
+			}
+		}
+		node.get_mut("action_list").and_then(Value::as_array_mut).ok_or("node has no action_list")?.extend(keepalives);
+	}
+	Ok(())
+}
+
+fn count_departure_costs(body: &mut Value) -> Result<(), String> {
+	body.pointer_mut("/net/dht_stats").and_then(Value::as_object_mut).ok_or("net has no dht_stats")?.insert("removes".to_owned(), json!(0));
+	let dead_dropped = body.pointer("/net/router/stats/dead_dropped").cloned().ok_or("router has no stats")?;
+	let churn = match body.pointer_mut("/net/churn").and_then(Value::as_object_mut) { Some(churn) => churn, None => return Ok(()) };
+	// Drops before the snapshot are not attributed to any sample, older samples record none
+	churn.insert("dropped_last".to_owned(), dead_dropped);
+	for sample in churn.get_mut("samples").and_then(Value::as_array_mut).ok_or("churn has no samples")? {
+		let sample = sample.as_object_mut().ok_or("churn sample is not an object")?;
+		sample.insert("stale_sessions".to_owned(), json!(0));
+		sample.insert("dead_dropped".to_owned(), json!(0));
+	}
+	Ok(())
+}
+
+fn add_routed_sessions(body: &mut Value) -> Result<(), String> {
+	// Actions may be nested in conditions
+	fn add_offset(action: &mut Value) {
+		if let Some(args) = action.get_mut("ConnectRouted").and_then(Value::as_array_mut) {
+			args.push(json!(0.));
+		} else if let Some(embedded) = action.pointer_mut("/Condition/1") {
+			add_offset(embedded);
+		}
+	}
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		node.as_object_mut().ok_or("node is not an object")?.insert("relays".to_owned(), json!({}));
+		node.get_mut("action_list").and_then(Value::as_array_mut).ok_or("node has no action_list")?.iter_mut().for_each(add_offset);
+	}
+	Ok(())
+}
+
+fn sort_directs_by_distance_and_index(body: &mut Value) -> Result<(), String> {
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		let direct_sorted = node.get_mut("direct_sorted").ok_or("node has no direct_sorted")?;
+		// `{distance: node_idx}` becomes a set of `[distance, node_idx]`
+		let sorted = direct_sorted.as_object().ok_or("direct_sorted is not an object")?.iter().map(|(distance, node_idx)| {
+			let distance = distance.parse::<u64>().map_err(|_| format!("direct_sorted has a non-numeric distance {:?}", distance))?;
+			Ok(json!([distance, node_idx]))
+		}).collect::<Result<Vec<Value>, String>>()?;
+		*direct_sorted = Value::Array(sorted);
+	}
+	Ok(())
+}
+
+fn schedule_refinement(body: &mut Value) -> Result<(), String> {
+	// Actions may be nested in conditions
+	fn refines(action: &Value) -> bool {
+		action == "RefineRouteCoord" || action.pointer("/Condition/1").map_or(false, refines)
+	}
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		// Nodes without a route coordinate schedule refinement once they calculate one
+		if node.get("route_coord").map_or(true, Value::is_null) { continue }
+		let ticks = node.get("ticks").cloned().ok_or("node has no ticks")?;
+		let action_list = node.get_mut("action_list").and_then(Value::as_array_mut).ok_or("node has no action_list")?;
+		if !action_list.iter().any(refines) {
+			action_list.push(json!({ "Condition": [{ "RunAt": ticks }, "RefineRouteCoord"] }));
+		}
+	}
+	Ok(())
+}
+
+fn locate_attachments(body: &mut Value) -> Result<(), String> {
+	let routers = body.pointer("/net/router/latency_model/Topology/routers").and_then(Value::as_array).cloned().unwrap_or_default();
+	let node_map = body.pointer_mut("/net/router/node_map").and_then(Value::as_object_mut).ok_or("router has no node_map")?;
+	for router_node in node_map.values_mut() {
+		// Nodes used to be moved onto their router, where they are is the best guess if the router is gone
+		let position = router_node.get("position").cloned().ok_or("router node has no position")?;
+		let attachment = match router_node.get_mut("attachment").and_then(Value::as_object_mut) { Some(attachment) => attachment, None => continue };
+		let router = attachment.get("router").and_then(Value::as_u64).ok_or("attachment has no router")? as usize;
+		let position = routers.get(router).and_then(|router| router.get("position")).cloned().unwrap_or(position);
+		attachment.insert("position".to_owned(), position);
+	}
+	Ok(())
+}
+
+fn compact_remote_state(body: &mut Value) -> Result<(), String> {
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		for slot in node.get_mut("remotes").and_then(Value::as_array_mut).ok_or("node has no remotes")? {
+			let session = match slot.pointer_mut("/value/session") { Some(session) if !session.is_null() => session, _ => continue };
+			// Packet times were keyed by kind and the sending node, which is always the session's remote
+			let last_packet_times = session.get("last_packet_times").and_then(Value::as_array).ok_or("session has no last_packet_times")?;
+			let last_packet_times = last_packet_times.iter().map(|pair| {
+				let kind = pair.pointer("/0/0").and_then(Value::as_u64).ok_or("last_packet_times has no packet kind")?;
+				Ok((kind.to_string(), pair.get(1).cloned().ok_or("last_packet_times has no time")?))
+			}).collect::<Result<Map<String, Value>, String>>()?;
+			session["last_packet_times"] = Value::Object(last_packet_times);
+
+			let tracker = session.get_mut("tracker").and_then(Value::as_object_mut).ok_or("session has no tracker")?;
+			// The moving average stored the n-th distance at n % period too, a distance is half a round trip
+			let distances = tracker.get("ping_avg").and_then(|avg| avg.get("deque")).and_then(Value::as_array).ok_or("tracker has no ping_avg")?;
+			let round_trips = distances.iter().map(|distance| {
+				distance.as_f64().map(|distance| (distance * 2.).round() as u32).ok_or("ping_avg has a non-numeric distance")
+			}).collect::<Result<Vec<u32>, _>>()?;
+			tracker.remove("ping_avg");
+			tracker.remove("ping_dev");
+			tracker.insert("round_trips".to_owned(), json!(round_trips));
+		}
+		// `[nodes, [[from, to, distance]]]` becomes `[[[from, to], distance]]`, nodes without edges weren't used
+		let route_map = node.get_mut("route_map").ok_or("node has no route_map")?;
+		let edges = route_map.get(1).and_then(Value::as_array).ok_or("route_map has no edges")?.iter().map(|edge| {
+			match edge.as_array().map(Vec::as_slice) {
+				Some([from, to, distance]) => Ok(json!([[from, to], distance])),
+				_ => Err(format!("route_map has a malformed edge {}", edge)),
+			}
+		}).collect::<Result<Vec<Value>, String>>()?;
+		*route_map = Value::Array(edges);
+	}
+	Ok(())
+}
+
+fn back_off_idle_nodes(body: &mut Value) -> Result<(), String> {
+	let nodes = body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).ok_or("net has no nodes")?;
+	for node in nodes.values_mut() {
+		// Every direct remote used to be sent the route coordinate whenever peers were calculated, so it was told the current one
+		let route_coord = node.get("route_coord").cloned().ok_or("node has no route_coord")?;
+		for slot in node.get_mut("remotes").and_then(Value::as_array_mut).ok_or("node has no remotes")? {
+			let session = match slot.pointer_mut("/value/session") { Some(session) if !session.is_null() => session, _ => continue };
+			session.get_mut("tracker").and_then(Value::as_object_mut).ok_or("session has no tracker")?.insert("keepalive_backoff".to_owned(), json!(0));
+			if let Some(direct) = session.pointer_mut("/session_type/Direct").and_then(Value::as_object_mut) {
+				direct.insert("notified_route".to_owned(), route_coord.clone());
+			}
+		}
+		node.as_object_mut().ok_or("node is not an object")?.insert("refine_backoff".to_owned(), json!(0));
+	}
+	Ok(())
+}
+
+/// Describes the simulation in a snapshot, readable without loading the rest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+	/// Layout version of the body
+	pub version: u32,
+	/// Version of dbr-sim that wrote the snapshot
+	pub writer: String,
+	pub seed: u64,
+	pub ticks: usize,
+	pub nodes: usize,
+	pub field_dimensions: (Range<i32>, Range<i32>),
+}
+
+/// Layout of a JSON snapshot, the header comes first so that it can be read at the top of the file
+#[derive(Serialize, Deserialize)]
+struct JsonSnapshot {
+	header: SnapshotHeader,
+	/// `{"net": NetSim, "rng": SimRng}`
+	body: Value,
+}
+
+/// `serde_json::Value` in a form bincode can read back, bincode isn't self-describing and needs to know the type of everything it reads.
+/// Keeping field names in binary snapshots is what lets old snapshots be migrated after a struct changes.
+#[derive(Serialize, Deserialize)]
+enum Packed {
+	Null,
+	Bool(bool),
+	Unsigned(u64),
+	Signed(i64),
+	Float(f64),
+	String(String),
+	Array(Vec<Packed>),
+	Object(Vec<(String, Packed)>),
+}
+impl From<Value> for Packed {
+	fn from(value: Value) -> Self {
+		match value {
+			Value::Null => Packed::Null,
+			Value::Bool(b) => Packed::Bool(b),
+			Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+				(Some(n), _) => Packed::Unsigned(n),
+				(_, Some(n)) => Packed::Signed(n),
+				_ => Packed::Float(n.as_f64().unwrap_or_default()),
+			},
+			Value::String(s) => Packed::String(s),
+			Value::Array(values) => Packed::Array(values.into_iter().map(Packed::from).collect()),
+			Value::Object(map) => Packed::Object(map.into_iter().map(|(key, value)| (key, Packed::from(value))).collect()),
+		}
+	}
+}
+impl From<Packed> for Value {
+	fn from(packed: Packed) -> Self {
+		match packed {
+			Packed::Null => Value::Null,
+			Packed::Bool(b) => Value::Bool(b),
+			Packed::Unsigned(n) => Value::from(n),
+			Packed::Signed(n) => Value::from(n),
+			Packed::Float(n) => Value::from(n),
+			Packed::String(s) => Value::String(s),
+			Packed::Array(values) => Value::Array(values.into_iter().map(Value::from).collect()),
+			Packed::Object(fields) => Value::Object(fields.into_iter().map(|(key, value)| (key, Value::from(value))).collect()),
+		}
+	}
+}
+
+/// Reads any self-describing format (JSON text here) straight into `Packed`, without building a `Value` in between
+struct Unpacked(Packed);
+impl<'de> Deserialize<'de> for Unpacked {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		struct PackedVisitor;
+		impl<'de> Visitor<'de> for PackedVisitor {
+			type Value = Unpacked;
+			fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result { formatter.write_str("any JSON value") }
+			fn visit_unit<E>(self) -> Result<Unpacked, E> { Ok(Unpacked(Packed::Null)) }
+			fn visit_bool<E>(self, b: bool) -> Result<Unpacked, E> { Ok(Unpacked(Packed::Bool(b))) }
+			fn visit_u64<E>(self, n: u64) -> Result<Unpacked, E> { Ok(Unpacked(Packed::Unsigned(n))) }
+			fn visit_i64<E>(self, n: i64) -> Result<Unpacked, E> {
+				// Same as `Value`, non-negative numbers are always unsigned
+				Ok(Unpacked(if n >= 0 { Packed::Unsigned(n as u64) } else { Packed::Signed(n) }))
+			}
+			fn visit_f64<E>(self, n: f64) -> Result<Unpacked, E> { Ok(Unpacked(Packed::Float(n))) }
+			fn visit_str<E>(self, s: &str) -> Result<Unpacked, E> { Ok(Unpacked(Packed::String(s.to_owned()))) }
+			fn visit_string<E>(self, s: String) -> Result<Unpacked, E> { Ok(Unpacked(Packed::String(s))) }
+			fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Unpacked, A::Error> {
+				let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+				while let Some(Unpacked(value)) = seq.next_element()? { values.push(value); }
+				Ok(Unpacked(Packed::Array(values)))
+			}
+			fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Unpacked, A::Error> {
+				let mut fields = Vec::with_capacity(map.size_hint().unwrap_or(0));
+				while let Some((key, Unpacked(value))) = map.next_entry::<String, Unpacked>()? { fields.push((key, value)); }
+				Ok(Unpacked(Packed::Object(fields)))
+			}
+		}
+		deserializer.deserialize_any(PackedVisitor)
+	}
+}
+
+/// Same as `Packed::from(serde_json::to_value(value)?)`, but only ever holds the JSON text and the `Packed` in memory
+fn to_packed(value: &impl Serialize) -> Result<Packed, serde_json::Error> {
+	let Unpacked(packed) = serde_json::from_slice(&serde_json::to_vec(value)?)?;
+	Ok(packed)
+}
+
+fn invalid(reason: impl Into<String>) -> InternetError { InternetError::InvalidSnapshot { reason: reason.into() } }
+
+/// Bring a snapshot body written at `version` up to `SNAPSHOT_VERSION`
+fn migrate(body: &mut Value, version: u32) -> Result<(), InternetError> {
+	if version > SNAPSHOT_VERSION {
+		return Err(invalid(format!("version {} is newer than this build supports ({}), update dbr-sim", version, SNAPSHOT_VERSION)));
+	}
+	for current in version..SNAPSHOT_VERSION {
+		let migration = MIGRATIONS.iter().find(|migration| migration.from == current)
+			.ok_or_else(|| invalid(format!("no migration from version {} to {}", current, current + 1)))?;
+		(migration.migrate)(body).map_err(|err| invalid(format!("migration from version {} ({}) failed: {}", current, migration.description, err)))?;
+		log::info!("Snapshot: migrated from version {} to {}: {}", current, current + 1, migration.description);
+	}
+	Ok(())
+}
+
+impl<CN, LM> NetSim<CN, LM>
+where CN: CustomNode + Serialize + DeserializeOwned, LM: LatencyModel + Serialize + DeserializeOwned {
+	pub fn snapshot_header(&self) -> SnapshotHeader {
+		SnapshotHeader {
+			version: SNAPSHOT_VERSION,
+			writer: env!("CARGO_PKG_VERSION").to_owned(),
+			seed: self.seed,
+			ticks: self.ticks(),
+			nodes: self.nodes.len(),
+			field_dimensions: self.router.field_dimensions.clone(),
+		}
+	}
+	fn snapshot_body(&self, rng: &SimRng) -> Result<Value, serde_json::Error> {
+		let mut body = Map::new();
+		body.insert("net".to_owned(), serde_json::to_value(self)?);
+		body.insert("rng".to_owned(), serde_json::to_value(rng)?);
+		Ok(Value::Object(body))
+	}
+	fn packed_snapshot_body(&self, rng: &SimRng) -> Result<Packed, serde_json::Error> {
+		Ok(Packed::Object(vec![("net".to_owned(), to_packed(self)?), ("rng".to_owned(), to_packed(rng)?)]))
+	}
+	fn from_snapshot_body(mut body: Value, version: u32) -> Result<(Self, SimRng), InternetError> {
+		migrate(&mut body, version)?;
+		let mut take = |field: &str| body.get_mut(field).map(Value::take).ok_or_else(|| invalid(format!("body is missing {:?}", field)));
+		let (net, rng) = (take("net")?, take("rng")?);
+		let (mut net, rng): (Self, SimRng) = (serde_json::from_value(net)?, serde_json::from_value(rng)?);
+		// Migrations may give nodes actions, idle nodes have no wakeup scheduled to carry them out
+		if version < SNAPSHOT_VERSION {
+			let now = net.ticks();
+			let net_addrs: Vec<_> = net.nodes.keys().copied().collect();
+			for net_addr in net_addrs { net.wake(net_addr, now); }
+		}
+		Ok((net, rng))
+	}
+
+	/// Save the whole simulation: nodes with their pending actions and sessions, packets in flight, scheduled wakeups
+	/// and the random number generator driving the router. `load_snapshot` resumes exactly as if the simulation had never stopped.
+	pub fn save_snapshot(&self, rng: &SimRng, mut writer: impl Write) -> Result<(), InternetError> {
+		writer.write_all(SNAPSHOT_MAGIC)?;
+		bincode::serialize_into(&mut writer, &self.snapshot_header())?;
+		bincode::serialize_into(&mut writer, &self.packed_snapshot_body(rng)?)?;
+		writer.flush()?;
+		Ok(())
+	}
+	/// Load a simulation saved with `save_snapshot`, along with the random number generator to continue it with.
+	/// Snapshots of older versions are migrated. Files saved before snapshots were versioned are refused, they hold a bare `NetSim`
+	/// in a layout that no longer exists and lack everything it skipped when serializing.
+	pub fn load_snapshot(mut reader: impl Read) -> Result<(Self, SimRng, SnapshotHeader), InternetError> {
+		let mut magic = [0; SNAPSHOT_MAGIC.len()];
+		let mut read = 0;
+		while read < magic.len() {
+			match reader.read(&mut magic[read..])? { 0 => break, n => read += n }
+		}
+		if magic[..read] != SNAPSHOT_MAGIC[..] {
+			return Err(invalid("not a versioned snapshot, networks saved before snapshots were versioned can't be loaded and have to be generated again"));
+		}
+		let header: SnapshotHeader = bincode::deserialize_from(&mut reader)?;
+		let body: Packed = bincode::deserialize_from(&mut reader)?;
+		let (net, rng) = Self::from_snapshot_body(body.into(), header.version)?;
+		Ok((net, rng, header))
+	}
+	/// Read only the header of a snapshot saved with `save_snapshot`
+	pub fn read_snapshot_header(mut reader: impl Read) -> Result<SnapshotHeader, InternetError> {
+		let mut magic = [0; SNAPSHOT_MAGIC.len()];
+		reader.read_exact(&mut magic)?;
+		if &magic != SNAPSHOT_MAGIC { return Err(invalid("not a versioned snapshot")) }
+		Ok(bincode::deserialize_from(reader)?)
+	}
+
+	/// Write the same snapshot as `save_snapshot` as human-readable JSON
+	pub fn export_json(&self, rng: &SimRng, mut writer: impl Write) -> Result<(), InternetError> {
+		serde_json::to_writer_pretty(&mut writer, &JsonSnapshot { header: self.snapshot_header(), body: self.snapshot_body(rng)? })?;
+		writer.flush()?;
+		Ok(())
+	}
+	/// Load a snapshot written by `export_json`, migrating it if it is from an older version
+	pub fn import_json(reader: impl Read) -> Result<(Self, SimRng, SnapshotHeader), InternetError> {
+		let JsonSnapshot { header, body } = serde_json::from_reader(reader)?;
+		let (net, rng) = Self::from_snapshot_body(body, header.version)?;
+		Ok((net, rng, header))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::SeedableRng;
+
+	use super::*;
+	use crate::internet::churn::{ChurnModel, SessionLength};
+	use crate::node::{Node, NodeAction, estimator::Estimator};
+
+	/// Small network with floating point state everywhere: Vivaldi errors, churn arrivals and node latency averages
+	fn churning_net() -> (NetSim<Node>, SimRng) {
+		let mut rng = SimRng::seed_from_u64(3);
+		let mut net: NetSim<Node> = NetSim::new();
+		net.seed = 3;
+		for i in 0..12 {
+			let node = Node::new(i, net.lease()).with_estimator("vivaldi".parse::<Estimator>().unwrap());
+			net.add_node(node, &mut rng).unwrap();
+		}
+		for net_addr in 1..12 {
+			net.node_mut(net_addr).unwrap().action(NodeAction::Bootstrap(0, 0));
+			net.tick(1000, &mut rng);
+		}
+		net.start_churn(ChurnModel::new(0.002, SessionLength::Weibull { shape: 0.7, scale: 3000. }, 0.5).unwrap(), &mut rng);
+		net.tick_churn(5000, &mut rng).unwrap();
+		(net, rng)
+	}
+	fn save(net: &NetSim<Node>, rng: &SimRng) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		net.save_snapshot(rng, &mut bytes).unwrap();
+		bytes
+	}
+
+	#[test]
+	fn snapshot_round_trips_exactly() {
+		let (mut net, mut rng) = churning_net();
+		let saved = save(&net, &rng);
+		let (mut loaded, mut loaded_rng, header) = NetSim::<Node>::load_snapshot(saved.as_slice()).unwrap();
+		assert_eq!(header.version, SNAPSHOT_VERSION);
+		assert_eq!(save(&loaded, &loaded_rng), saved);
+
+		// Resuming from the snapshot takes the same course as never having stopped
+		net.tick_churn(5000, &mut rng).unwrap();
+		loaded.tick_churn(5000, &mut loaded_rng).unwrap();
+		assert_eq!(save(&loaded, &loaded_rng), save(&net, &rng));
+	}
+
+	#[test]
+	fn migrations_cover_every_version() {
+		for version in 1..SNAPSHOT_VERSION {
+			assert_eq!(MIGRATIONS.iter().filter(|migration| migration.from == version).count(), 1, "migrations from version {}", version);
+		}
+		let mut body = json!({});
+		assert!(matches!(migrate(&mut body, SNAPSHOT_VERSION + 1), Err(InternetError::InvalidSnapshot { .. })));
+		assert!(migrate(&mut body, SNAPSHOT_VERSION).is_ok());
+	}
+
+	#[test]
+	fn migrations_rewrite_nodes() {
+		let mut body = json!({ "net": { "nodes": { "0": {
+			"ticks": 500,
+			"route_coord": [3, 4],
+			"direct_sorted": { "20": { "idx": 2, "version": 1 }, "7": { "idx": 1, "version": 1 } },
+			"action_list": [{ "Condition": [{ "NodeConnected": 4 }, { "ConnectRouted": [4, 3] }] }],
+		}}}});
+		add_routed_sessions(&mut body).unwrap();
+		sort_directs_by_distance_and_index(&mut body).unwrap();
+		schedule_refinement(&mut body).unwrap();
+		let node = &body["net"]["nodes"]["0"];
+		assert_eq!(node["relays"], json!({}));
+		assert_eq!(node["direct_sorted"], json!([[20, { "idx": 2, "version": 1 }], [7, { "idx": 1, "version": 1 }]]));
+		assert_eq!(node["action_list"], json!([
+			{ "Condition": [{ "NodeConnected": 4 }, { "ConnectRouted": [4, 3, 0.] }] },
+			{ "Condition": [{ "RunAt": 500 }, "RefineRouteCoord"] },
+		]));
+		// Refinement that is already scheduled isn't scheduled twice
+		schedule_refinement(&mut body).unwrap();
+		assert_eq!(body["net"]["nodes"]["0"]["action_list"].as_array().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn migrated_snapshot_loads() {
+		// Backoffs start over and remotes count as told the current route coordinate
+		let (mut net, rng) = churning_net();
+		for node in net.nodes.values_mut() {
+			node.refine_backoff = 0;
+			let route_coord = node.route_coord;
+			for (_, remote) in node.remotes.iter_mut() {
+				let session = match remote.session.as_mut() { Some(session) => session, None => continue };
+				session.tracker.keepalive_backoff = 0;
+				if let Ok(direct) = session.direct_mut() { direct.notified_route = route_coord; }
+			}
+		}
+		// Take a snapshot apart the way an older version would have written it
+		let mut body = net.snapshot_body(&rng).unwrap();
+		for node in body.pointer_mut("/net/nodes").and_then(Value::as_object_mut).unwrap().values_mut() {
+			node.as_object_mut().unwrap().remove("refine_backoff");
+			let sorted = node["direct_sorted"].as_array().unwrap().iter().map(|pair| (pair[0].to_string(), pair[1].clone())).collect();
+			node["direct_sorted"] = Value::Object(sorted);
+			node["action_list"].as_array_mut().unwrap().retain(|action| action.pointer("/Condition/1") != Some(&json!("RefineRouteCoord")));
+			let edges: Vec<Value> = node["route_map"].as_array().unwrap().iter().map(|edge| json!([edge[0][0], edge[0][1], edge[1]])).collect();
+			node["route_map"] = json!([[], edges]);
+			for slot in node["remotes"].as_array_mut().unwrap() {
+				let node_id = slot["value"]["node_id"].clone();
+				let session = match slot.pointer_mut("/value/session") { Some(session) if !session.is_null() => session, _ => continue };
+				if let Some(direct) = session.pointer_mut("/session_type/Direct").and_then(Value::as_object_mut) { direct.remove("notified_route"); }
+				session["tracker"].as_object_mut().unwrap().remove("keepalive_backoff");
+				let times: Vec<Value> = session["last_packet_times"].as_object().unwrap().iter().map(|(kind, time)| json!([[kind.parse::<u8>().unwrap(), node_id], time])).collect();
+				session["last_packet_times"] = Value::Array(times);
+				let tracker = session["tracker"].as_object_mut().unwrap();
+				let distances: Vec<f64> = tracker.remove("round_trips").unwrap().as_array().unwrap().iter().map(|rtt| rtt.as_f64().unwrap() / 2.).collect();
+				tracker.insert("ping_avg".to_owned(), json!({ "period": 10, "index": 0, "count": 0, "sum": 0., "deque": distances }));
+				tracker.insert("ping_dev".to_owned(), json!({ "period": 10, "index": 0, "count": 0, "m": 0., "m2": 0., "deque": distances }));
+			}
+		}
+		let (migrated, _) = NetSim::<Node>::from_snapshot_body(body, 5).unwrap();
+		for (net_addr, node) in &migrated.nodes {
+			assert_eq!(node.direct_sorted, net.nodes[net_addr].direct_sorted);
+			assert_eq!(serde_json::to_value(&node.remotes).unwrap(), serde_json::to_value(&net.nodes[net_addr].remotes).unwrap());
+			assert_eq!(node.route_map, net.nodes[net_addr].route_map);
+			assert_eq!(node.route_coord.is_some(), node.action_list.iter().any(|action| matches!(action, NodeAction::Condition(_, action) if matches!(**action, NodeAction::RefineRouteCoord))));
+		}
+	}
+
+	#[test]
+	fn unversioned_snapshots_are_refused() {
+		let (net, rng) = churning_net();
+		// A bare `NetSim`, the way `net save` wrote networks before snapshots were versioned
+		let unversioned = bincode::serialize(&net).unwrap();
+		assert!(matches!(NetSim::<Node>::load_snapshot(unversioned.as_slice()), Err(InternetError::InvalidSnapshot { .. })));
+		let saved = save(&net, &rng);
+		assert!(matches!(NetSim::<Node>::load_snapshot(&saved[..4]), Err(InternetError::InvalidSnapshot { .. })));
+	}
+
+	#[test]
+	fn json_round_trips_exactly() {
+		let (net, rng) = churning_net();
+		let mut json = Vec::new();
+		net.export_json(&rng, &mut json).unwrap();
+		let (imported, imported_rng, _) = NetSim::<Node>::import_json(json.as_slice()).unwrap();
+		assert_eq!(save(&imported, &imported_rng), save(&net, &rng));
+	}
+}
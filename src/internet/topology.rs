@@ -0,0 +1,212 @@
+use std::ops::Range;
+
+use nalgebra::{Point2, Vector2};
+use petgraph::graph::{NodeIndex, UnGraph};
+use rand::Rng;
+use rand::seq::IteratorRandom;
+
+use super::InternetError;
+use super::router::RouterNode;
+use super::latency::{LatencyModel, VARIANCE};
+
+/// Whether a router belongs to a transit domain or a stub AS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RouterKind {
+	Transit,
+	Stub,
+}
+
+/// Router in the router-level graph
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopologyRouter {
+	pub position: Point2<f32>,
+	pub kind: RouterKind,
+	/// Autonomous system the router belongs to, transit domains are numbered first
+	pub asn: usize,
+	/// Transit domain the router's AS hangs off, stands in for a geographic region
+	pub region: usize,
+}
+
+/// Where a node is plugged into the router-level graph
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Attachment {
+	/// Index of a stub router in `TopologyLatency::routers`
+	pub router: usize,
+	/// Position of that router, where the node sits as far as latency is concerned
+	pub position: Point2<f32>,
+	/// Latency of the node's access link to that router
+	pub access: isize,
+}
+
+/// Parameters of a GT-ITM style transit-stub topology.
+/// Transit domains are connected to each other, every transit router has a few stub ASes hanging off it
+/// and stub ASes only reach each other through the transit core (unless multihomed).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransitStub {
+	pub transit_domains: usize,
+	/// Routers per transit domain
+	pub transit_routers: usize,
+	/// Stub ASes attached to every transit router
+	pub stubs_per_transit: usize,
+	/// Routers per stub AS
+	pub stub_routers: usize,
+	/// Chance of an extra link between two routers of the same domain, or between two transit domains
+	pub extra_link_prob: f64,
+	/// Chance that a stub AS has a second uplink into a different transit domain
+	pub multihome_prob: f64,
+	/// Upper bound of the latency of a node's access link
+	pub max_access: isize,
+}
+impl Default for TransitStub {
+	fn default() -> Self {
+		Self { transit_domains: 4, transit_routers: 4, stubs_per_transit: 3, stub_routers: 4, extra_link_prob: 0.2, multihome_prob: 0.1, max_access: 5 }
+	}
+}
+impl TransitStub {
+	pub fn new(transit_domains: usize, transit_routers: usize, stubs_per_transit: usize, stub_routers: usize) -> Self {
+		Self { transit_domains, transit_routers, stubs_per_transit, stub_routers, ..Default::default() }
+	}
+	/// Generate a random topology spread over the field, link latencies are the distances between their routers.
+	/// Fails without stub ASes, nodes would have nowhere to attach to.
+	pub fn generate<R: Rng>(&self, field: &(Range<i32>, Range<i32>), rng: &mut R) -> Result<TopologyLatency, InternetError> {
+		if self.stubs_per_transit == 0 { return Err(InternetError::InvalidTopology { reason: "stubs_per_transit must be at least 1, nodes only attach to stub routers".into() }) }
+		let (domains, transit_routers, stubs, stub_routers) = (self.transit_domains.max(1), self.transit_routers.max(1), self.stubs_per_transit, self.stub_routers.max(1));
+		let span = (field.0.end - field.0.start).max(field.1.end - field.1.start).max(1) as f32;
+		let (min, max) = (Point2::new(field.0.start as f32, field.1.start as f32), Point2::new(field.0.end as f32, field.1.end as f32));
+		let scatter = |center: Point2<f32>, radius: f32, rng: &mut R| -> Point2<f32> {
+			let (angle, dist) = (rng.gen_range(0. ..std::f32::consts::TAU), radius * rng.gen::<f32>().sqrt());
+			let point = center + Vector2::new(angle.cos(), angle.sin()) * dist;
+			Point2::new(point.x.clamp(min.x, max.x), point.y.clamp(min.y, max.y))
+		};
+
+		let mut topology = TopologyLatency { max_access: self.max_access.max(1), variance: VARIANCE, ..Default::default() };
+		// Connect routers[first..] with a random spanning tree plus extra links
+		let connect_domain = |topology: &mut TopologyLatency, first: usize, rng: &mut R| {
+			for router in first + 1..topology.routers.len() {
+				topology.link(router, rng.gen_range(first..router));
+				for other in first..router {
+					if rng.gen_bool(self.extra_link_prob) { topology.link(router, other); }
+				}
+			}
+		};
+
+		let mut domain_routers = Vec::with_capacity(domains);
+		for region in 0..domains {
+			let center = Point2::new(rng.gen_range(min.x..max.x.max(min.x + 1.)), rng.gen_range(min.y..max.y.max(min.y + 1.)));
+			let first = topology.routers.len();
+			for _ in 0..transit_routers {
+				let position = scatter(center, span / 8., rng);
+				topology.routers.push(TopologyRouter { position, kind: RouterKind::Transit, asn: region, region });
+			}
+			connect_domain(&mut topology, first, rng);
+			domain_routers.push(first..topology.routers.len());
+		}
+		// Chain the transit domains together, then add a few peering links
+		for domain in 1..domains {
+			let other = rng.gen_range(0..domain);
+			topology.link(rng.gen_range(domain_routers[domain].clone()), rng.gen_range(domain_routers[other].clone()));
+			for other in 0..domain {
+				if rng.gen_bool(self.extra_link_prob) { topology.link(rng.gen_range(domain_routers[domain].clone()), rng.gen_range(domain_routers[other].clone())); }
+			}
+		}
+
+		let mut asn = domains;
+		for transit in 0..domains * transit_routers {
+			let (uplink_position, region) = (topology.routers[transit].position, topology.routers[transit].region);
+			for _ in 0..stubs {
+				let center = scatter(uplink_position, span / 16., rng);
+				let first = topology.routers.len();
+				for _ in 0..stub_routers {
+					let position = scatter(center, span / 64., rng);
+					topology.routers.push(TopologyRouter { position, kind: RouterKind::Stub, asn, region });
+				}
+				connect_domain(&mut topology, first, rng);
+				topology.link(rng.gen_range(first..topology.routers.len()), transit);
+				if domains > 1 && rng.gen_bool(self.multihome_prob) {
+					let other = (region + rng.gen_range(1..domains)) % domains;
+					topology.link(rng.gen_range(first..topology.routers.len()), rng.gen_range(domain_routers[other].clone()));
+				}
+				asn += 1;
+			}
+		}
+		topology.compute_paths();
+		Ok(topology)
+	}
+}
+
+/// Latency is the shortest-path cost through a router-level graph between the routers two nodes are attached to,
+/// plus both nodes' access links. Unlike `GeometricLatency` this is far from a flat 2D embedding.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TopologyLatency {
+	pub routers: Vec<TopologyRouter>,
+	/// Undirected router-level links as (router, router, latency)
+	pub links: Vec<(usize, usize, isize)>,
+	/// Shortest-path latency between every pair of routers, `paths[src * routers.len() + dest]`
+	paths: Vec<isize>,
+	/// Upper bound of the latency of a node's access link
+	pub max_access: isize,
+	pub variance: isize,
+}
+impl TopologyLatency {
+	fn link(&mut self, a: usize, b: usize) {
+		let latency = (nalgebra::distance(&self.routers[a].position, &self.routers[b].position) as isize).max(1);
+		self.links.push((a, b, latency));
+	}
+	/// Precompute all-pairs shortest paths so that `distance` is a lookup
+	fn compute_paths(&mut self) {
+		let size = self.routers.len();
+		let mut graph = UnGraph::<(), isize, usize>::with_capacity(size, self.links.len());
+		for _ in 0..size { graph.add_node(()); }
+		graph.extend_with_edges(self.links.iter().copied());
+		self.paths = vec![0; size * size];
+		for src in 0..size {
+			let costs = petgraph::algo::dijkstra(&graph, NodeIndex::new(src), None, |edge| *edge.weight());
+			for (dest, cost) in costs { self.paths[src * size + dest.index()] = cost; }
+		}
+	}
+	/// Shortest-path latency between two routers
+	pub fn path(&self, src: usize, dest: usize) -> isize { self.paths[src * self.routers.len() + dest] }
+	/// Number of autonomous systems (transit domains and stub ASes)
+	pub fn as_count(&self) -> usize { self.routers.last().map_or(0, |router| router.asn + 1) }
+	/// Mean ratio of shortest-path latency to straight-line distance over `samples` random pairs of stub routers
+	pub fn stretch(&self, samples: usize, rng: &mut impl Rng) -> f64 {
+		let stubs: Vec<usize> = (0..self.routers.len()).filter(|&router| self.routers[router].kind == RouterKind::Stub).collect();
+		let ratios: Vec<f64> = (0..if stubs.len() < 2 { 0 } else { samples }).filter_map(|_| {
+			let (src, dest) = (stubs[rng.gen_range(0..stubs.len())], stubs[rng.gen_range(0..stubs.len())]);
+			let straight = nalgebra::distance(&self.routers[src].position, &self.routers[dest].position) as f64;
+			(straight >= 1.).then(|| self.path(src, dest) as f64 / straight)
+		}).collect();
+		if ratios.is_empty() { return 0. }
+		ratios.iter().sum::<f64>() / ratios.len() as f64
+	}
+}
+impl LatencyModel for TopologyLatency {
+	/// Attach the node to a random stub router, the node's own position is left alone
+	fn add_node(&mut self, node: &mut RouterNode, rng: &mut impl Rng) {
+		let stubs = self.routers.iter().enumerate().filter(|(_, router)| router.kind == RouterKind::Stub);
+		let (router, position) = match stubs.map(|(idx, router)| (idx, router.position)).choose_stable(rng) {
+			Some(stub) => stub,
+			None => return,
+		};
+		node.attachment = Some(Attachment { router, position, access: rng.gen_range(1..=self.max_access.max(1)) });
+	}
+	/// Re-attach the node to the stub router closest to its new position, keeping its access link
+	fn move_node(&mut self, node: &mut RouterNode, _rng: &mut impl Rng) {
+		let closest = self.routers.iter().enumerate().filter(|(_, router)| router.kind == RouterKind::Stub)
+			.min_by(|(_, a), (_, b)| nalgebra::distance(&a.position, &node.position).total_cmp(&nalgebra::distance(&b.position, &node.position)));
+		if let (Some((router, closest)), Some(attachment)) = (closest, &mut node.attachment) {
+			attachment.router = router;
+			attachment.position = closest.position;
+		}
+	}
+	fn distance(&self, src: &RouterNode, dest: &RouterNode) -> isize {
+		match (src.attachment, dest.attachment) {
+			(Some(src), Some(dest)) if src.router < self.routers.len() && dest.router < self.routers.len() => src.access + self.path(src.router, dest.router) + dest.access,
+			_ => nalgebra::distance(&src.position, &dest.position) as isize,
+		}
+	}
+	fn jitter(&self, _distance: isize, rng: &mut impl Rng) -> isize {
+		if self.variance <= 0 { return 0 }
+		rng.gen_range(-self.variance..self.variance)
+	}
+}
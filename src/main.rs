@@ -16,15 +16,28 @@ extern crate bitflags;
 #[macro_use]
 extern crate slotmap;
 
-use std::{fs::File, io::{self, BufReader, prelude::*}};
+use std::{fs::File, io::{self, BufReader, BufWriter, prelude::*}};
 use anyhow::Context;
 
 pub mod internet;
-use internet::{NetAddr, NetSim, CustomNode};
+use internet::{NetAddr, NetSim, CustomNode, InternetError};
+use internet::conditions::{LinkConditions, Bandwidth};
+use internet::latency::{LatencyType, GeometricLatency, HeavyTailedLatency};
+use internet::topology::TransitStub;
+use internet::churn::{self, ChurnModel, SessionLength};
+use internet::partition::{Fault, FaultScope};
+use internet::nat::{Nat, NatProfile, NatType, MAPPING_TIMEOUT};
+use internet::mobility::RandomWaypoint;
+use internet::congestion::{Congestion, Diurnal, EpisodeModel, RegionLoad};
+use internet::access::{AccessLink, ClassMix, NodeClass};
+use internet::capture::{self, CaptureFilter};
 pub mod node;
 use node::{Node, NodeAction, NodeID};
+use node::estimator::Estimator;
 pub mod plot;
-use rand::SeedableRng;
+pub mod rng;
+use rng::SimRng;
+use rand::{Rng, SeedableRng};
 
 const CACHE_FILE: &str = "./target/net.cache";
 
@@ -33,15 +46,17 @@ fn main() -> anyhow::Result<()> {
 	println!("Hello, Network!");
 	let _ = std::fs::create_dir_all("target/images");
 
-	let rng = &mut rand::rngs::SmallRng::seed_from_u64(0);
+	let rng = &mut SimRng::seed_from_u64(0);
 	// Try and read cache file, else gen new network
 	let mut internet = NetSim::new();
 	if let Ok(cache_file) = File::open(CACHE_FILE) {
-		if let Ok(cached_network) = bincode::deserialize_from(BufReader::new(cache_file)) {
-			println!("Loaded Cached Network: {}", CACHE_FILE);
-			internet = cached_network;
-		} else {
-			println!("Found cache file but was unable to deserialize it, perhaps it is from an older version?");
+		match NetSim::load_snapshot(BufReader::new(cache_file)) {
+			Ok((cached_network, cached_rng, header)) => {
+				println!("Loaded Cached Network: {} (snapshot version {}, {} nodes at tick {})", CACHE_FILE, header.version, header.nodes, header.ticks);
+				internet = cached_network;
+				*rng = cached_rng;
+			}
+			Err(err) => println!("Found cache file but was unable to load it: {:#}", anyhow::Error::from(err)),
 		}
 	}
 
@@ -62,7 +77,13 @@ fn main() -> anyhow::Result<()> {
 	Ok(())
 }
 
-fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl rand::Rng) -> anyhow::Result<()> {
+/// Peak resident memory of this process (Linux only)
+fn peak_memory() -> Option<String> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	status.lines().find(|l| l.starts_with("VmHWM:")).map(|l| l["VmHWM:".len()..].trim().to_owned())
+}
+
+fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut SimRng) -> anyhow::Result<()> {
 	match input {
 		["help"] => {
 			println!(
@@ -70,8 +91,15 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 						command list:
 						add <NodeID> - add a node to network
 						del <NetAddr> - delete node from network
+						leave <NetAddr> - have a node leave the network gracefully, telling its remotes and the DHT
 						tick <usize> - run network a certain number of iterations
 						net <subcommand> - network operations
+						churn <subcommand> - nodes joining and leaving while ticking
+						partition <subcommand> - cut links between parts of the network for a while
+						mobility <subcommand> - move nodes around while ticking
+						checkpoint <subcommand> - keep copies of the simulation in memory
+						rewind [tick] - go back to a checkpoint and re-run up to a tick
+						capture <subcommand> - record node ticks and packets, view and replay them
 						graph - output graph of current network as targe/images/network_snapshot.png
 						list <subcommand> - list various aspects of network
 						print <NetAddr> - pretty-print a node on the network
@@ -85,55 +113,306 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 			if let Ok(node_id) = id.parse::<NodeID>() {
 				let node = Node::new(node_id, internet.lease());
 				println!("Adding Node: {:?}", node);
-				internet.add_node(node, rng);
+				internet.add_node(node, rng)?;
 			} else { bail!("add: {:?} cannot be parsed as NodeID", id) }
 		}
 		["add"] => bail!("add: requires second argument to be NodeID"),
 		// Removing Nodes
 		["del", addr] => {
 			let net_addr = addr.parse::<NetAddr>().context(anyhow!("del: {:?} cannot be parsed as NetAddr", addr))?;
-			internet.del_node(net_addr);
+			let node = internet.del_node(net_addr)?;
+			println!("Removed Node: {:?}", node);
 		}
 		["del"] => bail!("tick: requires second argument to be an existing NetAddr"),
+		["leave", addr] => {
+			let net_addr = addr.parse::<NetAddr>().context(anyhow!("leave: {:?} cannot be parsed as NetAddr", addr))?;
+			let node = internet.leave_node(net_addr, rng)?;
+			println!("Node left: {}", node);
+		}
+		["leave"] => bail!("leave: requires second argument to be an existing NetAddr"),
 		["tick", times] => {
 			let num_ticks = times.parse::<usize>().context("tick: number of ticks must be type usize")?;
 			println!("Running {} ticks", num_ticks);
-			internet.tick(num_ticks, rng);
+			internet.tick_checkpointed(num_ticks, rng)?;
 		}
 		["tick"] => bail!("tick: requires second argument to be a valid positive integer"),
+		["checkpoint", subcommand @ ..] => {
+			match subcommand {
+				[] => {
+					let checkpoint = internet.checkpoint(rng)?;
+					println!("Took checkpoint at tick {} ({} bytes)", checkpoint.ticks, checkpoint.size());
+				}
+				["every", interval] => {
+					internet.checkpoints.interval = Some(interval.parse::<usize>().context("checkpoint: every: interval must be usize (ticks)")?);
+					internet.checkpoint(rng)?;
+				}
+				["off"] => internet.checkpoints.interval = None,
+				["keep", max] => internet.checkpoints.max = max.parse::<usize>().context("checkpoint: keep: must be usize")?,
+				["list"] => {
+					internet.checkpoints.iter().for_each(|checkpoint| println!("tick {}: {} bytes", checkpoint.ticks, checkpoint.size()));
+					println!("{} checkpoints, taking one every {:?} ticks, keeping {}", internet.checkpoints.len(), internet.checkpoints.interval, internet.checkpoints.max);
+				}
+				["clear"] => internet.checkpoints.clear(),
+				_ => bail!("checkpoint: must pass valid subcommand: (none to take one now), every <ticks>, off, keep <count>, list, clear"),
+			}
+		}
+		["capture", subcommand @ ..] => {
+			match subcommand {
+				[] => match &internet.capture {
+					Some(capture) => println!("Capturing {} nodes, {} records so far", if capture.nodes.is_empty() { "all".to_owned() } else { capture.nodes.len().to_string() }, capture.records),
+					None => println!("Not capturing"),
+				},
+				["start", filepath, addrs @ ..] => {
+					let nodes = addrs.iter().map(|addr| addr.parse::<NetAddr>()).collect::<Result<_, _>>().context("capture: start: nodes must be NetAddrs")?;
+					let file = File::create(filepath).context("capture: start: failed to create file (check perms)")?;
+					internet.start_capture(BufWriter::new(file), nodes)?;
+				}
+				["stop"] => match internet.stop_capture()? {
+					Some(records) => println!("Captured {} records", records),
+					None => println!("Not capturing"),
+				},
+				["view", log_path, ndjson_path, filters @ ..] => {
+					let mut filter = CaptureFilter::default();
+					let (mut from, mut to) = (0, usize::MAX);
+					for pair in filters.chunks(2) {
+						match pair {
+							["node", addr] => { filter.nodes.insert(addr.parse::<NetAddr>().context("capture: view: node: must pass NetAddr")?); }
+							["kind", kind] => { filter.kinds.insert(kind.to_string()); }
+							["from", time] => from = time.parse().context("capture: view: from: must be usize (ticks)")?,
+							["to", time] => to = time.parse().context("capture: view: to: must be usize (ticks)")?,
+							_ => bail!("capture: view: filters are node <NetAddr>, kind <variant>, from <tick>, to <tick>"),
+						}
+					}
+					if from > 0 || to < usize::MAX { filter.time = Some(from..to); }
+					let records = capture::read_capture::<Node>(BufReader::new(File::open(log_path).context("capture: view: failed to open log")?))?;
+					let file = File::create(ndjson_path).context("capture: view: failed to create file (check perms)")?;
+					let written = capture::write_capture_view(&records, &filter, BufWriter::new(file))?;
+					println!("Wrote {} packets out of {} records", written, records.len());
+				}
+				["replay", log_path, addr] => {
+					let net_addr = addr.parse::<NetAddr>().context("capture: replay: must pass NetAddr")?;
+					let records = capture::read_capture::<Node>(BufReader::new(File::open(log_path).context("capture: replay: failed to open log")?))?;
+					let (node, report) = capture::replay_capture(records, net_addr)?;
+					match &report.divergence {
+						None => println!("Replayed {} ticks of NetAddr({}), the node sent the same packets every tick", report.ticks, net_addr),
+						Some(divergence) => {
+							println!("NetAddr({}) diverged at tick {} after {} replayed ticks", net_addr, divergence.time, report.ticks);
+							println!("captured:");
+							divergence.expected.iter().for_each(|packet| println!("\t{}", packet));
+							println!("replayed:");
+							divergence.replayed.iter().for_each(|packet| println!("\t{}", packet));
+						}
+					}
+					println!("{}", node);
+				}
+				_ => bail!("capture: must pass valid subcommand: start <filepath> [NetAddr...], stop, view <log> <ndjson filepath> [node <NetAddr> | kind <variant> | from <tick> | to <tick>]..., replay <log> <NetAddr>"),
+			}
+		}
+		["rewind", time @ ..] => {
+			let time = match time {
+				[] => internet.checkpoints.iter().last().context("rewind: there are no checkpoints")?.ticks,
+				[time] => time.parse::<usize>().context("rewind: tick must be usize")?,
+				_ => bail!("rewind: [tick]"),
+			};
+			let restored = internet.rewind(time, rng)?;
+			println!("Restored checkpoint at tick {}, re-ran {} ticks", restored, time - restored);
+		}
+		["churn", subcommand @ ..] => {
+			match subcommand {
+				[] => match &internet.churn {
+					Some(churn) => println!("{:?}, {} samples", churn.model, churn.samples.len()),
+					None => println!("Churn is off"),
+				},
+				["off"] => internet.churn = None,
+				["start", arrival_rate, crash_ratio, session @ ..] => {
+					let arrival_rate = arrival_rate.parse::<f64>().context("churn: start: arrival rate must be f64 (nodes per tick)")?;
+					let crash_ratio = crash_ratio.parse::<f64>().context("churn: start: crash ratio must be f64")?;
+					let session = match session {
+						["exp", mean] => SessionLength::Exponential { mean: mean.parse().context("churn: start: exp: mean must be f64 (ticks)")? },
+						["weibull", shape, scale] => SessionLength::Weibull {
+							shape: shape.parse().context("churn: start: weibull: shape must be f64")?,
+							scale: scale.parse().context("churn: start: weibull: scale must be f64 (ticks)")?,
+						},
+						_ => bail!("churn: start: session length must be exp <mean> or weibull <shape> <scale>"),
+					};
+					internet.start_churn(ChurnModel::new(arrival_rate, session, crash_ratio).context("churn: start")?, rng);
+				}
+				["interval", interval] => {
+					let churn = internet.churn.as_mut().context("churn: interval: churn is off")?;
+					churn.model.sample_interval = interval.parse().context("churn: interval: must be usize (ticks)")?;
+				}
+				["metrics"] => {
+					let churn = internet.churn.as_ref().context("churn: metrics: churn is off")?;
+					churn.samples.iter().for_each(|sample| println!("{:?}", sample));
+				}
+				["metrics", filepath] => {
+					let churn = internet.churn.as_ref().context("churn: metrics: churn is off")?;
+					churn.write_csv(File::create(filepath).context("churn: metrics: failed to create file (check perms)")?).context("churn: metrics: failed to write csv")?;
+				}
+				_ => bail!("churn: must pass valid subcommand: start <arrival_rate> <crash_ratio> exp <mean> | weibull <shape> <scale>, off, interval <ticks>, metrics [csv filepath]"),
+			}
+		}
+		["partition", subcommand @ ..] => {
+			let parse_addrs = |list: &str| -> anyhow::Result<std::collections::BTreeSet<NetAddr>> {
+				list.split(',').map(|addr| addr.parse::<NetAddr>().context(anyhow!("partition: {:?} cannot be parsed as NetAddr", addr))).collect()
+			};
+			let parse_f32 = |arg: &str| arg.parse::<f32>().context(anyhow!("partition: {:?} cannot be parsed as f32", arg));
+			let (scope, window) = match subcommand {
+				[] | ["list"] => {
+					let now = internet.ticks();
+					internet.router.faults.iter().enumerate().for_each(|(id, fault)| println!("{}: {:?}, active: {}", id, fault, fault.is_active(now)));
+					return Ok(())
+				}
+				["clear"] => { internet.router.faults.clear(); return Ok(()) }
+				["measure", id, ticks, interval, output @ ..] => {
+					let id = id.parse::<usize>().context("partition: measure: fault id must be usize")?;
+					let fault = internet.router.faults.get(id).cloned().context("partition: measure: no fault with that id, see `partition list`")?;
+					let ticks = ticks.parse::<usize>().context("partition: measure: ticks must be usize")?;
+					let interval = interval.parse::<usize>().context("partition: measure: interval must be usize")?;
+					let samples = internet.measure_partition(&fault, ticks, interval, churn::ROUTING_SAMPLES, rng)?;
+					match output {
+						[] => samples.iter().for_each(|sample| println!("{:?}", sample)),
+						[filepath] => {
+							let mut wtr = csv::Writer::from_writer(File::create(filepath).context("partition: measure: failed to create file (check perms)")?);
+							for sample in samples { wtr.serialize(sample).context("partition: measure: failed to write csv")?; }
+							wtr.flush()?;
+						}
+						_ => bail!("partition: measure: <id> <ticks> <interval> [csv filepath]"),
+					}
+					return Ok(())
+				}
+				["split", window @ ..] => {
+					let field = &internet.router.field_dimensions.0;
+					(FaultScope::Split { x: (field.start + field.end) as f32 / 2. }, window)
+				}
+				["region", x0, y0, x1, y1, window @ ..] => {
+					let (x0, y0, x1, y1) = (parse_f32(x0)?, parse_f32(y0)?, parse_f32(x1)?, parse_f32(y1)?);
+					(FaultScope::Region { min: nalgebra::Point2::new(x0.min(x1), y0.min(y1)), max: nalgebra::Point2::new(x0.max(x1), y0.max(y1)) }, window)
+				}
+				["isolate", addrs, window @ ..] => (FaultScope::Isolate(parse_addrs(addrs)?), window),
+				["between", group_a, group_b, window @ ..] => (FaultScope::Between(parse_addrs(group_a)?, parse_addrs(group_b)?), window),
+				["link", src, dest, window @ ..] => {
+					let link = (src.parse::<NetAddr>().context("partition: link: must pass source NetAddr")?, dest.parse::<NetAddr>().context("partition: link: must pass destination NetAddr")?);
+					(FaultScope::Links(std::iter::once(link).collect()), window)
+				}
+				_ => bail!("partition: must pass valid subcommand: list, clear, measure <id> <ticks> <interval> [csv], split | region <x0> <y0> <x1> <y1> | isolate <a,b,..> | between <a,b,..> <c,d,..> | link <a> <b>, followed by <duration> [delay]"),
+			};
+			// Fault window starts `delay` ticks from now
+			let (duration, delay) = match window {
+				[duration] => (duration.parse::<usize>().context("partition: duration must be usize")?, 0),
+				[duration, delay] => (duration.parse::<usize>().context("partition: duration must be usize")?, delay.parse::<usize>().context("partition: delay must be usize")?),
+				_ => bail!("partition: fault must end with <duration> [delay]"),
+			};
+			let start = internet.ticks() + delay;
+			internet.router.faults.push(Fault::new(scope, start, start + duration));
+			println!("Added fault {}: {:?}", internet.router.faults.len() - 1, internet.router.faults.last().unwrap());
+		}
+		["mobility", subcommand @ ..] => {
+			let parse_f32 = |arg: &str| arg.parse::<f32>().context(anyhow!("mobility: {:?} cannot be parsed as f32", arg));
+			match subcommand {
+				[] => match &internet.mobility {
+					Some(mobility) => println!("{:?}, {} moving, {} moved, {} pending teleports", mobility.model, mobility.moving(), mobility.moved.len(), mobility.pending_teleports()),
+					None => println!("Mobility is off"),
+				},
+				["off"] => internet.stop_mobility(),
+				["waypoint", fraction, min_speed, max_speed, max_pause] => {
+					let model = RandomWaypoint::new(
+						fraction.parse().context("mobility: waypoint: fraction must be f64")?,
+						parse_f32(min_speed)?, parse_f32(max_speed)?,
+						max_pause.parse().context("mobility: waypoint: max_pause must be usize (ticks)")?,
+					);
+					internet.start_mobility(model, rng);
+				}
+				["interval", interval] => {
+					let mobility = internet.mobility.as_mut().context("mobility: interval: mobility is off")?;
+					mobility.update_interval = interval.parse().context("mobility: interval: must be usize (ticks)")?;
+				}
+				["teleport", addr, x, y, delay @ ..] => {
+					let net_addr = addr.parse::<NetAddr>().context("mobility: teleport: must pass NetAddr")?;
+					let delay = match delay {
+						[] => 0,
+						[delay] => delay.parse::<usize>().context("mobility: teleport: delay must be usize")?,
+						_ => bail!("mobility: teleport: <NetAddr> <x> <y> [delay]"),
+					};
+					internet.teleport(net_addr, nalgebra::Point2::new(parse_f32(x)?, parse_f32(y)?), internet.ticks() + delay)?;
+				}
+				["report", output @ ..] => {
+					let reports = internet.mobility_report();
+					match output {
+						[] => reports.iter().for_each(|report| println!("{:?}", report)),
+						[filepath] => {
+							let mut wtr = csv::Writer::from_writer(File::create(filepath).context("mobility: report: failed to create file (check perms)")?);
+							for report in &reports { wtr.serialize(report).context("mobility: report: failed to write csv")?; }
+							wtr.flush()?;
+						}
+						_ => bail!("mobility: report [csv filepath]"),
+					}
+					let (peer_entries, peer_entries_current) = reports.iter().fold((0, 0), |(all, current), report| (all + report.peer_entries, current + report.peer_entries_current));
+					println!("{} nodes moved: {} updated their route coordinate, {} have a current DHT entry, {}/{} peer list entries are current",
+						reports.len(), reports.iter().filter(|report| report.coord_updated).count(), reports.iter().filter(|report| report.dht_current).count(), peer_entries_current, peer_entries);
+				}
+				_ => bail!("mobility: must pass valid subcommand: waypoint <fraction> <min_speed> <max_speed> <max_pause>, off, interval <ticks>, teleport <NetAddr> <x> <y> [delay], report [csv filepath]"),
+			}
+		}
 		// Configuring network
 		["net", subcommand @ ..] => {
 			match subcommand {
 				["save", filepath] => {
-					let mut file = File::create(filepath).context("net: save: failed to create file (check perms)")?;
-					let data = bincode::serialize(&internet).context("net: save: failed to serialize network")?;
-					file.write_all(&data).context("net: save: failed to write to file")?;
+					let file = File::create(filepath).context("net: save: failed to create file (check perms)")?;
+					internet.save_snapshot(rng, BufWriter::new(file)).context("net: save: failed to save network")?;
 				}
 				["save"] => bail!("net: save: must pass file path to save network"),
 				["load", filepath] => {
 					let file = File::open(filepath).context("net: load: failed to open file (check perms)")?;
-					let internet_new: NetSim<Node> = bincode::deserialize_from(BufReader::new(file)).context("net: load: failed to deserialize network")?;
+					let (internet_new, rng_new, header) = NetSim::load_snapshot(BufReader::new(file)).context("net: load: failed to deserialize network")?;
 					*internet = internet_new;
+					*rng = rng_new;
+					println!("Loaded snapshot version {}: {} nodes at tick {}, seed {}", header.version, header.nodes, header.ticks, header.seed);
 					//internet = bincode::deserialize_from(BufReader::new(file)).context("net: save: failed to serialize object")?;
 				}
 				["load"] => bail!("net: load: must pass file path to load network"),
+				["info", filepath] => {
+					let file = File::open(filepath).context("net: info: failed to open file (check perms)")?;
+					let header = NetSim::<Node>::read_snapshot_header(BufReader::new(file)).context("net: info: failed to read snapshot header")?;
+					println!("{:#?}", header);
+				}
+				["export", filepath] => {
+					let file = File::create(filepath).context("net: export: failed to create file (check perms)")?;
+					internet.export_json(rng, BufWriter::new(file)).context("net: export: failed to export network")?;
+				}
+				["import", filepath] => {
+					let file = File::open(filepath).context("net: import: failed to open file (check perms)")?;
+					let (internet_new, rng_new, header) = NetSim::import_json(BufReader::new(file)).context("net: import: failed to import network")?;
+					*internet = internet_new;
+					*rng = rng_new;
+					println!("Imported snapshot version {}: {} nodes at tick {}, seed {}", header.version, header.nodes, header.ticks, header.seed);
+				}
+				["info" | "export" | "import"] => bail!("net: {}: must pass file path", subcommand[0]),
 				["cache"] => {
-					let mut cache_file = File::create(CACHE_FILE).context("net: cache: can't create ./net.cache (check perms?)")?;
-					let data = bincode::serialize(&internet).context("net: cache: failed to serialize network")?;
-					cache_file.write_all(&data).context("net: cache: failed to write to cache file")?;
+					let cache_file = File::create(CACHE_FILE).context("net: cache: can't create ./net.cache (check perms?)")?;
+					internet.save_snapshot(rng, BufWriter::new(cache_file)).context("net: cache: failed to write to cache file")?;
 					println!("Created network cache");
 				}
-				["clear"] => *internet = NetSim::new(),
-				["gen", number] => {
-					*internet = NetSim::new();
+				["clear"] => internet.clear(),
+				["gen", number, estimator @ ..] => {
+					let estimator = match estimator {
+						[] => Estimator::default(),
+						[estimator] => estimator.parse::<Estimator>().map_err(|err| anyhow!("net: gen: {}", err))?,
+						_ => bail!("net: gen: <number> [estimator]"),
+					};
+					internet.clear();
+					let start_time = std::time::Instant::now();
 
 					let num_nodes = number.parse::<u32>().context("net: gen: <number:u32> for first argument")?;
 					for i in 0..num_nodes {
-						let node2 = Node::new(i, internet.lease());
-						internet.add_node(node2, rng);
+						let node2 = Node::new(i, internet.lease()).with_estimator(estimator.clone());
+						internet.add_node(node2, rng)?;
 					}
+					// Everyone bootstraps off node 0, it has to be publicly reachable
+					if let Some(rn) = internet.router.node_map.get_mut(&0) { rn.nat = None; }
 		
 					let snapshots_per_boot = 10;
+					let progress_interval = (num_nodes as usize / 20).max(1);
 					for i in 1..(internet.nodes.len()+0) {
 						let node = internet.node_mut(i as NetAddr)?;
 						node.action(NodeAction::Bootstrap(0,0));
@@ -141,11 +420,264 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 							internet.tick(4000/snapshots_per_boot, rng);
 							//plot::default_graph(&internet, &internet.router.field_dimensions, &format!("target/images/{:0>6}.png", (i-1)*snapshots_per_boot+_j), (1280,720))?;
 						}
+						if i % progress_interval == 0 {
+							let elapsed = start_time.elapsed().as_secs_f64();
+							log::info!("net gen: bootstrapped {}/{} nodes after {:.1}s, {} events ({:.0} events/s), peak memory: {}",
+								i, num_nodes, elapsed, internet.router.stats.events, internet.router.stats.events as f64 / elapsed,
+								peak_memory().unwrap_or_else(|| "unknown".into()));
+						}
 					}
 					internet.tick(10000, rng);
+
+					let elapsed = start_time.elapsed();
+					let events = internet.router.stats.events;
+					println!("Generated {} nodes in {:.2?}: {} ticks, {} events ({:.0} events/s), {} packets, peak memory: {}",
+						internet.nodes.len(), elapsed, internet.ticks(), events, events as f64 / elapsed.as_secs_f64(),
+						internet.router.stats.sent, peak_memory().unwrap_or_else(|| "unknown".into()));
 				}
 				["print"] => println!("{:#?}", internet),
-				_ => bail!("net: must pass valid subcommand: save <filepath>, load <filepath>, cache, clear, gen <number>, print"),
+				["seed"] => println!("{}", internet.seed),
+				["threads"] => println!("{}", internet.threads),
+				["threads", threads] => {
+					let threads = threads.parse::<usize>().context("net: threads: number of threads must be usize")?;
+					internet.threads = if threads == 0 { std::thread::available_parallelism().map_or(1, |n| n.get()) } else { threads };
+					println!("Ticking nodes on {} threads", internet.threads);
+				}
+				["seed", seed] => {
+					// Same seed and same commands afterwards always produce the same simulation
+					let seed = seed.parse::<u64>().context("net: seed: seed must be u64")?;
+					internet.reseed(seed);
+					*rng = SimRng::seed_from_u64(seed);
+				}
+				["import-latency", filepath, options @ ..] => {
+					let ticks_per_unit = match options {
+						[] => 1.,
+						[scale] => scale.parse::<f64>().context("net: import-latency: ticks_per_unit must be f64")?,
+						_ => bail!("net: import-latency: <filepath> [ticks_per_unit]"),
+					};
+					let (seed, threads) = (internet.seed, internet.threads);
+					*internet = NetSim::from_latency_matrix(filepath, ticks_per_unit).context("net: import-latency: failed to import latency matrix")?;
+					// Settings made with `net seed` and `net threads` carry over to the new network
+					internet.seed = seed;
+					internet.threads = threads;
+					if let LatencyType::Matrix(matrix) = &internet.router.latency_model {
+						println!("Imported {}x{} latency matrix, triangle inequality violations: {:.2}%", matrix.size, matrix.size, matrix.triangle_violations(10000, rng) * 100.);
+						println!("Run `net gen {}` to populate the network", matrix.size);
+					}
+				}
+				["estimator"] => {
+					let mut counts = std::collections::BTreeMap::new();
+					for node in internet.nodes.values() { *counts.entry(node.estimator.name()).or_insert(0) += 1; }
+					println!("{:?}", counts);
+				}
+				["estimator", estimator] => {
+					// Nodes joining later use the estimator of the node they bootstrap from
+					let estimator = estimator.parse::<Estimator>().map_err(|err| anyhow!("net: estimator: {}", err))?;
+					internet.nodes.values_mut().for_each(|node| node.estimator = estimator.clone());
+				}
+				["impair", subcommand @ ..] => {
+					let parse_conditions = |args: &[&str]| -> anyhow::Result<LinkConditions> {
+						match args {
+							[loss, duplication, reordering] => Ok(LinkConditions::new(
+								loss.parse().context("net: impair: loss must be f64")?,
+								duplication.parse().context("net: impair: duplication must be f64")?,
+								reordering.parse().context("net: impair: reordering must be f64")?,
+							).context("net: impair")?),
+							_ => bail!("net: impair: requires <loss> <duplication> <reordering> probabilities"),
+						}
+					};
+					match subcommand {
+						[] => println!("{:?}", internet.router.conditions),
+						["clear"] => {
+							internet.router.conditions = LinkConditions::default();
+							internet.router.node_map.values_mut().for_each(|rn| { rn.conditions = None; rn.link_conditions.clear(); });
+						}
+						["node", addr, args @ ..] => {
+							let net_addr = addr.parse::<NetAddr>().context("net: impair: node: must pass NetAddr")?;
+							let conditions = parse_conditions(args)?;
+							internet.router.node_map.get_mut(&net_addr).ok_or(InternetError::NoNodeError { net_addr })?.conditions = Some(conditions);
+						}
+						["link", src, dest, args @ ..] => {
+							let src_addr = src.parse::<NetAddr>().context("net: impair: link: must pass source NetAddr")?;
+							let dest_addr = dest.parse::<NetAddr>().context("net: impair: link: must pass destination NetAddr")?;
+							let conditions = parse_conditions(args)?;
+							internet.router.node_map.get_mut(&src_addr).ok_or(InternetError::NoNodeError { net_addr: src_addr })?.link_conditions.insert(dest_addr, conditions);
+						}
+						args => internet.router.conditions = parse_conditions(args)?,
+					}
+				}
+				["bandwidth", subcommand @ ..] => {
+					let parse_bandwidth = |args: &[&str]| -> anyhow::Result<Bandwidth> {
+						match args {
+							[uplink, downlink, buffer] => Ok(Bandwidth::new(
+								uplink.parse().context("net: bandwidth: uplink must be f64 (bytes per tick)")?,
+								downlink.parse().context("net: bandwidth: downlink must be f64 (bytes per tick)")?,
+								buffer.parse().context("net: bandwidth: buffer must be usize (bytes)")?,
+							).context("net: bandwidth")?),
+							_ => bail!("net: bandwidth: requires <uplink> <downlink> <buffer>"),
+						}
+					};
+					match subcommand {
+						[] => println!("{:?}", internet.router.bandwidth),
+						["clear"] => {
+							internet.router.bandwidth = None;
+							internet.router.node_map.values_mut().for_each(|rn| rn.bandwidth = None);
+						}
+						["node", addr, args @ ..] => {
+							let net_addr = addr.parse::<NetAddr>().context("net: bandwidth: node: must pass NetAddr")?;
+							let bandwidth = parse_bandwidth(args)?;
+							internet.router.node_map.get_mut(&net_addr).ok_or(InternetError::NoNodeError { net_addr })?.bandwidth = Some(bandwidth);
+						}
+						args => internet.router.bandwidth = Some(parse_bandwidth(args)?),
+					}
+				}
+				["nat", subcommand @ ..] => {
+					let parse_nat = |args: &[&str]| -> anyhow::Result<(NatType, usize)> {
+						let (nat_type, timeout) = match args {
+							[nat_type] => (*nat_type, None),
+							[nat_type, timeout] => (*nat_type, Some(timeout.parse::<usize>().context("net: nat: timeout must be usize (ticks)")?)),
+							_ => bail!("net: nat: requires <full-cone|restricted|symmetric> [timeout]"),
+						};
+						let nat_type = match nat_type {
+							"full-cone" => NatType::FullCone,
+							"restricted" => NatType::Restricted,
+							"symmetric" => NatType::Symmetric,
+							_ => bail!("net: nat: type must be full-cone, restricted or symmetric"),
+						};
+						Ok((nat_type, timeout.unwrap_or(MAPPING_TIMEOUT)))
+					};
+					match subcommand {
+						[] => {
+							let natted = internet.router.node_map.values().filter(|rn| rn.nat.is_some()).count();
+							println!("{:?}, {} of {} nodes behind a NAT", internet.router.nat_profile, natted, internet.router.node_map.len());
+						}
+						["clear"] => {
+							internet.router.nat_profile = None;
+							internet.router.node_map.values_mut().for_each(|rn| rn.nat = None);
+						}
+						["node", addr, args @ ..] => {
+							let net_addr = addr.parse::<NetAddr>().context("net: nat: node: must pass NetAddr")?;
+							let nat = match args {
+								["none"] => None,
+								args => { let (nat_type, timeout) = parse_nat(args)?; Some(Nat::new(nat_type, timeout)) }
+							};
+							internet.router.node_map.get_mut(&net_addr).ok_or(InternetError::NoNodeError { net_addr })?.nat = nat;
+						}
+						[fraction, args @ ..] => {
+							// Applies to nodes already in the network and to nodes added later
+							let fraction = fraction.parse::<f64>().context("net: nat: fraction of nodes must be f64")?;
+							let (nat_type, timeout) = parse_nat(args)?;
+							let profile = NatProfile::new(fraction, nat_type, timeout);
+							internet.router.nat_profile = Some(profile);
+							internet.router.node_map.values_mut().for_each(|rn| rn.nat = profile.roll(rng));
+						}
+					}
+				}
+				["access", subcommand @ ..] => {
+					let parse_f64 = |arg: &str| arg.parse::<f64>().context(anyhow!("net: access: {:?} cannot be parsed as f64", arg));
+					match subcommand {
+						[] => {
+							let mut counts = std::collections::BTreeMap::new();
+							internet.router.node_map.values().filter_map(|rn| rn.access).for_each(|link| *counts.entry(link.class).or_insert(0) += 1);
+							println!("{:?}, nodes by class: {:?}", internet.router.access_mix, counts);
+						}
+						["clear"] => {
+							internet.router.access_mix = None;
+							internet.router.node_map.values_mut().for_each(|rn| rn.access = None);
+						}
+						["node", addr, class] => {
+							let net_addr = addr.parse::<NetAddr>().context("net: access: node: must pass NetAddr")?;
+							let access = match *class {
+								"none" => None,
+								class => Some(AccessLink::new(class.parse::<NodeClass>().map_err(|err| anyhow!("net: access: node: {}", err))?, rng)),
+							};
+							internet.router.node_map.get_mut(&net_addr).ok_or(InternetError::NoNodeError { net_addr })?.access = access;
+						}
+						["error", samples @ ..] => {
+							let samples = match samples {
+								[] => 10000,
+								[samples] => samples.parse::<usize>().context("net: access: error: samples must be usize")?,
+								_ => bail!("net: access: error [samples]"),
+							};
+							println!("{:?}", internet.embedding_error(samples, rng));
+						}
+						[datacenter, broadband, mobile, satellite] => {
+							// Applies to nodes already in the network and to nodes added later
+							let mix = ClassMix::new(parse_f64(datacenter)?, parse_f64(broadband)?, parse_f64(mobile)?, parse_f64(satellite)?);
+							internet.router.access_mix = Some(mix);
+							internet.router.node_map.values_mut().for_each(|rn| rn.access = mix.roll(rng));
+						}
+						_ => bail!("net: access: valid subcommands: clear, node <NetAddr> <class>|none, error [samples], <datacenter> <broadband> <mobile> <satellite> (class weights)"),
+					}
+				}
+				["congestion", subcommand @ ..] => {
+					let parse_f64 = |arg: &str| arg.parse::<f64>().context(anyhow!("net: congestion: {:?} cannot be parsed as f64", arg));
+					let parse_f32 = |arg: &str| arg.parse::<f32>().context(anyhow!("net: congestion: {:?} cannot be parsed as f32", arg));
+					match subcommand {
+						[] => println!("{:?}", internet.router.congestion),
+						["clear"] => internet.router.congestion = None,
+						["measure", ticks, interval, output @ ..] => {
+							let ticks = ticks.parse::<usize>().context("net: congestion: measure: ticks must be usize")?;
+							let interval = interval.parse::<usize>().context("net: congestion: measure: interval must be usize")?;
+							let samples = internet.measure_congestion(ticks, interval, rng)?;
+							match output {
+								[] => samples.iter().for_each(|sample| println!("{:?}", sample)),
+								[filepath] => {
+									let mut wtr = csv::Writer::from_writer(File::create(filepath).context("net: congestion: measure: failed to create file (check perms)")?);
+									for sample in samples { wtr.serialize(sample).context("net: congestion: measure: failed to write csv")?; }
+									wtr.flush()?;
+								}
+								_ => bail!("net: congestion: measure <ticks> <interval> [csv filepath]"),
+							}
+						}
+						["diurnal", period, amplitude] => {
+							let diurnal = Diurnal { period: period.parse().context("net: congestion: diurnal: period must be usize (ticks)")?, amplitude: parse_f64(amplitude)? };
+							internet.router.congestion.get_or_insert_with(Congestion::default).diurnal = Some(diurnal);
+						}
+						["episodes", rate, mean_duration, radius, severity] => {
+							let episodes = EpisodeModel { rate: parse_f64(rate)?, mean_duration: parse_f64(mean_duration)?, radius: parse_f32(radius)?, severity: parse_f64(severity)? };
+							let now = internet.ticks();
+							internet.router.congestion.get_or_insert_with(Congestion::default).set_episodes(episodes, now, rng);
+						}
+						["region", x0, y0, x1, y1, multiplier] => {
+							let (x0, y0, x1, y1) = (parse_f32(x0)?, parse_f32(y0)?, parse_f32(x1)?, parse_f32(y1)?);
+							let region = RegionLoad { min: nalgebra::Point2::new(x0.min(x1), y0.min(y1)), max: nalgebra::Point2::new(x0.max(x1), y0.max(y1)), multiplier: parse_f64(multiplier)? };
+							internet.router.congestion.get_or_insert_with(Congestion::default).regions.push(region);
+						}
+						_ => bail!("net: congestion: valid subcommands: clear, diurnal <period> <amplitude>, episodes <rate> <mean_duration> <radius> <severity>, region <x0> <y0> <x1> <y1> <multiplier>, measure <ticks> <interval> [csv]"),
+					}
+				}
+				["import-latency"] => bail!("net: import-latency: must pass file path of RTT matrix"),
+				["latency", model @ ..] => {
+					let latency_model = match model {
+						["geometric"] => LatencyType::Geometric(GeometricLatency::default()),
+						["geometric", variance] => LatencyType::Geometric(GeometricLatency { variance: variance.parse().context("net: latency: variance must be isize")? }),
+						["lognormal", sigma] => LatencyType::HeavyTailed(HeavyTailedLatency::log_normal(sigma.parse().context("net: latency: sigma must be f64")?).context("net: latency")?),
+						["pareto", alpha, scale] => LatencyType::HeavyTailed(HeavyTailedLatency::pareto(
+							alpha.parse().context("net: latency: alpha must be f64")?,
+							scale.parse().context("net: latency: scale must be f64")?,
+						).context("net: latency")?),
+						["topology", params @ ..] => {
+							let params = match params {
+								[] => TransitStub::default(),
+								[domains, transit_routers, stubs, stub_routers] => TransitStub::new(
+									domains.parse().context("net: latency: topology: transit_domains must be usize")?,
+									transit_routers.parse().context("net: latency: topology: transit_routers must be usize")?,
+									stubs.parse().context("net: latency: topology: stubs_per_transit must be usize")?,
+									stub_routers.parse().context("net: latency: topology: stub_routers must be usize")?,
+								),
+								_ => bail!("net: latency: topology [<transit_domains> <transit_routers> <stubs_per_transit> <stub_routers>]"),
+							};
+							let topology = params.generate(&internet.router.field_dimensions, rng).context("net: latency: topology")?;
+							println!("Generated transit-stub topology: {} routers in {} ASes, {} links, mean path stretch {:.2}", topology.routers.len(), topology.as_count(), topology.links.len(), topology.stretch(10000, rng));
+							LatencyType::Topology(topology)
+						}
+						[] => { println!("{:?}", internet.router.latency_model); return Ok(()) }
+						_ => bail!("net: latency: valid models: geometric [variance], lognormal <sigma>, pareto <alpha> <scale>, topology [<transit_domains> <transit_routers> <stubs_per_transit> <stub_routers>]"),
+					};
+					internet.router.set_latency_model(latency_model, rng);
+				}
+				_ => bail!("net: must pass valid subcommand: save <filepath>, load <filepath>, info <filepath>, export <json filepath>, import <json filepath>, cache, clear, gen <number> [estimator], estimator [oracle|mds|vivaldi], print, seed <u64>, threads <n|0=all>, latency <model>, import-latency <filepath>, impair [node|link] <loss> <dup> <reorder>, bandwidth [node] <up> <down> <buffer>, nat [clear | node <a> <type>|none | <fraction> <type>] [timeout], congestion <subcommand>, access <subcommand>"),
 			}
 		}
 		["graph"] => {
@@ -160,12 +692,13 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 				["sessions"] => internet.nodes.iter().for_each(|(addr,node)| println!("{}: {:?}", addr, node.sessions)),
 				["routes"] => internet.nodes.iter().for_each(|(addr,node)| println!("{}: {:?}", addr, node.route_coord)),
 				["router"] => internet.router.node_map.iter().for_each(|(net_addr,lc)| println!("{}: {:?}", net_addr, lc)),
+				["stats"] => println!("{:?}\n{:?}", internet.router.stats, internet.dht_stats),
 				["node", addr] => {
 					let net_addr = addr.parse::<NetAddr>().context("Need NetAddr")?;
 					println!("{}", internet.node(net_addr)?);
 				}
 				["all"] => internet.nodes.iter().for_each(|(addr,node)|println!("{}:	{:?}", addr, node)),
-				_ => { println!("list: unknown subcommand. valid: directs, peers, sessions, routes, router, node, all") }
+				_ => { println!("list: unknown subcommand. valid: directs, peers, sessions, routes, router, stats, node, all") }
 			}
 		}
 		//["list"] => bail!("list: must have secondary command. allowed: directs, peers, sessions, routes, router, node, all"),
@@ -184,7 +717,7 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 					let remote_node_id = id.parse::<NodeID>().context("node: connect: must pass valid NodeID")?;
 					let remote_net_addr = addr.parse::<NetAddr>().context("node: connect: must pass valid NetAddr")?;
 					println!("Connecting NodeID({:?}) to NodeID({:?}), NetAddr({:?}))", node.node_id, remote_node_id, remote_net_addr);
-					node.action(NodeAction::Connect(remote_node_id, SessionType::direct(remote_net_addr), vec![]));
+					node.action(NodeAction::Connect(remote_node_id, Box::new(SessionType::direct(remote_net_addr)), vec![]));
 				}
 				["connect" | "conn"] => bail!("node: connect: <NodeID> <NetAddr>"), */
 				["bootstrap" | "boot", id, addr] => {
@@ -195,6 +728,8 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 				}
 				["boostrap" | "boot"] => bail!("node: bootstrap: <NodeID> <NetAddr>"),
 				["print"] => println!("Node: {:#?}", node),
+				["recalc"] => node.action(NodeAction::CalcRouteCoord),
+				["estimator", estimator] => node.estimator = estimator.parse::<Estimator>().map_err(|err| anyhow!("node: estimator: {}", err))?,
 				["notify", id, data] => {
 					let remote_node_id = id.parse::<NodeID>().context("node: notify: requires remote NodeID")?;
 					let data = data.parse::<u64>().context("node: notify: data must be u64")?;
@@ -204,14 +739,24 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 					let remote_node_id = id.parse::<NodeID>().context("node: traverse: must pass valid NodeID")?;
 					node.action(NodeAction::ConnectTraversed(remote_node_id, vec![]));
 				}
-				["route", id] => {
+				["route", id, args @ ..] => {
 					let remote_node_id = id.parse::<NodeID>().context("node: route: must pass valid NodeID")?;
-					node.action(NodeAction::ConnectRouted(remote_node_id, 3));
+					let (hops, offset) = match args {
+						[] => (3, 0.),
+						[hops] => (hops.parse::<usize>().context("node: route: hops must be usize")?, 0.),
+						[hops, offset] => (hops.parse::<usize>().context("node: route: hops must be usize")?, offset.parse::<f64>().context("node: route: offset must be f64")?),
+						_ => bail!("node: route: <NodeID> [hops] [offset]"),
+					};
+					node.action(NodeAction::ConnectRouted(remote_node_id, hops, offset));
 				}
 				["send", id, string] => {
 					let remote_node_id = id.parse::<NodeID>().context("node: send: must pass valid NodeID")?;
 					node.action(NodeAction::SendData(remote_node_id, string.as_bytes().to_owned()))
 				}
+				["disconnect", id] => {
+					let remote_node_id = id.parse::<NodeID>().context("node: disconnect: must pass valid NodeID")?;
+					node.action(NodeAction::Disconnect(remote_node_id));
+				}
 				_ => bail!("node: unknown subcommand"),
 			}
 		}
@@ -220,9 +765,11 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 			match subcommand {
 				["sample-artificial", amount] => {
 					let num_samples = amount.parse::<usize>().context("test: sample: requires number of samples")?;
-					use permutation_iterator::{RandomPairPermutor, Permutor};
-					let nlen = internet.nodes.len() as u32;
-					let permutor = RandomPairPermutor::new(nlen, nlen).map(|(i,j)|(i as NetAddr, j as NetAddr));
+					use permutation_iterator::Permutor;
+					// Addresses have gaps once nodes are removed, so sample indices into the list of live addresses
+					let net_addrs: Vec<NetAddr> = internet.nodes.keys().cloned().collect();
+					let nlen = net_addrs.len() as u64;
+					let permutor = Permutor::new_with_u64_key(nlen * nlen, rng.gen()).map(|v|(net_addrs[(v / nlen) as usize], net_addrs[(v % nlen) as usize]));
 
 					let nodes = permutor.take(num_samples).map(|(i,j)|((i, internet.nodes.get(&i).unwrap()), (j, internet.nodes.get(&j).unwrap())));
 					let nodes = nodes.collect::<Vec<((NetAddr, &Node), (NetAddr, &Node))>>();
@@ -272,7 +819,7 @@ fn parse_command(internet: &mut NetSim<Node>, input: &[&str], rng: &mut impl ran
 						// Calculate random times
 						let mut random_times = Vec::with_capacity(3);
 						// Get some nodes
-						let random_itermediate_nodes = Permutor::new(internet.nodes.len() as u64).take(3).map(|i|&internet.nodes[&(i as u128)]);
+						let random_itermediate_nodes = Permutor::new_with_u64_key(nlen, rng.gen()).take(3).map(|i|&internet.nodes[&net_addrs[i as usize]]);
 						let mut current_node = start;
 						for node in random_itermediate_nodes {
 							let dist = node::types::route_dist(&current_node.route_coord.unwrap(), &node.route_coord.unwrap());
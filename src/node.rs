@@ -4,34 +4,54 @@ const TARGET_PEER_COUNT: usize = 10;
 // Amount of time to wait to connect to a peer who wants to ping
 // const WANT_PING_CONN_TIMEOUT: usize = 300;
 const MAX_REQUEST_PINGS: usize = 10;
+// Recalculate the route coordinate for every new anchor until there are this many, after that one more barely changes the estimate
+const ESTIMATE_ANCHORS: usize = 8;
+// Ticks between re-estimating the route coordinate
+const REFINE_INTERVAL: usize = 2000;
+// Refinement that leaves the route coordinate where it was doubles the time until the next one, up to this many times
+const MAX_REFINE_BACKOFF: u8 = 8;
+// Ticks between pings over each direct session
+const KEEPALIVE_INTERVAL: usize = 2000;
+// A remote answering at a steady distance doubles the time until the next keepalive ping, up to this many times.
+// Kept low since a remote that crashed is only noticed once pings to it go unanswered.
+const MAX_KEEPALIVE_BACKOFF: u8 = 4;
+// A session is torn down once this many keepalive pings in a row went unanswered
+const MISSED_PINGS: usize = 3;
+// A refined estimate is only adopted if it is further than this from the current route coordinate, so jitter doesn't move the node
+const COORD_HYSTERESIS: f64 = 5.;
+// The DHT record is only rewritten once the route coordinate drifted further than this from it
+const DHT_REPUBLISH_DRIFT: f64 = 20.;
 
-use std::collections::BTreeMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::any::Any;
 
 pub mod types;
+pub mod estimator;
 mod session;
 mod packet;
 mod remote;
 
-pub use types::{NodeID, SessionID, RouteCoord, RouteScalar};
+pub use types::{NodeID, SessionID, RouteCoord, RouteScalar, StableBiHashMap};
 use session::{SessionError, RemoteSession, SessionType};
 use remote::{RemoteNode, RemoteNodeError};
 pub use packet::{NodePacket, TraversedPacket, NodeEncryption};
+use estimator::{Anchor, CoordinateEstimator, Estimator, Observations};
 
 use crate::internet::{CustomNode, NetAddr, NetSimPacket, NetSimPacketVec, NetSimRequest};
 use crate::plot::GraphPlottable;
+use crate::rng::SimRng;
 
-use petgraph::{graphmap::DiGraphMap, graph::Graph};
-use bimap::BiHashMap;
-use smallvec::SmallVec;
+use petgraph::graph::Graph;
+use rand::{Rng, SeedableRng};
 use slotmap::SlotMap;
-use nalgebra::Point2;
+use nalgebra::{Point2, Vector2};
 
 type InternetPacket = NetSimPacket<Node>;
 type PacketVec = NetSimPacketVec<Node>;
 type InternetRequest = NetSimRequest<Node>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 /// A condition that should be satisfied before an action is executed
 pub enum NodeActionCondition {
 	/// Yields if there is a session of any kind with NodeID
@@ -43,7 +63,7 @@ pub enum NodeActionCondition {
 }
 impl NodeActionCondition {
 	// Returns true if condition is satisfied
-	fn check(&self, node: &mut Node) -> Result<bool, NodeError> {
+	fn check(&self, node: &Node) -> Result<bool, NodeError> {
 		Ok(match self {
 			// Yields None if there is a session active
 			NodeActionCondition::Session(node_id) => node.remote(node.index_by_node_id(node_id)?)?.session_active(),
@@ -65,12 +85,13 @@ impl NodeActionCondition {
 		})
 	}
 }
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NodeAction {
 	/// Bootstrap this node onto a specific other network node, starts the self-organization process
 	Bootstrap(NodeID, NetAddr),
-	/// Initiate Handshake with remote NodeID, NetAddr and initial packets
-	Connect(NodeID, SessionType, Vec<NodePacket>),
+	/// Initiate Handshake with remote NodeID, NetAddr and initial packets.
+	/// The session type is boxed, it would make up most of the size of every action otherwise and nodes keep many actions scheduled.
+	Connect(NodeID, Box<SessionType>, Vec<NodePacket>),
 	/* /// Ping a node
 	Ping(NodeID, usize), // Ping node X number of times */
 	/// Run various functions pertaining to receiving specific information
@@ -81,6 +102,10 @@ pub enum NodeAction {
 	RequestPeers(NodeID, usize),
 	/// Try and calculate route coordinate using Principle Coordinate Analysis of closest nodes (MDS)
 	CalcRouteCoord,
+	/// Re-estimate route coordinate from the latest pings and schedule the next round
+	RefineRouteCoord,
+	/// Ping the remote of a direct session, or tear the session down if the remote went quiet, reschedules itself while the session lasts
+	Keepalive(SessionID),
 	/// Exchange Info with another node
 	ExchangeInformation(NodeID),
 	/// Organize and set/unset known nodes as peers for Routing
@@ -93,12 +118,20 @@ pub enum NodeAction {
 	/// Looks up remote node's RouteCoord on DHT and enables Traversed Session
 	ConnectTraversed(NodeID, Vec<NodePacket>),
 	/// Establishes Routed session with remote NodeID
-	/// Looks up remote node's RouteCoord on DHT and runs CalculateRoute after RouteCoord is received
+	/// Looks up remote node's RouteCoord on DHT and picks intermediate nodes once RouteCoord is received
 	/// * `usize`: Number of intermediate nodes to route through
 	/// * `f64`: Random intermediate offset (high offset is more anonymous but less efficient, very high offset is random routing strategy)
-	ConnectRouted(NodeID, usize),
+	ConnectRouted(NodeID, usize, f64),
+	/// Open a session with each intermediate node in turn, routed through the ones before it, then the Routed session with remote NodeID.
+	/// Sessions this node already has with an intermediate node are used as they are.
+	/// * `Vec<NodeID>`: Intermediate nodes in route order
+	ExtendRoute(NodeID, Vec<NodeID>),
 	/// Send specific packet to node
 	SendData(NodeID, Vec<u8>),
+	/// Close the session with a remote, telling it to close its end too
+	Disconnect(NodeID),
+	/// Leave the network: tell every remote with a session and withdraw this node's route coordinate from the DHT
+	Shutdown,
 	/// Establish a dynamic routed connection
 	// Route(NodeID, RouteCoord),
 	/// Condition for a condition to be fulfilled before running imbedded Action
@@ -109,7 +142,8 @@ impl NodeAction {
 		NodeAction::Condition(condition, Box::new(self))
 	}
 }
-type ActionVec = SmallVec<[NodeAction; 8]>;
+/// Nodes keep dozens of scheduled actions (a keepalive per session), an inline buffer would only ever be spilled
+type ActionVec = Vec<NodeAction>;
 new_key_type! { pub struct NodeIdx; }
 
 #[derive(Error, Debug)]
@@ -162,24 +196,34 @@ pub struct Node {
 	pub net_addr: NetAddr,
 
 	pub route_coord: Option<RouteCoord>, // This node's route coordinate (None if not yet calculated)
+	/// Calculates route_coord from what this node measured
+	pub estimator: Estimator,
 	#[derivative(Debug="ignore")]
-	deus_ex_data: Option<RouteCoord>,
+	deus_ex_data: Option<RouteCoord>, // True position of the node, only the Oracle estimator may use it
 	pub is_public: bool, // Does this node publish it's RouteCoord to the DHT?
 	#[derivative(Debug="ignore")]
 	public_route: Option<RouteCoord>,
 	pub ticks: usize, // Amount of time passed since startup of this node
+	/// Number of refinements in a row that left the route coordinate where it was, each one doubles the time until the next refinement
+	pub refine_backoff: u8,
+	#[derivative(Debug="ignore")]
+	rng: SimRng, // Source of SessionIDs and PingIDs, seeded by the simulation
 
 	pub remotes: SlotMap<NodeIdx, RemoteNode>, // ECS-type data structure that stores all nodes
-	pub ids: BiHashMap<NodeID, NodeIdx>,
+	#[serde(with = "types::pairs")]
+	pub ids: BTreeMap<NodeID, NodeIdx>, // The reverse is the remote's node_id
 
-	pub sessions: BiHashMap<SessionID, NodeIdx>, // Each SessionID links to a unique RemoteNode
-	pub direct_sorted: BTreeMap<u64, NodeIdx>, // All nodes that have been tested, sorted by lowest value
+	#[serde(with = "types::pairs")]
+	pub sessions: BTreeMap<SessionID, NodeIdx>, // Each SessionID links to a unique RemoteNode, the reverse is the remote's session
+	pub direct_sorted: BTreeSet<(u64, NodeIdx)>, // All nodes that have been tested, sorted by lowest value (distance ties broken by NodeIdx)
 
-	pub peer_list: BiHashMap<NodeIdx, RouteCoord>, // Used for routing and peer management, peer count should be no more than TARGET_PEER_COUNT
+	#[serde(with = "types::stable_bimap")]
+	pub peer_list: StableBiHashMap<NodeIdx, RouteCoord>, // Used for routing and peer management, peer count should be no more than TARGET_PEER_COUNT
+	/// Sessions this node is a proxy for, mapped to the remote that routed them through it, see `NodePacket::Proxy`
+	pub relays: BTreeMap<SessionID, NodeIdx>,
 	#[derivative(Debug="ignore")]
-	#[serde(skip)]
-	pub route_map: DiGraphMap<NodeID, u64>, // Bi-directional graph of all locally known nodes and the estimated distances between them 
-	#[serde(skip)]
+	#[serde(with = "types::pairs")]
+	pub route_map: BTreeMap<(NodeID, NodeID), u64>, // Estimated distances between locally known nodes, as directed edges (from, to)
 	pub action_list: ActionVec, // Actions will wait here until NodeID session is established
 }
 impl CustomNode for Node {
@@ -211,6 +255,8 @@ impl CustomNode for Node {
 		let aq = std::mem::replace(&mut self.action_list, Default::default()); // Move actions out of action_list
 		// Execute and collect actions back into action_list
 		self.action_list = aq.into_iter().filter_map(|action|{
+			// Nodes with many sessions mostly wait on keepalive deadlines, keep those that haven't passed without cloning them
+			if matches!(action, NodeAction::Condition(NodeActionCondition::RunAt(time), _) if time > self.ticks) { return Some(action) }
 			let action_clone = action.clone();
 			self.parse_action(action, &mut outgoing, &mut new_actions).unwrap_or_else(|err|{
 				log::error!("NodeID({}), Action {:?} errored: {:?}", self.node_id, action_clone, err); None
@@ -221,9 +267,23 @@ impl CustomNode for Node {
 		self.ticks += 1;
 		outgoing
 	}
+	fn skip_ticks(&mut self, ticks: usize) { self.ticks += ticks; }
+	fn next_wakeup(&self) -> Option<usize> {
+		// Conditions are checked on every tick, so a node only has to wake up by itself for deadlines and for actions that are ready to run.
+		// Sessions and remote route coordinates only change when a packet arrives, which wakes the node anyway.
+		self.action_list.iter().filter_map(|action| match action {
+			NodeAction::Condition(NodeActionCondition::RunAt(time), _) => Some((time + 1).saturating_sub(self.ticks).max(1)),
+			// A condition that fails to check is dropped on the next tick
+			NodeAction::Condition(condition, _) => condition.check(self).unwrap_or(true).then_some(1),
+			_ => Some(1),
+		}).min()
+	}
 	fn action(&mut self, action: NodeAction) { self.action_list.push(action); }
 	fn as_any(&self) -> &dyn Any { self }
+	fn seed_rng(&mut self, seed: u64) { self.rng = SimRng::seed_from_u64(seed); }
 	fn set_deus_ex_data(&mut self, data: Option<RouteCoord>) { self.deus_ex_data = data; }
+	fn packet_variants(data: &[u8]) -> Vec<&'static str> { bincode::deserialize::<NodeEncryption>(data).map_or_else(|_| Vec::new(), |encryption| encryption.variants()) }
+	fn route_coord(&self) -> Option<RouteCoord> { self.route_coord }
 }
 
 impl Node {
@@ -236,9 +296,10 @@ impl Node {
 		}
 	}
 	pub fn with_action(mut self, action: NodeAction) -> Self { self.action_list.push(action); self }
+	pub fn with_estimator(mut self, estimator: Estimator) -> Self { self.estimator = estimator; self }
 	
 	pub fn add_remote(&mut self, node_id: NodeID) -> Result<(NodeIdx, &mut RemoteNode), NodeError> {
-		let node_idx = if let Some(node_idx) = self.ids.get_by_left(&node_id) {
+		let node_idx = if let Some(node_idx) = self.ids.get(&node_id) {
 			*node_idx
 		} else {
 			let index = self.remotes.insert(RemoteNode::new(node_id));
@@ -248,14 +309,14 @@ impl Node {
 	}
 	pub fn remote(&self, node_idx: NodeIdx) -> Result<&RemoteNode, NodeError> { self.remotes.get(node_idx).ok_or(NodeError::InvalidNodeIndex { node_idx } ) }
 	pub fn remote_mut(&mut self, node_idx: NodeIdx) -> Result<&mut RemoteNode, NodeError> { self.remotes.get_mut(node_idx).ok_or(NodeError::InvalidNodeIndex { node_idx } ) }
-	pub fn index_by_node_id(&self, node_id: &NodeID) -> Result<NodeIdx, NodeError> { self.ids.get_by_left(node_id).cloned().ok_or(NodeError::InvalidNodeID { node_id: node_id.clone() }) }
-	pub fn index_by_session_id(&self, session_id: &SessionID) -> Result<NodeIdx, NodeError> { self.sessions.get_by_left(session_id).cloned().ok_or(NodeError::InvalidSessionID { session_id: session_id.clone() }) }
+	pub fn index_by_node_id(&self, node_id: &NodeID) -> Result<NodeIdx, NodeError> { self.ids.get(node_id).cloned().ok_or(NodeError::InvalidNodeID { node_id: node_id.clone() }) }
+	pub fn index_by_session_id(&self, session_id: &SessionID) -> Result<NodeIdx, NodeError> { self.sessions.get(session_id).cloned().ok_or(NodeError::InvalidSessionID { session_id: session_id.clone() }) }
 
 	pub fn find_closest_peer(&self, remote_route_coord: &RouteCoord) -> Result<NodeIdx, NodeError> {
 		let min_peer = self.peer_list.iter()
-			.min_by_key(|(_,&p)|{
+			.min_by_key(|(&node_idx,&p)|{
 				let diff = p - *remote_route_coord;
-				diff.dot(&diff)
+				(diff.dot(&diff), node_idx) // Break ties by NodeIdx so the result doesn't depend on iteration order
 				//println!("Dist from {:?}: {}: {}", self.node_id, self.remote(**id).unwrap().node_id, d_sq);
 				//d_sq
 			});
@@ -270,10 +331,10 @@ impl Node {
 				self.connect(remote_node_id, SessionType::direct(net_addr), vec![NodePacket::ExchangeInfo(self.route_coord, 0, 0)], outgoing)?;
 			}
 			NodeAction::Connect(remote_node_id, session_type, ref packets) => {
-				self.connect(remote_node_id, session_type, packets.clone(), outgoing)?;
+				self.connect(remote_node_id, *session_type, packets.clone(), outgoing)?;
 			}
 			NodeAction::UpdateRemote(remote_node_id, remote_route_coord, remote_direct_count, remote_ping) => {
-				self.route_map.add_edge(remote_node_id, self.node_id, remote_ping);
+				self.route_map.insert((remote_node_id, self.node_id), remote_ping);
 
 				let self_route_coord = self.route_coord;
 				
@@ -281,15 +342,23 @@ impl Node {
 				let node_idx = self.index_by_node_id(&remote_node_id)?;
 				let remote = self.remote_mut(node_idx)?;
 				let mut did_route_change = remote.route_coord != remote_route_coord;
+				let new_anchor = remote.route_coord.is_none() && remote_route_coord.is_some();
 				remote.route_coord = remote_route_coord;
 
-				// If this node has coord,
+				// If this node has no coord, estimate one once there is a remote to estimate it from
 				if let None = self.route_coord {
-					out_actions.push(NodeAction::CalcRouteCoord);
+					if remote_route_coord.is_some() || matches!(self.estimator, Estimator::Oracle) {
+						out_actions.push(NodeAction::CalcRouteCoord);
+					}
 					did_route_change = false;
 				}
 				if did_route_change {
-					out_actions.push(NodeAction::CalculatePeers);
+					// The first few anchors each improve the estimate a lot, the oracle doesn't need them
+					if new_anchor && !matches!(self.estimator, Estimator::Oracle) && self.observations().anchors.len() <= ESTIMATE_ANCHORS {
+						out_actions.push(NodeAction::CalcRouteCoord);
+					} else {
+						out_actions.push(NodeAction::CalculatePeers);
+					}
 				}
 				// If need more peers & remote has a peer, request pings
 				if self.direct_sorted.len() < TARGET_PEER_COUNT && remote_direct_count >= 2 {
@@ -297,9 +366,35 @@ impl Node {
 				}
 			}
 			NodeAction::CalcRouteCoord => {
+				if self.route_coord.is_none() { out_actions.push(self.next_refinement()); }
 				self.route_coord = Some(self.calculate_route_coord()?);
 				out_actions.push(NodeAction::CalculatePeers);
 			}
+			NodeAction::RefineRouteCoord => {
+				let moved = self.refine_route_coord(outgoing, out_actions);
+				// Refine less and less often while the route coordinate stays put, and at the base rate again once it moves
+				self.refine_backoff = if let Ok(true) = moved { 0 } else { (self.refine_backoff + 1).min(MAX_REFINE_BACKOFF) };
+				out_actions.push(self.next_refinement());
+				moved?;
+			}
+			NodeAction::Keepalive(session_id) => {
+				// Session was replaced or torn down in the meantime
+				let node_idx = match self.sessions.get(&session_id) { Some(&node_idx) => node_idx, None => return Ok(None) };
+				let remote = self.remotes.get_mut(node_idx).ok_or(NodeError::InvalidNodeIndex { node_idx })?;
+				let tracker = &mut remote.session_mut()?.tracker;
+				if tracker.unanswered_pings() >= MISSED_PINGS {
+					log::debug!("[{: >6}] NodeID({}) Session with NodeID({}) timed out", self.ticks, self.node_id, remote.node_id);
+					self.remove_session(node_idx)?;
+					if self.route_coord.is_some() { out_actions.push(NodeAction::CalculatePeers); }
+					return Ok(None);
+				}
+				// Ping less and less often while the remote answers at a steady distance, and at the base rate again as soon as it doesn't
+				tracker.keepalive_backoff = if tracker.unanswered_pings() == 0 && tracker.is_steady() { (tracker.keepalive_backoff + 1).min(MAX_KEEPALIVE_BACKOFF) } else { 0 };
+				let backoff = tracker.keepalive_backoff;
+				let ping_id = tracker.gen_ping(self.ticks, &mut self.rng);
+				self.send_packet(node_idx, NodePacket::Ping(ping_id), outgoing)?;
+				out_actions.push(self.next_keepalive(session_id, backoff));
+			}
 			NodeAction::ExchangeInformation(remote_node_id) => {
 				let node_idx = self.index_by_node_id(&remote_node_id)?;
 				let avg_dist = self.remote(node_idx)?.session()?.tracker.dist_avg;
@@ -308,7 +403,7 @@ impl Node {
 			NodeAction::CalculatePeers => {
 				// Collect the viable peers
 				let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
-				let direct_nodes = self.direct_sorted.iter().map(|s|s.1.clone()).collect::<Vec<NodeIdx>>();
+				let direct_nodes = self.direct_sorted.iter().map(|&(_, node_idx)| node_idx).collect::<Vec<NodeIdx>>();
 				self.peer_list = direct_nodes.iter().filter_map(|&node_idx| {
 					// Decides whether remote should be added to peer list
 					self.remote(node_idx).ok().map(|remote|{
@@ -316,13 +411,14 @@ impl Node {
 					}).flatten()
 				}).take(TARGET_PEER_COUNT).collect();
 				
-				// Notify Peers if just became peer
+				// Notify remotes whose peer status changed, and all of them once this node's route coordinate moved since they were last told
 				let num_peers = self.peer_list.len();
 				for node_idx in direct_nodes {
 					let toggle = self.peer_list.contains_left(&node_idx);
-					let remote = self.remote(node_idx)?;
-					let dist = remote.session()?.tracker.dist_avg;
-					match (!remote.session()?.is_peer(), toggle) {
+					let session = self.remote(node_idx)?.session()?;
+					let dist = session.tracker.dist_avg;
+					let direct = session.direct()?;
+					match (session.is_peer(), toggle) {
 						(false, true) => {
 							// Notify that this node thinks of other node as a direct peer
 							self.send_packet(node_idx, NodePacket::PeerNotify(0, self_route_coord, num_peers, dist), outgoing)?;
@@ -331,13 +427,20 @@ impl Node {
 							// Notify that this node no longer things of other node as a direct peer, so perhaps other node should drop connection
 							self.send_packet(node_idx, NodePacket::PeerNotify(usize::MAX, self_route_coord, num_peers, dist), outgoing)?;
 						},
+						(_, toggle) if direct.notified_route != Some(self_route_coord) => {
+							let rank = if toggle { 0 } else { usize::MAX };
+							self.send_packet(node_idx, NodePacket::PeerNotify(rank, self_route_coord, num_peers, dist), outgoing)?;
+						},
 						_ => {},
 					}
-					self.remote_mut(node_idx)?.session_mut()?.direct_mut()?.set_peer(toggle);
+					let direct = self.remote_mut(node_idx)?.session_mut()?.direct_mut()?;
+					direct.set_peer(toggle);
+					direct.notified_route = Some(self_route_coord);
 				}
 				
 				// If have enough peers & want to host node as public, write RouteCoord to DHT
-				if self.peer_list.len() >= TARGET_PEER_COUNT && self.is_public && self.public_route != self.route_coord {
+				let drifted = self.public_route.map_or(true, |public_route| types::route_dist(&public_route, &self_route_coord) > DHT_REPUBLISH_DRIFT);
+				if self.peer_list.len() >= TARGET_PEER_COUNT && self.is_public && drifted {
 					self.public_route = self.route_coord;
 					outgoing.push( InternetPacket::gen_request(self.net_addr, InternetRequest::RouteCoordDHTWrite(self.node_id, self_route_coord)) );
 				}
@@ -365,33 +468,63 @@ impl Node {
 					out_actions.push(NodeAction::ConnectTraversed(remote_node_id, packets).gen_condition(NodeActionCondition::RemoteRouteCoord(remote_node_id)));
 				}
 			}
-			NodeAction::ConnectRouted(remote_node_id, hops) => {
-				let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+			NodeAction::ConnectRouted(remote_node_id, hops, offset) => {
+				if self.route_coord.is_none() { return Err(NodeError::NoCalculatedRouteCoord) }
 				// Check if Remote Route Coord was allready requested
 				let (_, remote) = self.add_remote(remote_node_id.clone())?;
 				if let Some(remote_route_coord) = remote.route_coord {
-					let self_route_coord = self_route_coord.map(|s|s as f64);
-					let remote_route_coord = remote_route_coord.map(|s|s as f64);
-					let diff = (remote_route_coord - self_route_coord) / hops as f64;
-					let mut routes = Vec::with_capacity(hops);
-					for i in 1..hops {
-						routes.push(self_route_coord + diff * i as f64);
-					}
-					println!("Routes: {:?}", routes);
-					//use nalgebra::distance_squared;
-					// Find nearest node
-					//let nearest_peer = self.peer_list.iter().min_by_key(|(id,&r)|distance_squared(&routes[0], &r.map(|s|s as f64)) as i64);
-
-					//self.routed_connect(remote_node_id, outgoing);
-					//self.remote_mut(self.index_by_node_id(&remote_node_id)?)?.connect_routed(routes);
+					let proxies = self.choose_proxies(remote_node_id, remote_route_coord, hops, offset)?;
+					log::debug!("[{: >6}] NodeID({}) Routing session with NodeID({}) through {:?}", self.ticks, self.node_id, remote_node_id, proxies);
+					out_actions.push(NodeAction::ExtendRoute(remote_node_id, proxies));
 				} else { // Otherwise, Request it and await Condition for next ConnectRouted
 					out_actions.push(NodeAction::RequestRouteCoord(remote_node_id));
-					out_actions.push(NodeAction::ConnectRouted(remote_node_id, hops).gen_condition(NodeActionCondition::RemoteRouteCoord(remote_node_id)));
+					out_actions.push(NodeAction::ConnectRouted(remote_node_id, hops, offset).gen_condition(NodeActionCondition::RemoteRouteCoord(remote_node_id)));
+				}
+			}
+			NodeAction::ExtendRoute(remote_node_id, proxies) => {
+				// Sessions with the intermediate nodes so far, the next session is routed through them
+				let mut proxy_sessions = Vec::with_capacity(proxies.len());
+				for &proxy_id in &proxies {
+					let proxy = self.remote(self.index_by_node_id(&proxy_id)?)?;
+					if let Ok(session) = proxy.session() { proxy_sessions.push(session.session_id); continue }
+					if proxy.pending_session.is_none() {
+						let proxy_route_coord = proxy.route_coord.ok_or(NodeError::NoRemoteRouteCoord { remote: proxy_id })?;
+						let session_type = if proxy_sessions.is_empty() { SessionType::traversed(proxy_route_coord) } else { SessionType::routed(proxy_route_coord, proxy_sessions) };
+						self.connect(proxy_id, session_type, vec![], outgoing)?;
+					}
+					return Ok(Some(NodeAction::ExtendRoute(remote_node_id, proxies).gen_condition(NodeActionCondition::Session(proxy_id))));
+				}
+				let remote_idx = self.index_by_node_id(&remote_node_id)?;
+				let remote_route_coord = self.remote(remote_idx)?.route_coord.ok_or(NodeError::NoRemoteRouteCoord { remote: remote_node_id })?;
+				// The routed session takes the place of whatever session there was with the remote
+				if self.remote(remote_idx)?.session.is_some() {
+					self.send_packet(remote_idx, NodePacket::Close, outgoing)?;
+					self.remove_session(remote_idx)?;
+					out_actions.push(NodeAction::CalculatePeers);
 				}
+				self.connect(remote_node_id, SessionType::routed(remote_route_coord, proxy_sessions), vec![], outgoing)?;
 			}
 			NodeAction::SendData(remote_node_id, data) => {
 				self.send_packet(self.index_by_node_id(&remote_node_id)?, NodePacket::Data(data), outgoing)?;
 			}
+			NodeAction::Disconnect(remote_node_id) => {
+				let node_idx = self.index_by_node_id(&remote_node_id)?;
+				self.send_packet(node_idx, NodePacket::Close, outgoing)?;
+				self.remove_session(node_idx)?;
+				if self.route_coord.is_some() { out_actions.push(NodeAction::CalculatePeers); }
+			}
+			NodeAction::Shutdown => {
+				let session_remotes: Vec<NodeIdx> = self.remotes.iter().filter(|(_, remote)| remote.session.is_some()).map(|(node_idx, _)| node_idx).collect();
+				for node_idx in session_remotes {
+					self.send_packet(node_idx, NodePacket::Leave, outgoing)?;
+					self.remove_session(node_idx)?;
+				}
+				// Only a coordinate this node published needs withdrawing
+				if self.public_route.take().is_some() {
+					outgoing.push(InternetPacket::gen_request(self.net_addr, InternetRequest::RouteCoordDHTRemove(self.node_id)));
+				}
+				log::debug!("[{: >6}] NodeID({}) Shut down", self.ticks, self.node_id);
+			}
 			NodeAction::Condition(condition, embedded_action) => {
 				// Returns embedded action if condition is satisfied (e.g. check() returns true), else returns false to prevent action from being deleted
 				if condition.check(self)? { return Ok(Some(*embedded_action)); } else { return Ok(Some(NodeAction::Condition(condition, embedded_action))); }
@@ -405,23 +538,37 @@ impl Node {
 		let self_ticks = self.ticks;
 		let return_remote = self.remote_mut(return_node_idx)?;
 		let return_node_id = return_remote.node_id;
-		let packet_last_received = return_remote.session_mut()?.check_packet_time(&received_packet, return_node_id, self_ticks);
+		let return_session = return_remote.session_mut()?;
+		return_session.tracker.heard(self_ticks);
+		// Only the packets rate limited below need to know when they were last received, there's no point recording the rest
+		let packet_last_received = match received_packet {
+			NodePacket::RequestPings(..) | NodePacket::AcceptWantPing(..) => return_session.check_packet_time(&received_packet, self_ticks),
+			_ => None,
+		};
 
 		log::debug!("[{: >6}] Node({}) received NodePacket::{:?} from NodeID({})", self.ticks, self.node_id, received_packet, return_node_id);
 
 		match received_packet {
 			NodePacket::ConnectionInit(ping_id, packets) => {
 				// Acknowledge ping
-				let distance = self.remote_mut(return_node_idx)?.session_mut()?.tracker.acknowledge_ping(ping_id, self_ticks)?;
-				self.route_map.add_edge(self.node_id, return_node_id, distance);
-				self.direct_sorted.insert(distance, return_node_idx);
+				let session = self.remote_mut(return_node_idx)?.session_mut()?;
+				let distance = session.tracker.acknowledge_ping(ping_id, self_ticks)?;
+				// Pings over other sessions take a detour, they don't measure the distance to the remote
+				if session.direct().is_ok() {
+					self.route_map.insert((self.node_id, return_node_id), distance);
+					self.sort_direct(return_node_idx, distance);
+				}
 				// Recursively parse packets
 				for packet in packets {
 					self.parse_node_packet(return_node_idx, packet, outgoing)?;
 				}
 			}
 			NodePacket::ExchangeInfo(remote_route_coord, _remote_direct_count, remote_ping) => {
-				if self.node_id == 0 && self.direct_sorted.len() == 1 && self.route_coord.is_none() { self.route_coord = Some(self.calculate_route_coord()?); }
+				// The first node has nobody to measure itself against and starts the coordinate space
+				if self.node_id == 0 && self.direct_sorted.len() == 1 && self.route_coord.is_none() {
+					self.route_coord = Some(match self.estimator { Estimator::Oracle => self.calculate_route_coord()?, _ => RouteCoord::origin() });
+					self.action(self.next_refinement());
+				}
 
 				// Note Data, Update Remote
 				self.action(NodeAction::UpdateRemote(return_node_id, remote_route_coord, _remote_direct_count, remote_ping));
@@ -460,7 +607,7 @@ impl Node {
 				self.remote_mut(return_node_idx)?.route_coord = requester_route_coord;
 				let closest_nodes = if let Some(route_coord) = requester_route_coord {
 					let point_target = route_coord.map(|s|s as f64);
-					let mut sorted = self.direct_sorted.iter().filter_map(|(&_,&node_idx)|{
+					let mut sorted = self.direct_sorted.iter().filter_map(|&(_, node_idx)|{
 						self.remote(node_idx).ok().map(|remote|{
 							if let Some(p) = remote.route_coord {
 								Some((node_idx, nalgebra::distance_squared(&p.map(|s|s as f64), &point_target) as u64))
@@ -470,7 +617,7 @@ impl Node {
 					sorted.sort_unstable_by_key(|k|k.1);
 					sorted.iter().map(|(node,_)|node.clone()).take(num_requests).collect()
 				} else {
-					self.direct_sorted.iter().map(|(_,node)|node.clone()).take(num_requests).collect::<Vec<NodeIdx>>()
+					self.direct_sorted.iter().map(|&(_, node_idx)| node_idx).take(num_requests).collect::<Vec<NodeIdx>>()
 				};
 
 				// Send WantPing packet to first num_requests of those peers
@@ -492,13 +639,13 @@ impl Node {
 					return Ok(())
 				} else { // If no session, send request
 					if request_remote.pending_session.is_none() {
-						self.action(NodeAction::Connect(requesting_node_id, SessionType::direct(requesting_net_addr), vec![NodePacket::AcceptWantPing(return_node_id, distance_self_to_return)]));
+						self.action(NodeAction::Connect(requesting_node_id, Box::new(SessionType::direct(requesting_net_addr)), vec![NodePacket::AcceptWantPing(return_node_id, distance_self_to_return)]));
 					}
 				}
 			}
 			NodePacket::AcceptWantPing(intermediate_node_id, return_to_intermediate_distance) => {
 				let avg_dist = self.remote(return_node_idx)?.session()?.dist();
-				self.route_map.add_edge(return_node_id, intermediate_node_id, return_to_intermediate_distance);
+				self.route_map.insert((return_node_id, intermediate_node_id), return_to_intermediate_distance);
 				if let Some(time) = packet_last_received { if time < 300 { return Ok(()) } }
 
 				let self_route_coord = self.route_coord;
@@ -527,6 +674,9 @@ impl Node {
 					} else {
 						log::info!("Node({}) send message with no return coordinates: {:?}", return_node_id, traversal_packet.encryption);
 					}
+				} else if let Some(&relay_idx) = traversal_packet.encryption.session_id().and_then(|session_id| self.relays.get(&session_id)) {
+					// Reply to a session this node is a proxy for
+					self.send_packet(relay_idx, NodePacket::Relayed(Box::new(traversal_packet.encryption.clone())), outgoing)?;
 				} else {
 					// Check if next node is not node that I received the packet from
 					if return_node_id != closest_peer.node_id {
@@ -541,6 +691,39 @@ impl Node {
 			NodePacket::Data(data) => {
 				println!("{} -> {}, Data: {}", return_node_id, self.node_id, String::from_utf8_lossy(&data));
 			}
+			NodePacket::Ping(ping_id) => {
+				self.send_packet(return_node_idx, NodePacket::Pong(ping_id), outgoing)?;
+			}
+			NodePacket::Pong(ping_id) => {
+				let distance = self.remote_mut(return_node_idx)?.session_mut()?.tracker.acknowledge_ping(ping_id, self_ticks)?;
+				self.route_map.insert((self.node_id, return_node_id), distance);
+				self.sort_direct(return_node_idx, distance);
+			}
+			NodePacket::Proxy(destination, encryption) => {
+				// Replies are traversed back to this node, remember whom to relay them to
+				if let Some(session_id) = encryption.session_id() { self.relays.insert(session_id, return_node_idx); }
+				let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+				let closest_peer_idx = self.find_closest_peer(&destination)?;
+				self.send_packet(closest_peer_idx, TraversedPacket::new(destination, *encryption, Some(self_route_coord)), outgoing)?;
+			}
+			NodePacket::Relayed(encryption) => {
+				// Peel the proxy's layer off, what is inside may be relayed by another proxy
+				let return_session_type = self.remote(return_node_idx)?.session()?.session_type.clone();
+				if let Some((node_idx, packet)) = self.parse_node_encryption(*encryption, return_session_type, outgoing)? {
+					self.parse_node_packet(node_idx, packet, outgoing)?;
+				}
+			}
+			NodePacket::Close => {
+				self.remove_session(return_node_idx)?;
+				if self.route_coord.is_some() { self.action(NodeAction::CalculatePeers); }
+			}
+			NodePacket::Leave => {
+				self.remove_session(return_node_idx)?;
+				// Distances other remotes reported to the leaving node are of no use anymore, neither is its route coordinate
+				self.route_map.retain(|&(from, to), _| from != return_node_id && to != return_node_id);
+				self.remote_mut(return_node_idx)?.route_coord = None;
+				if self.route_coord.is_some() { self.action(NodeAction::CalculatePeers); }
+			}
 			//_ => { }
 		}
 		Ok(())
@@ -548,7 +731,7 @@ impl Node {
 
 	/// Initiate handshake process and send packets when completed
 	pub fn connect(&mut self, dest_node_id: NodeID, session_type: SessionType, initial_packets: Vec<NodePacket>, outgoing: &mut PacketVec) -> Result<(), NodeError> {
-		let session_id: SessionID = rand::random(); // Create random session ID
+		let session_id: SessionID = self.rng.gen(); // Create random session ID
 		//let self_node_id = self.node_id;
 		let self_ticks = self.ticks;
 		let self_node_id = self.node_id;
@@ -569,7 +752,10 @@ impl Node {
 				let closest_peer = self.find_closest_peer(&traversal.route_coord)?;
 				self.send_packet(closest_peer, TraversedPacket::new(traversal.route_coord, encryption, Some(self_route_coord)), outgoing)?;
 			}
-			_ => unimplemented!(),
+			SessionType::Routed(routed) => {
+				// Send through the proxies, the same way packets of the session will go
+				outgoing.push(routed.gen_packet(encryption, self)?);
+			}
 		}
 		
 		Ok(())
@@ -597,7 +783,7 @@ impl Node {
 						log::warn!("No Route Coordinate found for: {:?}", query_node_id);
 					}
 				},
-				InternetRequest::RouteCoordDHTWriteResponse(_) => {},
+				InternetRequest::RouteCoordDHTWriteResponse(_) | InternetRequest::RouteCoordDHTRemoveResponse(_) => {},
 				_ => { log::warn!("Not a InternetRequest Response variant") }
 			}
 			return Ok(None);
@@ -615,33 +801,47 @@ impl Node {
 			NodeEncryption::Handshake { recipient, session_id, signer } => {
 				if recipient != self.node_id { Err(RemoteNodeError::UnknownAckRecipient { recipient })?; }
 				let (remote_idx, remote) = self.add_remote(signer)?;
+				// Duplicated handshake of the session already established, it was acknowledged the first time
+				if remote.session.as_ref().map_or(false, |session| session.session_id == session_id) {
+					log::debug!("[{: >6}] Node({:?}) Ignoring duplicate Handshake: {:?}", self_ticks, self_node_id, encryption);
+					return Ok(None);
+				}
 				// Check if there is not already a pending session
 				if remote.pending_session.is_some() {
 					if self_node_id < remote.node_id { remote.pending_session = None }
 				}
 
+				// A new session takes the place of whatever session there was with the remote, its Keepalive won't find it anymore
+				if remote.session.is_some() { self.remove_session(remote_idx)?; }
+
+				let direct = matches!(return_session_type, SessionType::Direct(_));
 				let mut session = RemoteSession::new(session_id, return_session_type);
-				let return_ping_id = session.tracker.gen_ping(self_ticks);
+				session.tracker.heard(self_ticks);
+				let return_ping_id = session.tracker.gen_ping(self_ticks, &mut self.rng);
 				let acknowledgement = NodeEncryption::Acknowledge { session_id, acknowledger: recipient, return_ping_id };
 				let packet = session.gen_packet(acknowledgement, self)?;
 				outgoing.push(packet);
 				self.remote_mut(remote_idx)?.session = Some(session);
 				
 				self.sessions.insert(session_id, remote_idx);
+				if direct { self.action(self.next_keepalive(session_id, 0)); }
 				log::debug!("[{: >6}] Node({:?}) Received Handshake: {:?}", self_ticks, self_node_id, encryption);
 				None
 			},
 			NodeEncryption::Acknowledge { session_id, acknowledger, return_ping_id } => {
 				let remote_idx = self.index_by_node_id(&acknowledger)?;
-				let mut remote = self.remote_mut(remote_idx)?;
+				// Borrow remote directly so the node's rng can be borrowed alongside it
+				let remote = self.remotes.get_mut(remote_idx).ok_or(NodeError::InvalidNodeIndex { node_idx: remote_idx })?;
 				if let Some(boxed_pending) = remote.pending_session.take() {
 					let (pending_session_id, time_sent_handshake, packets_to_send, pending_session_type) = *boxed_pending;
 					if pending_session_id == session_id {
 						// Create session and acknowledge out-of-tracker ping
+						let direct = matches!(pending_session_type, SessionType::Direct(_));
 						let mut session = RemoteSession::new(session_id, pending_session_type);
-						let ping_id = session.tracker.gen_ping(time_sent_handshake);
+						let ping_id = session.tracker.gen_ping(time_sent_handshake, &mut self.rng);
 						let distance = session.tracker.acknowledge_ping(ping_id, self_ticks)?;
-						remote.session = Some(session); // update remote
+						// A session that was still there is replaced, its SessionID no longer leads to the remote
+						if let Some(replaced) = remote.session.replace(session) { self.sessions.remove(&replaced.session_id); }
 
 						// Update packets
 						let packets_to_send = self.update_connection_packets(remote_idx, packets_to_send)?;
@@ -650,8 +850,11 @@ impl Node {
 						self.send_packet(remote_idx, NodePacket::ConnectionInit(return_ping_id, packets_to_send), outgoing)?;
 						// Make note of session
 						self.sessions.insert(session_id, remote_idx);
-						self.direct_sorted.insert(distance, remote_idx);
-						self.route_map.add_edge(self.node_id, acknowledger, distance);
+						if direct {
+							self.sort_direct(remote_idx, distance);
+							self.route_map.insert((self.node_id, acknowledger), distance);
+							self.action(self.next_keepalive(session_id, 0));
+						}
 
 						log::debug!("[{: >6}] Node({:?}) Received Acknowledgement: {:?}", self_ticks, self_node_id, encryption);
 						None
@@ -679,108 +882,113 @@ impl Node {
 		outgoing.push(packet);
 		Ok(())
 	}
-	fn calculate_route_coord(&mut self) -> Result<RouteCoord, NodeError> {
-		let route_coord = self.deus_ex_data.ok_or(NodeError::Other(anyhow!("no deus ex machina data")))?;
-		log::debug!("NodeID({}) Calculated RouteCoord({})", self.node_id, route_coord);
-		return Ok(route_coord);
-
-		/* use nalgebra::{DMatrix, SymmetricEigen};
-		// TODO: Fix matrix output rotation & translation
-		// println!("node_list: {:?}", self.remotes.iter().map(|(&id,n)|(id,n.route_coord)).collect::<Vec<(NodeID,Option<RouteCoord>)>>() );
-		/* let nodes: Vec<(NodeIdx, RouteCoord)> = self.direct_sorted.iter().filter_map(|(_,&node_idx)|self.remote(node_idx).ok().map(|node|node.route_coord.map(|s|(node_idx,s))).flatten()).collect(); */
-		
-		// Get index list of all peered remotes
-		let remote_idxs = self.peer_list.iter().map(|(idx,_)| *idx).collect::<Vec<NodeIdx>>();
-		let mat_size = remote_idxs.len() + 1;
-		
-		/* println!("filtered_node_list: {:?}", nodes); */
-		let mut proximity_matrix = DMatrix::from_element(mat_size, mat_size, 0f64);
-		
-		
-		// This is inefficient b.c. multiple vector creation but whatever
-		/* let node_id_index: Vec<NodeIdx> = self.route_map.edges(self.node_id).filter_map(|(_,n,&e)|{
-			(e != 0 && n != self.node_id).then(||(self.index_by_node_id(&n).unwrap()))
-		}).unzip(); */
+	/// What this node knows about where it is, from measured distances and remotes' route coordinates
+	fn observations(&self) -> Observations {
+		let anchors: Vec<Anchor> = self.remotes.values().filter_map(|remote| {
+			let session = remote.session().ok()?;
+			// Only direct sessions measure the latency to the remote itself
+			if !matches!(session.session_type, SessionType::Direct(_)) || session.tracker.ping_count == 0 { return None }
+			Some(Anchor { node_id: remote.node_id, route_coord: remote.route_coord?, distance: session.tracker.dist_avg as f64 })
+		}).collect();
+		let anchor_ids: BTreeSet<NodeID> = anchors.iter().map(|anchor| anchor.node_id).collect();
+		let mut anchor_distances = BTreeMap::new();
+		for (&(a, b), &distance) in &self.route_map {
+			if a == b || !anchor_ids.contains(&a) || !anchor_ids.contains(&b) { continue }
+			// Either end may have reported the distance, average the two if both did
+			anchor_distances.entry((a.min(b), a.max(b)))
+				.and_modify(|average: &mut f64| *average = (*average + distance as f64) / 2.)
+				.or_insert(distance as f64);
+		}
+		Observations { route_coord: self.route_coord, anchors, anchor_distances, oracle: self.deus_ex_data }
+	}
+	fn next_refinement(&self) -> NodeAction {
+		NodeAction::RefineRouteCoord.gen_condition(NodeActionCondition::RunAt(self.ticks + (REFINE_INTERVAL << self.refine_backoff)))
+	}
+	fn next_keepalive(&self, session_id: SessionID, backoff: u8) -> NodeAction {
+		NodeAction::Keepalive(session_id).gen_condition(NodeActionCondition::RunAt(self.ticks + (KEEPALIVE_INTERVAL << backoff)))
+	}
+	/// Re-estimate the route coordinate and adopt the estimate if it moved further than `COORD_HYSTERESIS`, returns whether it moved
+	fn refine_route_coord(&mut self, outgoing: &mut PacketVec, out_actions: &mut ActionVec) -> Result<bool, NodeError> {
+		let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+		match self.calculate_route_coord() {
+			Ok(estimate) if types::route_dist(&self_route_coord, &estimate) > COORD_HYSTERESIS => {
+				log::debug!("[{: >6}] NodeID({}) RouteCoord moved from {} to {}", self.ticks, self.node_id, self_route_coord, estimate);
+				self.route_coord = Some(estimate);
+				self.announce_route_coord(outgoing)?;
+				out_actions.push(NodeAction::CalculatePeers);
+				Ok(true)
+			}
+			// Nobody to estimate from yet, the pings may change that
+			Ok(_) | Err(NodeError::InsufficientPeers { .. }) => Ok(false),
+			Err(err) => Err(err),
+		}
+	}
+	/// Sort a direct remote under the distance just measured to it, replacing the distance it was sorted under before
+	fn sort_direct(&mut self, node_idx: NodeIdx, distance: u64) {
+		self.direct_sorted.retain(|&(_, sorted_idx)| sorted_idx != node_idx);
+		self.direct_sorted.insert((distance, node_idx));
+	}
+	/// Forget the session with a remote and every distance measured through it, the remote and its route coordinate are kept.
+	/// Sessions routed through the remote and sessions proxied for it go too.
+	fn remove_session(&mut self, node_idx: NodeIdx) -> Result<(), NodeError> {
+		let remote = self.remote_mut(node_idx)?;
+		let remote_node_id = remote.node_id;
+		let session_id = match remote.session.take() { Some(session) => session.session_id, None => return Ok(()) };
+		self.sessions.remove(&session_id);
+		self.direct_sorted.retain(|&(_, sorted_idx)| sorted_idx != node_idx);
+		self.peer_list.remove_by_left(&node_idx);
+		self.route_map.remove(&(self.node_id, remote_node_id));
+		self.route_map.remove(&(remote_node_id, self.node_id));
+		self.relays.retain(|_, &mut relay_idx| relay_idx != node_idx);
 
-		
-		/* let mut first_row_insert = self.peer_list.iter().map(|(a, _)|*a).collect();
-		first_row_insert.insert(0, 0); */
-
-		/* println!("first_row_insert: {:?}", first_row_insert);
-		println!("node: {:?}", self);
-		println!("route_map: {:#?}", self.route_map); */
-		// Fill first row and collumn
-		first_row_insert.iter().enumerate().for_each(|(i,&w)| {
-			proximity_matrix[(0,i)] = w as f64;
-			proximity_matrix[(i,0)] = w as f64;
-		});
-
-		node_id_index.iter().enumerate().for_each(|(i_row, idx_row)|{
-			node_id_index.iter().enumerate().for_each(|(i_col, idx_col)|{
-				let coord_row = self.remote(*idx_row).unwrap().route_coord.unwrap();
-				let coord_col = self.remote(*idx_col).unwrap().route_coord.unwrap();
-				/* let dist_vec = Vector2::new(coord_x.0 as f64, coord_x.1 as f64) - Vector2::new(coord_y.0 as f64,coord_y.1 as f64);
-				let dist = dist_vec.norm(); */				
-				let dist = types::route_dist(&coord_row, &coord_col);
-				proximity_matrix[(i_row+1, i_col+1)] = dist;
-				proximity_matrix[(i_col+1, i_row+1)] = dist;
-			});
-		});
-		println!("Proximity Matrix: {}", proximity_matrix);
-		// Algorithm for Multidimensional Scaling (MDS) Adapted from: http://citeseerx.ist.psu.edu/viewdoc/download?doi=10.1.1.495.4629&rep=rep1&type=pdf
-		let proximity_squared = proximity_matrix.component_mul(&proximity_matrix); 
-		
-		let j_matrix = DMatrix::from_diagonal_element(mat_size, mat_size, 1.) - DMatrix::from_element(mat_size, mat_size, 1./mat_size as f64);
-		
-		let b_matrix = -0.5 * j_matrix.clone() * proximity_squared * j_matrix;
-		
-		// Calculate Eigenvectors and Eigenvalues and choose the 2 biggest ones
-		let eigen = SymmetricEigen::try_new(b_matrix.clone(), 0., 0).unwrap();
-		let eigenvalues: Vec<f64> = eigen.eigenvalues.data.as_vec().clone();
-		let max_eigenvalue = eigenvalues.iter().enumerate().max_by(|(_,&ev1),(_,ev2)|ev1.partial_cmp(ev2).unwrap()).unwrap();
-		let second_max_eigenvalue = eigenvalues.iter().enumerate().filter(|(i,_)|*i!=max_eigenvalue.0).max_by(|(_,&ev1),(_,ev2)|ev1.partial_cmp(ev2).unwrap()).unwrap();
-
-		let top_eigenvalues = nalgebra::Matrix2::new(max_eigenvalue.1.abs().sqrt(), 0., 0., second_max_eigenvalue.1.abs().sqrt()); // Eigenvalue matrix
-		let top_eigenvectors = DMatrix::from_fn(mat_size, 2, |r,c| if c==0 { eigen.eigenvectors[(r,max_eigenvalue.0)] } else { eigen.eigenvectors[(r,second_max_eigenvalue.0)] });
-		let mut x_matrix = top_eigenvectors.clone() * top_eigenvalues; // Output, index 0 needs to be mapped to virtual routecoord coordinates based on other indices
-		log::trace!("NodeID({}) x_matrix prediction = {}", self.node_id, x_matrix);
-		/* if mat_size == 3 {
-			x_matrix.row_iter_mut().for_each(|mut r|r[1] = -r[1]);
+		let routed_through: Vec<NodeIdx> = self.remotes.iter().filter(|(_, remote)| {
+			matches!(remote.session.as_ref().map(|session| &session.session_type), Some(SessionType::Routed(routed)) if routed.proxy_nodes.contains(&session_id))
+		}).map(|(routed_idx, _)| routed_idx).collect();
+		for routed_idx in routed_through { self.remove_session(routed_idx)?; }
+		Ok(())
+	}
+	/// Known remotes closest to `hops` evenly spaced waypoints between this node and the remote, in route order.
+	/// Each waypoint is moved in a random direction by up to `offset` times the spacing, so routes to the same remote differ.
+	fn choose_proxies(&mut self, remote_node_id: NodeID, remote_route_coord: RouteCoord, hops: usize, offset: f64) -> Result<Vec<NodeID>, NodeError> {
+		let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?.map(|s| s as f64);
+		let spacing = (remote_route_coord.map(|s| s as f64) - self_route_coord) / (hops + 1) as f64;
+		let mut proxies: Vec<NodeID> = Vec::with_capacity(hops);
+		for i in 1..=hops {
+			let mut waypoint = self_route_coord + spacing * i as f64;
+			if offset > 0. {
+				let angle = self.rng.gen_range(0.0..std::f64::consts::TAU);
+				// Square root spreads waypoints evenly over the disk instead of bunching them at its center
+				waypoint += Vector2::new(angle.cos(), angle.sin()) * offset * spacing.norm() * self.rng.gen::<f64>().sqrt();
+			}
+			let closest = self.remotes.values()
+				.filter(|remote| remote.node_id != remote_node_id && !proxies.contains(&remote.node_id))
+				.filter_map(|remote| Some((remote.node_id, nalgebra::distance(&remote.route_coord?.map(|s| s as f64), &waypoint))))
+				.min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal).then(a.0.cmp(&b.0)));
+			proxies.push(closest.ok_or(NodeError::InsufficientPeers { required: hops })?.0);
 		}
-		log::trace!("NodeID({}) x_matrix prediction flip = {}", self.node_id, x_matrix); */
-
-		// Map MDS output to 2 RouteCoordinates
-		// TODO: Refactor this messy code
-		let v1_routecoord = self.remote(self.index_by_node_id(&node_id_index[0])?)?.route_coord.unwrap();
-		let v1 = v1_routecoord.coords.map(|s|s as f64);
-		let v2_routecoord = self.remote(self.index_by_node_id(&node_id_index[1])?)?.route_coord.unwrap();
-		let v2 = v2_routecoord.coords.map(|s|s as f64);
-		use nalgebra::{U2, U1};
-		let x1 = x_matrix.row(1).clone_owned().reshape_generic(U2,U1);
-		//println!("x1: {}", x1);
-		//println!("v1: {}, v2: {}", v1, v2);
-		let x_shift = v1 - x1;
-		println!("x_shift: {}", x_shift);
-		let x1s = x1 + x_shift;
-		let x2s = x_matrix.row(2).clone_owned().reshape_generic(U2,U1) + x_shift;
-		let x3s = x_matrix.row(0).clone_owned().reshape_generic(U2,U1) + x_shift;
-		//println!("x1s: {}, x2s: {}, x3s: {}", x1s, x2s, x3s);
-
-		let xd = x1s - x2s;
-		let vd = v1 - v2;
-		let cos_a = (vd[1] + vd[0]) / (2. * xd[0]);
-		let sin_a = (vd[1] - vd[0]) / (2. * xd[1]);
-		//println!("cos_a: {}, sin_a: {}", cos_a, sin_a);
-		let a = f64::atan2(sin_a, cos_a);
-		log::debug!("a = {}", a.to_degrees());
-		
-		use nalgebra::Matrix2;
-		let rot = Matrix2::new(a.cos(), -a.sin(), a.sin(), a.cos());
-		println!("matrix layout: {}", Matrix2::new(0,1,2,3));
-		let v3_g = rot * x3s;
-		
-		log::info!("RouteCoord generated: {}", v3_g);
-		Ok(Point2::new(v3_g[0] as i64, v3_g[1] as i64)) */
+		Ok(proxies)
+	}
+	/// Tell direct remotes that this node's route coordinate changed, peers through PeerNotify and the rest through ExchangeInfo
+	fn announce_route_coord(&self, outgoing: &mut PacketVec) -> Result<(), NodeError> {
+		let self_route_coord = self.route_coord.ok_or(NodeError::NoCalculatedRouteCoord)?;
+		let num_peers = self.peer_list.len();
+		for (node_idx, remote) in self.remotes.iter() {
+			let session = match remote.session() { Ok(session) if matches!(session.session_type, SessionType::Direct(_)) => session, _ => continue };
+			let dist = session.tracker.dist_avg;
+			let packet = if self.peer_list.contains_left(&node_idx) {
+				NodePacket::PeerNotify(0, self_route_coord, num_peers, dist)
+			} else {
+				NodePacket::ExchangeInfo(Some(self_route_coord), self.direct_sorted.len(), dist)
+			};
+			self.send_packet(node_idx, packet, outgoing)?;
+		}
+		Ok(())
+	}
+	fn calculate_route_coord(&mut self) -> Result<RouteCoord, NodeError> {
+		let observations = self.observations();
+		let route_coord = self.estimator.estimate(&observations, &mut self.rng)?;
+		log::debug!("NodeID({}) Calculated RouteCoord({}) with {} from {} anchors", self.node_id, route_coord, self.estimator.name(), observations.anchors.len());
+		Ok(route_coord)
 	}
 }
 
@@ -807,8 +1015,11 @@ impl fmt::Display for Node {
 					SessionType::Traversed(traversed) => write!(f, ", @ ({}, {})", traversed.route_coord.x, traversed.route_coord.y)?,
 					SessionType::Routed(routed) => {
 						write!(f, ", @ ({}, {}): ", routed.route_coord.x, routed.route_coord.y)?;
-						for node_id in &routed.proxy_nodes {
-							write!(f, "{} -> ", node_id)?;
+						for session_id in &routed.proxy_nodes {
+							match self.sessions.get(session_id).and_then(|&node_idx| self.remotes.get(node_idx)) {
+								Some(proxy) => write!(f, "{} -> ", proxy.node_id)?,
+								None => write!(f, "s:{}? -> ", session_id)?,
+							}
 						}
 						write!(f, "{}", remote.node_id)?;
 					}
@@ -841,4 +1052,55 @@ impl GraphPlottable for Node {
 		}) */
 		Graph::with_capacity(0, 0)
 	}
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn waiting_conditions_sleep_until_a_packet_arrives() {
+		let mut node = Node::new(0, 0);
+		node.add_remote(1).unwrap();
+		node.ticks = 10;
+		let waiting = NodeAction::Notify(1, 0).gen_condition(NodeActionCondition::Session(1));
+		node.action(waiting.clone());
+		node.action(NodeAction::Notify(1, 0).gen_condition(NodeActionCondition::RemoteRouteCoord(1)));
+		assert_eq!(node.next_wakeup(), None);
+		// Deadlines wake the node on the tick they pass
+		node.action(NodeAction::RefineRouteCoord.gen_condition(NodeActionCondition::RunAt(25)));
+		assert_eq!(node.next_wakeup(), Some(16));
+		// A satisfied condition or a condition on an unknown remote is dealt with on the next tick
+		node.remote_mut(node.index_by_node_id(&1).unwrap()).unwrap().route_coord = Some(RouteCoord::new(0, 0));
+		assert_eq!(node.next_wakeup(), Some(1));
+		node.action_list = vec![waiting, NodeAction::Notify(2, 0).gen_condition(NodeActionCondition::Session(2))];
+		assert_eq!(node.next_wakeup(), Some(1));
+		node.action_list = vec![NodeAction::CalculatePeers];
+		assert_eq!(node.next_wakeup(), Some(1));
+	}
+
+	#[test]
+	fn idle_network_backs_off() {
+		let mut rng = SimRng::seed_from_u64(3);
+		let mut net: crate::internet::NetSim<Node> = crate::internet::NetSim::new();
+		for i in 0..12 {
+			let node = Node::new(i, net.lease());
+			net.add_node(node, &mut rng).unwrap();
+		}
+		for net_addr in 1..12 {
+			net.node_mut(net_addr).unwrap().action(NodeAction::Bootstrap(0, 0));
+			net.tick(1000, &mut rng);
+		}
+		let sessions = |net: &crate::internet::NetSim<Node>| net.nodes.values().map(|node| node.sessions.len()).sum::<usize>();
+		let sent_over = |net: &mut crate::internet::NetSim<Node>, rng: &mut SimRng, ticks| {
+			let sent = net.router.stats.sent;
+			net.tick(ticks, rng);
+			net.router.stats.sent - sent
+		};
+		let settling = sent_over(&mut net, &mut rng, 50_000);
+		let connected = sessions(&net);
+		net.tick(500_000, &mut rng);
+		// Steady sessions are pinged and refined a lot less often, but none of them time out
+		assert!(sent_over(&mut net, &mut rng, 50_000) * 4 < settling);
+		assert_eq!(sessions(&net), connected);
+	}
+}
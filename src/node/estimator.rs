@@ -0,0 +1,318 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use nalgebra::{DMatrix, Matrix2, Point2, SymmetricEigen, Vector2};
+use rand::Rng;
+
+use super::{NodeError, NodeID, RouteCoord};
+use super::types::route_dist;
+use crate::rng::SimRng;
+
+/// Most anchors `Mds` embeds, closest first, the eigendecomposition is cubic in their number
+pub const MDS_MAX_ANCHORS: usize = 16;
+/// How fast `Vivaldi::error` follows the error of new samples
+const VIVALDI_ERROR_WEIGHT: f64 = 0.25;
+/// Relative error assumed for anchors, remotes don't share their own estimate
+const VIVALDI_ANCHOR_ERROR: f64 = 0.1;
+
+/// A remote with a known route coordinate, along with the distance this node measured to it
+#[derive(Debug, Clone)]
+pub struct Anchor {
+	pub node_id: NodeID,
+	pub route_coord: RouteCoord,
+	/// Average one-way latency of pings over the direct session with the remote
+	pub distance: f64,
+}
+
+/// Everything a node knows that it can estimate its route coordinate from
+#[derive(Debug, Clone, Default)]
+pub struct Observations {
+	/// The node's current route coordinate, if it has one
+	pub route_coord: Option<RouteCoord>,
+	pub anchors: Vec<Anchor>,
+	/// Distances between anchors that remotes reported, keyed by (lower NodeID, higher NodeID)
+	pub anchor_distances: BTreeMap<(NodeID, NodeID), f64>,
+	/// Where the node really is, only the `Estimator::Oracle` baseline looks at this
+	pub oracle: Option<RouteCoord>,
+}
+impl Observations {
+	/// Distance between two anchors, measured if a remote reported it, otherwise what their route coordinates say
+	fn anchor_distance(&self, a: &Anchor, b: &Anchor) -> f64 {
+		let key = (a.node_id.min(b.node_id), a.node_id.max(b.node_id));
+		self.anchor_distances.get(&key).copied().unwrap_or_else(|| route_dist(&a.route_coord, &b.route_coord))
+	}
+}
+
+/// Places a node in route coordinate space
+pub trait CoordinateEstimator {
+	/// Estimate the node's route coordinate, fails if the observations aren't enough to place the node
+	fn estimate(&mut self, observations: &Observations, rng: &mut SimRng) -> Result<RouteCoord, NodeError>;
+}
+
+fn to_point(route_coord: &RouteCoord) -> Point2<f64> { route_coord.map(|s| s as f64) }
+fn to_route_coord(point: Point2<f64>) -> RouteCoord { point.map(|s| s.round() as i64) }
+fn random_direction(rng: &mut SimRng) -> Vector2<f64> {
+	let angle = rng.gen_range(0.0..std::f64::consts::TAU);
+	Vector2::new(angle.cos(), angle.sin())
+}
+/// At the measured distance from `anchor`, which is all a single anchor can tell.
+/// Keeps the direction of the current coordinate if there is one, so that re-estimating doesn't move the node around the anchor.
+fn around(anchor: &Anchor, current: Option<&RouteCoord>, rng: &mut SimRng) -> Point2<f64> {
+	let anchor_point = to_point(&anchor.route_coord);
+	let direction = current.map(|current| to_point(current) - anchor_point).filter(|offset| offset.norm() > f64::EPSILON);
+	anchor_point + direction.map_or_else(|| random_direction(rng), |offset| offset.normalize()) * anchor.distance
+}
+/// `point` mirrored across the line through `a` and `b`
+fn mirror(point: Point2<f64>, a: Point2<f64>, b: Point2<f64>) -> Point2<f64> {
+	let line = b - a;
+	if line.norm() <= f64::EPSILON { return point }
+	let line = line.normalize();
+	let foot = a + line * (point - a).dot(&line);
+	foot + (foot - point)
+}
+
+/// Rotate (or mirror) and translate `positions` to best fit `targets` (orthogonal Procrustes), then move `point` the same way
+fn align(positions: &[Point2<f64>], targets: &[Point2<f64>], point: Point2<f64>) -> Point2<f64> {
+	let centroid = |points: &[Point2<f64>]| Point2::from(points.iter().map(|p| p.coords).sum::<Vector2<f64>>() / points.len() as f64);
+	let (from, to) = (centroid(positions), centroid(targets));
+	let covariance: Matrix2<f64> = positions.iter().zip(targets).map(|(p, t)| (p - from) * (t - to).transpose()).sum();
+	let svd = covariance.svd(true, true);
+	let rotation = match (svd.u, svd.v_t) {
+		(Some(u), Some(v_t)) => v_t.transpose() * u.transpose(),
+		_ => Matrix2::identity(),
+	};
+	to + rotation * (point - from)
+}
+
+/// Classical multidimensional scaling: lay out the node and its closest anchors in 2D from their pairwise distances,
+/// then fit the layout onto the anchors' route coordinates.
+/// Adapted from: http://citeseerx.ist.psu.edu/viewdoc/download?doi=10.1.1.495.4629&rep=rep1&type=pdf
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mds {
+	pub max_anchors: usize,
+}
+impl Default for Mds {
+	fn default() -> Self { Self { max_anchors: MDS_MAX_ANCHORS } }
+}
+impl CoordinateEstimator for Mds {
+	fn estimate(&mut self, observations: &Observations, rng: &mut SimRng) -> Result<RouteCoord, NodeError> {
+		let mut anchors: Vec<&Anchor> = observations.anchors.iter().collect();
+		anchors.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal).then(a.node_id.cmp(&b.node_id)));
+		anchors.truncate(self.max_anchors.max(1));
+		match anchors.as_slice() {
+			[] => return Err(NodeError::InsufficientPeers { required: 1 }),
+			[anchor] => return Ok(to_route_coord(around(anchor, observations.route_coord.as_ref(), rng))),
+			_ => {}
+		}
+
+		// Index 0 is this node, the rest are the anchors
+		let size = anchors.len() + 1;
+		let distance = |i: usize, j: usize| match (i, j) {
+			_ if i == j => 0.,
+			(0, j) => anchors[j - 1].distance,
+			(i, 0) => anchors[i - 1].distance,
+			(i, j) => observations.anchor_distance(anchors[i - 1], anchors[j - 1]),
+		};
+		let squared = DMatrix::from_fn(size, size, |i, j| distance(i, j).powi(2));
+		// Double centering turns squared distances into inner products of positions around their centroid
+		let centering = DMatrix::identity(size, size) - DMatrix::from_element(size, size, 1. / size as f64);
+		let eigen = SymmetricEigen::new(-0.5 * &centering * squared * &centering);
+		let mut order: Vec<usize> = (0..size).collect();
+		order.sort_by(|&a, &b| eigen.eigenvalues[b].partial_cmp(&eigen.eigenvalues[a]).unwrap_or(Ordering::Equal));
+		// Negative eigenvalues come from distances that no layout satisfies, they contribute nothing
+		let positions: Vec<Point2<f64>> = (0..size).map(|i| {
+			Point2::new(0, 1).map(|axis| eigen.eigenvectors[(i, order[axis])] * eigen.eigenvalues[order[axis]].max(0.).sqrt())
+		}).collect();
+
+		let targets: Vec<Point2<f64>> = anchors.iter().map(|anchor| to_point(&anchor.route_coord)).collect();
+		let estimate = align(&positions[1..], &targets, positions[0]);
+		// Two anchors can't tell which side of them the node is on, stay on the side it already is
+		if let ([a, b], Some(current)) = (targets.as_slice(), observations.route_coord) {
+			let mirrored = mirror(estimate, *a, *b);
+			if nalgebra::distance(&mirrored, &to_point(&current)) < nalgebra::distance(&estimate, &to_point(&current)) {
+				return Ok(to_route_coord(mirrored));
+			}
+		}
+		Ok(to_route_coord(estimate))
+	}
+}
+
+/// Vivaldi: every anchor pulls or pushes the node like a spring whose rest length is the measured distance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vivaldi {
+	/// Passes over the anchors per estimate
+	pub rounds: usize,
+	/// Fraction of a spring's displacement the node moves by, scaled down as the node gets confident
+	pub step: f64,
+	/// Moving average of the relative error of samples, starts at 1 (no confidence)
+	pub error: f64,
+}
+impl Default for Vivaldi {
+	fn default() -> Self { Self { rounds: 100, step: 0.25, error: 1. } }
+}
+impl CoordinateEstimator for Vivaldi {
+	fn estimate(&mut self, observations: &Observations, rng: &mut SimRng) -> Result<RouteCoord, NodeError> {
+		let closest = observations.anchors.iter()
+			.min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap_or(Ordering::Equal))
+			.ok_or(NodeError::InsufficientPeers { required: 1 })?;
+		// Without a coordinate yet, start out near the closest anchor
+		let mut position = observations.route_coord.as_ref().map_or_else(|| around(closest, None, rng), to_point);
+		for _ in 0..self.rounds {
+			for anchor in &observations.anchors {
+				let offset = position - to_point(&anchor.route_coord);
+				let length = offset.norm();
+				// Nodes on top of each other push apart in any direction
+				let direction = if length > f64::EPSILON { offset / length } else { random_direction(rng) };
+				let weight = self.error / (self.error + VIVALDI_ANCHOR_ERROR);
+				let sample_error = (length - anchor.distance).abs() / anchor.distance.max(1.);
+				self.error = sample_error * VIVALDI_ERROR_WEIGHT * weight + self.error * (1. - VIVALDI_ERROR_WEIGHT * weight);
+				position += direction * (anchor.distance - length) * self.step * weight;
+			}
+		}
+		Ok(to_route_coord(position))
+	}
+}
+
+/// Estimator a node uses to calculate its route coordinate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Estimator {
+	/// Baseline that reads the node's true position from the simulator, real nodes can't do this
+	Oracle,
+	Mds(Mds),
+	Vivaldi(Vivaldi),
+}
+impl Default for Estimator {
+	fn default() -> Self { Estimator::Mds(Mds::default()) }
+}
+impl Estimator {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Estimator::Oracle => "oracle",
+			Estimator::Mds(_) => "mds",
+			Estimator::Vivaldi(_) => "vivaldi",
+		}
+	}
+	/// Same estimator without anything it learned, for a new node
+	pub fn fresh(&self) -> Self {
+		match self {
+			Estimator::Vivaldi(vivaldi) => Estimator::Vivaldi(Vivaldi { error: Vivaldi::default().error, ..vivaldi.clone() }),
+			estimator => estimator.clone(),
+		}
+	}
+}
+impl CoordinateEstimator for Estimator {
+	fn estimate(&mut self, observations: &Observations, rng: &mut SimRng) -> Result<RouteCoord, NodeError> {
+		match self {
+			Estimator::Oracle => observations.oracle.ok_or(NodeError::Other(anyhow!("no deus ex machina data"))),
+			Estimator::Mds(mds) => mds.estimate(observations, rng),
+			Estimator::Vivaldi(vivaldi) => vivaldi.estimate(observations, rng),
+		}
+	}
+}
+impl std::str::FromStr for Estimator {
+	type Err = String;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"oracle" => Ok(Estimator::Oracle),
+			"mds" => Ok(Estimator::Mds(Mds::default())),
+			"vivaldi" => Ok(Estimator::Vivaldi(Vivaldi::default())),
+			_ => Err(format!("unknown estimator {:?}, valid: oracle, mds, vivaldi", s)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::SeedableRng;
+
+	use super::*;
+
+	/// Observations of a node at `truth` that measured exact distances to anchors at `anchors`
+	fn observe(truth: RouteCoord, anchors: &[RouteCoord], current: Option<RouteCoord>) -> Observations {
+		let anchors = anchors.iter().enumerate().map(|(node_id, &route_coord)| {
+			Anchor { node_id: node_id as NodeID, route_coord, distance: route_dist(&truth, &route_coord) }
+		}).collect();
+		Observations { route_coord: current, anchors, ..Default::default() }
+	}
+	fn error(estimator: &mut impl CoordinateEstimator, truth: RouteCoord, observations: &Observations) -> f64 {
+		route_dist(&estimator.estimate(observations, &mut SimRng::seed_from_u64(1)).unwrap(), &truth)
+	}
+	fn anchors() -> Vec<RouteCoord> {
+		vec![RouteCoord::new(0, 0), RouteCoord::new(300, 20), RouteCoord::new(-50, 250), RouteCoord::new(200, -180), RouteCoord::new(-220, -90)]
+	}
+
+	#[test]
+	fn mirror_across_line() {
+		let (a, b) = (Point2::new(0., 0.), Point2::new(10., 0.));
+		assert_eq!(mirror(Point2::new(3., 4.), a, b), Point2::new(3., -4.));
+		// Points on the line stay put, so does everything if the line is a single point
+		assert_eq!(mirror(Point2::new(7., 0.), a, b), Point2::new(7., 0.));
+		assert_eq!(mirror(Point2::new(3., 4.), a, a), Point2::new(3., 4.));
+	}
+
+	#[test]
+	fn align_undoes_rotation_and_translation() {
+		let targets = [Point2::new(0., 0.), Point2::new(100., 0.), Point2::new(0., 50.)];
+		let (angle, shift) = (1.1f64, Vector2::new(-40., 75.));
+		let rotate = |p: &Point2<f64>| Point2::new(p.x * angle.cos() - p.y * angle.sin(), p.x * angle.sin() + p.y * angle.cos()) + shift;
+		let positions: Vec<Point2<f64>> = targets.iter().map(rotate).collect();
+		let point = Point2::new(30., 30.);
+		assert!(nalgebra::distance(&align(&positions, &targets, rotate(&point)), &point) < 1e-9);
+		// A mirrored layout is flipped back too
+		let mirrored: Vec<Point2<f64>> = targets.iter().map(|p| Point2::new(-p.x, p.y)).collect();
+		assert!(nalgebra::distance(&align(&mirrored, &targets, Point2::new(-30., 30.)), &point) < 1e-9);
+	}
+
+	#[test]
+	fn mds_places_node_from_anchors() {
+		let truth = RouteCoord::new(60, 80);
+		for count in 3..=anchors().len() {
+			let observations = observe(truth, &anchors()[..count], None);
+			assert!(error(&mut Mds::default(), truth, &observations) <= 2., "{} anchors", count);
+		}
+		// Only the closest `max_anchors` are embedded
+		let observations = observe(truth, &anchors(), None);
+		assert!(error(&mut Mds { max_anchors: 3 }, truth, &observations) <= 2.);
+	}
+
+	#[test]
+	fn mds_keeps_side_of_two_anchors() {
+		let two = [RouteCoord::new(0, 0), RouteCoord::new(200, 0)];
+		let (truth, mirrored) = (RouteCoord::new(80, 60), RouteCoord::new(80, -60));
+		// Two anchors can't tell the sides apart, the current coordinate decides
+		assert!(error(&mut Mds::default(), truth, &observe(truth, &two, Some(RouteCoord::new(70, 50)))) <= 2.);
+		assert!(error(&mut Mds::default(), mirrored, &observe(truth, &two, Some(RouteCoord::new(70, -50)))) <= 2.);
+	}
+
+	#[test]
+	fn single_anchor_keeps_direction() {
+		let anchor = [RouteCoord::new(10, 10)];
+		let truth = RouteCoord::new(10, 110);
+		let estimate = Mds::default().estimate(&observe(truth, &anchor, Some(RouteCoord::new(10, 500))), &mut SimRng::seed_from_u64(1)).unwrap();
+		assert!(route_dist(&estimate, &truth) <= 1.);
+		// Without a coordinate the direction is random, the distance is still right
+		let estimate = Mds::default().estimate(&observe(truth, &anchor, None), &mut SimRng::seed_from_u64(1)).unwrap();
+		assert!((route_dist(&estimate, &anchor[0]) - 100.).abs() <= 1.);
+	}
+
+	#[test]
+	fn vivaldi_converges_on_node() {
+		let truth = RouteCoord::new(60, 80);
+		let mut vivaldi = Vivaldi::default();
+		let observations = observe(truth, &anchors(), None);
+		assert!(error(&mut vivaldi, truth, &observations) <= 5.);
+		// The node grew confident in its coordinate
+		assert!(vivaldi.error < 0.1);
+		// Starting from a poor coordinate it gets pulled back
+		let observations = observe(truth, &anchors(), Some(RouteCoord::new(-150, 200)));
+		assert!(error(&mut Vivaldi::default(), truth, &observations) <= 5.);
+	}
+
+	#[test]
+	fn estimators_need_anchors() {
+		let observations = Observations::default();
+		let mut rng = SimRng::seed_from_u64(1);
+		assert!(matches!(Mds::default().estimate(&observations, &mut rng), Err(NodeError::InsufficientPeers { required: 1 })));
+		assert!(matches!(Vivaldi::default().estimate(&observations, &mut rng), Err(NodeError::InsufficientPeers { required: 1 })));
+	}
+}
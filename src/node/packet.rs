@@ -69,7 +69,71 @@ pub enum NodePacket {
 	RoutedSessionRequest(RouteCoord),
 	RoutedSessionAccept(), */
 
-	Data(Vec<u8>)
+	Data(Vec<u8>),
+
+	/// ### Latency Measurement
+	/// Measure latency to a direct remote, answered with a Pong carrying the same PingID
+	Ping(PingID),
+	Pong(PingID),
+
+	/// ### Teardown
+	/// The sender closed the session, the receiver should forget it too
+	Close,
+	/// The sender is leaving the network, the receiver should forget the session and every distance it learned through the sender
+	Leave,
+
+	/// ### Onion Routing
+	/// Ask a proxy to traverse an encryption on towards a route coordinate, whatever comes back for it is relayed through the session this arrived on
+	/// * `RouteCoord`: Where to traverse the encryption to, the next proxy or the destination
+	/// * `NodeEncryption`: The next layer, only the next proxy or the destination can read it
+	Proxy(RouteCoord, Box<NodeEncryption>),
+	/// Encryption a proxy relays back to the node that routed a session through it
+	Relayed(Box<NodeEncryption>),
+}
+impl NodePacket {
+	/// Index of the packet's variant, unlike `mem::Discriminant` it can be saved in a snapshot
+	pub fn kind(&self) -> u8 {
+		match self {
+			NodePacket::ConnectionInit(..) => 0,
+			NodePacket::ExchangeInfo(..) => 1,
+			NodePacket::ExchangeInfoResponse(..) => 2,
+			NodePacket::PeerNotify(..) => 3,
+			NodePacket::ProposeRouteCoords(..) => 4,
+			NodePacket::ProposeRouteCoordsResponse(..) => 5,
+			NodePacket::RequestPings(..) => 6,
+			NodePacket::WantPing(..) => 7,
+			NodePacket::AcceptWantPing(..) => 8,
+			NodePacket::Traverse(..) => 9,
+			NodePacket::Data(..) => 10,
+			NodePacket::Ping(..) => 11,
+			NodePacket::Pong(..) => 12,
+			NodePacket::Close => 13,
+			NodePacket::Leave => 14,
+			NodePacket::Proxy(..) => 15,
+			NodePacket::Relayed(..) => 16,
+		}
+	}
+	pub fn name(&self) -> &'static str {
+		match self {
+			NodePacket::ConnectionInit(..) => "ConnectionInit",
+			NodePacket::ExchangeInfo(..) => "ExchangeInfo",
+			NodePacket::ExchangeInfoResponse(..) => "ExchangeInfoResponse",
+			NodePacket::PeerNotify(..) => "PeerNotify",
+			NodePacket::ProposeRouteCoords(..) => "ProposeRouteCoords",
+			NodePacket::ProposeRouteCoordsResponse(..) => "ProposeRouteCoordsResponse",
+			NodePacket::RequestPings(..) => "RequestPings",
+			NodePacket::WantPing(..) => "WantPing",
+			NodePacket::AcceptWantPing(..) => "AcceptWantPing",
+			NodePacket::Traverse(..) => "Traverse",
+			NodePacket::Data(..) => "Data",
+			NodePacket::Ping(..) => "Ping",
+			NodePacket::Pong(..) => "Pong",
+			NodePacket::Close => "Close",
+			NodePacket::Leave => "Leave",
+			NodePacket::Proxy(..) => "Proxy",
+			NodePacket::Relayed(..) => "Relayed",
+		}
+	}
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -103,6 +167,38 @@ impl NodeEncryption {
 	pub fn unpackage(packet: &InternetPacket) -> Result<Self, bincode::Error> {
 		bincode::deserialize(&packet.data)
 	}
+	pub fn name(&self) -> &'static str {
+		match self {
+			NodeEncryption::Handshake { .. } => "Handshake",
+			NodeEncryption::Acknowledge { .. } => "Acknowledge",
+			NodeEncryption::Session { .. } => "Session",
+			NodeEncryption::Notify { .. } => "Notify",
+			NodeEncryption::Request { .. } => "Request",
+		}
+	}
+	/// Names of this encryption's variant and the packet inside it, following traversed, proxied and relayed packets down to the innermost one
+	pub fn variants(&self) -> Vec<&'static str> {
+		let mut variants = vec![self.name()];
+		let mut encryption = self;
+		while let NodeEncryption::Session { packet, .. } = encryption {
+			variants.push(packet.name());
+			encryption = match packet {
+				NodePacket::Traverse(traversed) => &traversed.encryption,
+				NodePacket::Proxy(_, inner) | NodePacket::Relayed(inner) => inner,
+				_ => break,
+			};
+			variants.push(encryption.name());
+		}
+		variants
+	}
+	/// Session the encryption belongs to, None for notifications outside of sessions
+	pub fn session_id(&self) -> Option<SessionID> {
+		use NodeEncryption::*;
+		match *self {
+			Handshake { session_id, .. } | Acknowledge { session_id, .. } | Session { session_id, .. } => Some(session_id),
+			Notify { .. } | Request { .. } => None,
+		}
+	}
 	/* pub fn wrap_traverse(self, session_id: SessionID, route_coord: RouteCoord) -> NodeEncryption {
 		let packet = NodePacket::Traverse(route_coord, Box::new(self));
 		NodeEncryption::Session { session_id, packet }
@@ -118,7 +214,7 @@ impl NodeEncryption {
 				};
 				result.is_ok()
 			},
-			Session { session_id, packet:_ } => node.sessions.contains_left(&session_id),
+			Session { session_id, packet:_ } => node.sessions.contains_key(&session_id),
 			Notify { recipient, data:_, sender:_ } => node.node_id == recipient,
 			Request { recipient, requester:_ } => node.node_id == recipient,
 		}
@@ -26,7 +26,6 @@ pub struct RemoteNode {
 	pub route_coord: Option<RouteCoord>,
 	// If handshake is pending: Some(pending_session_id, time_sent_handshake, packets_to_send)
 	#[derivative(PartialEq="ignore", Hash="ignore")]
-	#[serde(skip)]
 	pub pending_session: Option<Box< (SessionID, usize, Vec<NodePacket>, SessionType) >>,
 	// Contains Session details if session is connected
 	#[derivative(PartialEq="ignore", Hash="ignore")]
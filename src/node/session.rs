@@ -2,70 +2,90 @@
 
 #![allow(non_upper_case_globals)]
 
-use super::{RouteScalar, SessionID, NodeID, NodePacket, Node, NodeError, NetAddr, RouteCoord, NodeEncryption, InternetPacket, TraversedPacket};
+use super::{RouteScalar, SessionID, NodePacket, Node, NodeError, NetAddr, RouteCoord, NodeEncryption, InternetPacket, TraversedPacket};
 
-use std::{cmp::Reverse, collections::HashMap, mem::{Discriminant, discriminant}};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 
-use ta::{indicators::{SimpleMovingAverage, StandardDeviation}, Next};
 use thiserror::Error;
-use priority_queue::PriorityQueue;
+use rand::Rng;
 
 /// Number that uniquely identifies a ping request so that multiple Pings may be sent at the same time
 pub type PingID = u64;
 
 const MAX_PENDING_PINGS: usize = 25;
+/// Number of most recent pings `dist_avg` and `dist_dev` are calculated over
+const PING_WINDOW: usize = 10;
 pub const NUM_NODE_PACKETS: usize = 10;
 
 #[derive(Derivative, Serialize, Deserialize)]
 #[derivative(Debug)]
 pub struct SessionTracker {
 	#[derivative(Debug="ignore")]
-	#[serde(skip)]
-	ping_queue: PriorityQueue<PingID, Reverse<usize>>, // Tuple represents (ID of ping, priority by reversed time sent) 
+	ping_queue: Vec<(PingID, usize)>, // Pending pings as (ID of ping, time sent), oldest first
 	pub dist_avg: RouteScalar,
+	/// Standard deviation of the same pings dist_avg averages
+	pub dist_dev: RouteScalar,
+	/// Round trip times of the last `PING_WINDOW` pings, the ping numbered `ping_count` goes at `ping_count % PING_WINDOW`.
+	/// Kept inline rather than in a moving average and deviation that each box their own window, there is one tracker per session.
 	#[derivative(Debug="ignore")]
-	dist_dev: RouteScalar,
-	#[derivative(Debug="ignore")]
-	#[serde(skip)]
-	ping_avg: SimpleMovingAverage, // Moving average of ping times
-	#[derivative(Debug="ignore")]
-	#[serde(skip)]
-	ping_dev: StandardDeviation,
+	round_trips: [u32; PING_WINDOW],
 	pub ping_count: usize,
+	/// Node time a packet was last received over the session, pings sent since then are unanswered
+	pub last_heard: usize,
+	/// Number of keepalive pings in a row the remote answered at a steady distance, each one doubles the time until the next keepalive
+	pub keepalive_backoff: u8,
 }
 impl SessionTracker {
 	fn new() -> Self {
 		Self {
-			ping_queue: PriorityQueue::with_capacity(MAX_PENDING_PINGS),
+			ping_queue: Vec::new(),
 			dist_avg: 0,
 			dist_dev: 0,
-			ping_avg: SimpleMovingAverage::new(10).unwrap(),
-			ping_dev: ta::indicators::StandardDeviation::new(10).unwrap(),
+			round_trips: [0; PING_WINDOW],
 			ping_count: 0,
+			last_heard: 0,
+			keepalive_backoff: 0,
 		}
 	}
 	// Generate Ping Packet
-	pub fn gen_ping(&mut self, gen_time: usize) -> PingID {
-		let ping_id: PingID = rand::random();
-		self.ping_queue.push(ping_id, Reverse(gen_time));
-		// There shouldn't be more than 25 pings pending
+	pub fn gen_ping(&mut self, gen_time: usize, rng: &mut impl Rng) -> PingID {
+		let ping_id: PingID = rng.gen();
+		self.ping_queue.push((ping_id, gen_time));
+		// There shouldn't be more than 25 pings pending, forget the oldest
 		if self.ping_queue.len() >= MAX_PENDING_PINGS {
-			self.ping_queue.pop();
+			self.ping_queue.remove(0);
 		}
 		ping_id
 	}
 	// Acknowledge Ping Response packet
 	pub fn acknowledge_ping(&mut self, ping_id: PingID, current_time: usize) -> Result<RouteScalar, SessionError> {
-		if let Some(( _, Reverse(time_sent) )) = self.ping_queue.remove(&ping_id) {
-			let round_trip_time = current_time - time_sent;
-			let distance = round_trip_time as f64 / 2.0;
-			self.dist_avg = self.ping_avg.next(distance) as RouteScalar;
-			//self.dist_dev = self.ping_dev.next(distance) as RouteScalar;
+		if let Some(index) = self.ping_queue.iter().position(|&(id, _)| id == ping_id) {
+			let (_, time_sent) = self.ping_queue.remove(index);
+			let round_trip_time = u32::try_from(current_time - time_sent).unwrap_or(u32::MAX);
+			self.round_trips[self.ping_count % PING_WINDOW] = round_trip_time;
 			self.ping_count += 1;
+			// Distance is half the round trip
+			let window = &self.round_trips[..self.ping_count.min(PING_WINDOW)];
+			let mean = window.iter().map(|&rtt| rtt as f64 / 2.0).sum::<f64>() / window.len() as f64;
+			let variance = window.iter().map(|&rtt| (rtt as f64 / 2.0 - mean).powi(2)).sum::<f64>() / window.len() as f64;
+			self.dist_avg = mean as RouteScalar;
+			self.dist_dev = variance.sqrt() as RouteScalar;
+			self.heard(current_time);
 			Ok(self.dist_avg)
 		} else { Err(SessionError::UnknownPingID { ping_id }) }
 	}
 	pub fn pending_pings(&self) -> usize { self.ping_queue.len() }
+	/// Number of pending pings sent since a packet was last received over the session
+	pub fn unanswered_pings(&self) -> usize { self.ping_queue.iter().filter(|&&(_, time_sent)| time_sent >= self.last_heard).count() }
+	/// Whether the latest round trip agrees with the window `dist_avg` is calculated over, within twice the deviation or a tenth of the distance
+	pub fn is_steady(&self) -> bool {
+		if self.ping_count == 0 { return false }
+		let distance = self.round_trips[(self.ping_count - 1) % PING_WINDOW] as f64 / 2.0;
+		let tolerance = (2.0 * self.dist_dev as f64).max(self.dist_avg as f64 / 10.0).max(1.0);
+		(distance - self.dist_avg as f64).abs() <= tolerance
+	}
+	pub fn heard(&mut self, time: usize) { self.last_heard = self.last_heard.max(time); }
 }
 
 bitflags! {
@@ -84,12 +104,15 @@ pub struct DirectSession {
 	pub net_addr: NetAddr,
 	/// Some(bool) if peered, Some(true) if reciprocal peer
 	pub peer_status: PeerStatus,
+	/// Route coordinate of this node that was last sent to the remote in a PeerNotify
+	pub notified_route: Option<RouteCoord>,
 }
 impl DirectSession {
 	pub fn new(net_addr: NetAddr) -> SessionType {
 		SessionType::Direct(DirectSession {
 			net_addr,
 			peer_status: PeerStatus::None,
+			notified_route: None,
 		})
 	}
 	pub fn record_peer_notify(&mut self, rank: usize) {
@@ -112,9 +135,23 @@ impl TraversedSession { pub fn new(route_coord: RouteCoord) -> SessionType { Ses
 pub struct RoutedSession {
 	/// Coordinate of remote routed node
 	pub route_coord: RouteCoord,
-	/// Itermediate hops, First session may or may not be a peer, but it must be Direct or Traversed
+	/// Sessions with the intermediate hops in route order, the first one carries the outermost layer however it travels
 	pub proxy_nodes: Vec<SessionID>,
 }
+impl RoutedSession {
+	/// Wrap `encryption` in a `NodePacket::Proxy` layer for every proxy, the innermost for the last proxy which passes it on to the remote
+	pub fn gen_packet(&self, encryption: NodeEncryption, node: &Node) -> Result<InternetPacket, NodeError> {
+		let mut encryption = encryption;
+		let mut destination = self.route_coord;
+		for session_id in self.proxy_nodes.iter().rev() {
+			let proxy = node.remote(node.index_by_session_id(session_id)?)?;
+			encryption = proxy.session()?.wrap_session(NodePacket::Proxy(destination, Box::new(encryption)));
+			destination = proxy.route_coord.ok_or(NodeError::NoRemoteRouteCoord { remote: proxy.node_id })?;
+		}
+		let first_proxy = self.proxy_nodes.first().ok_or(NodeError::InsufficientPeers { required: 1 })?;
+		node.remote(node.index_by_session_id(first_proxy)?)?.session()?.gen_packet(encryption, node)
+	}
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum SessionType {
@@ -153,8 +190,7 @@ pub struct RemoteSession {
 	pub tracker: SessionTracker,
 	/// Keep track of times certain packets were last received from remote node
 	#[derivative(Debug="ignore")]
-	#[serde(skip)]
-	pub last_packet_times: HashMap<(Discriminant<NodePacket>, NodeID), usize>, // Maps Packets to time last sent
+	pub last_packet_times: BTreeMap<u8, usize>, // Maps packet kinds to time last sent
 }
 impl RemoteSession {
 	pub fn new(session_id: SessionID, session_type: SessionType) -> Self {
@@ -162,7 +198,7 @@ impl RemoteSession {
 			session_id,
 			session_type,
 			tracker: SessionTracker::new(),
-			last_packet_times: HashMap::with_capacity(NUM_NODE_PACKETS),
+			last_packet_times: BTreeMap::new(),
 		}
 	}
 	pub fn direct(&self) -> Result<&DirectSession, SessionError> {
@@ -173,13 +209,13 @@ impl RemoteSession {
 	}
 	pub fn is_peer(&self) -> bool { self.direct().map_or(false, |d|d.peer_status.contains(PeerStatus::Outgoing)) }
 	/// Returns how long ago (in ticks) a packet was last sent or None if packet has never been sent
-	pub fn check_packet_time(&mut self, packet: &NodePacket, sending_node_id: NodeID, current_time: usize) -> Option<usize> {
-		if let Some(last_time) = self.last_packet_times.get_mut(&(discriminant(packet), sending_node_id)) {
+	pub fn check_packet_time(&mut self, packet: &NodePacket, current_time: usize) -> Option<usize> {
+		if let Some(last_time) = self.last_packet_times.get_mut(&packet.kind()) {
 			let difference = current_time - *last_time;
 			*last_time = current_time;
 			Some(difference)
 		} else { 
-			self.last_packet_times.insert((discriminant(packet), sending_node_id), current_time); None
+			self.last_packet_times.insert(packet.kind(), current_time); None
 		}
 	}
 	pub fn wrap_session(&self, packet: NodePacket) -> NodeEncryption {
@@ -192,23 +228,7 @@ impl RemoteSession {
 		let mut encryption = encryption;
 		let outgoing_net_addr = match &self.session_type {
 			SessionType::Direct(direct_session) => { direct_session.net_addr }
-			SessionType::Routed(routed_session) => {
-				let mut current_route_coord = routed_session.route_coord;
-				for session_id in routed_session.proxy_nodes.iter().rev() {
-					// Handle these errors
-					let remote = node.remote(node.index_by_session_id(&session_id)?)?;
-					let origin_coord = remote.route_coord.unwrap();
-
-					let routed_packet = TraversedPacket::new(current_route_coord, encryption, Some(origin_coord));
-					encryption = self.wrap_session(routed_packet);
-					current_route_coord = origin_coord;
-				}
-
-				let node_idx = 
-					if let Some(node_idx) = node.peer_list.get_by_right(&current_route_coord) { *node_idx }
-					else { node.find_closest_peer(&current_route_coord)? };
-				node.remote(node_idx)?.session()?.direct()?.net_addr
-			}
+			SessionType::Routed(routed_session) => return routed_session.gen_packet(encryption, node),
 			SessionType::Traversed(traversed_session) => {
 				// Destination Route Coord
 				let route_coord = traversed_session.route_coord;
@@ -2,8 +2,12 @@
 
 pub use crate::node::session::{RemoteSession, SessionError, SessionType, RoutedSession};
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{BuildHasherDefault, Hash};
+
 use vpsearch::MetricSpace;
 use nalgebra::Point2;
+use bimap::BiHashMap;
 
 /// Hash uniquely identifying a node (represents the Multihash of the node's Public Key)
 pub type NodeID = u32;
@@ -15,6 +19,43 @@ pub type RouteScalar = u64;
 //#[repr(transparent)]
 pub type RouteCoord = Point2<i64>;
 
+/// BiHashMap with a fixed hasher, so iteration order only depends on the order of insertions and not on the process
+pub type StableBiHashMap<L, R> = BiHashMap<L, R, BuildHasherDefault<DefaultHasher>, BuildHasherDefault<DefaultHasher>>;
+
+/// Serialize a `StableBiHashMap` as a list of pairs sorted by their left side, use with `#[serde(with = "stable_bimap")]`.
+/// Iteration order also depends on the map's capacity and past removals, sorting makes the same contents always serialize the same.
+pub mod stable_bimap {
+	use super::{Hash, StableBiHashMap};
+	use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+	pub fn serialize<L, R, S>(map: &StableBiHashMap<L, R>, serializer: S) -> Result<S::Ok, S::Error>
+	where L: Serialize + Eq + Hash + Ord, R: Serialize + Eq + Hash, S: Serializer {
+		let mut pairs: Vec<(&L, &R)> = map.iter().collect();
+		pairs.sort_unstable_by(|a, b| a.0.cmp(b.0));
+		serializer.collect_seq(pairs)
+	}
+	pub fn deserialize<'de, L, R, D>(deserializer: D) -> Result<StableBiHashMap<L, R>, D::Error>
+	where L: Deserialize<'de> + Eq + Hash, R: Deserialize<'de> + Eq + Hash, D: Deserializer<'de> {
+		Ok(Vec::<(L, R)>::deserialize(deserializer)?.into_iter().collect())
+	}
+}
+
+/// Serialize a `BTreeMap` as a list of pairs, use with `#[serde(with = "pairs")]` on maps whose keys aren't strings or numbers,
+/// otherwise the map can't be exported as JSON
+pub mod pairs {
+	use std::collections::BTreeMap;
+	use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+	pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+	where K: Serialize, V: Serialize, S: Serializer {
+		serializer.collect_seq(map.iter())
+	}
+	pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+	where K: Deserialize<'de> + Ord, V: Deserialize<'de>, D: Deserializer<'de> {
+		Ok(Vec::<(K, V)>::deserialize(deserializer)?.into_iter().collect())
+	}
+}
+
 pub struct RouteCoordStruct {
 	x: i64,
 	y: i64,
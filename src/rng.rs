@@ -0,0 +1,34 @@
+use rand::{Error, RngCore, SeedableRng};
+
+/// Small, fast and serializable random number generator (SplitMix64) so that simulation state can be saved and resumed exactly
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimRng {
+	state: u64,
+}
+impl SimRng {
+	/// Derive an independent seed for a sub-component (e.g. a node) from a simulation seed
+	pub fn derive_seed(seed: u64, stream: u64) -> u64 {
+		SimRng::seed_from_u64(seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15)).next_u64()
+	}
+}
+impl RngCore for SimRng {
+	fn next_u32(&mut self) -> u32 { (self.next_u64() >> 32) as u32 }
+	fn next_u64(&mut self) -> u64 {
+		self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		let mut z = self.state;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		z ^ (z >> 31)
+	}
+	fn fill_bytes(&mut self, dest: &mut [u8]) {
+		for chunk in dest.chunks_mut(8) {
+			let bytes = self.next_u64().to_le_bytes();
+			chunk.copy_from_slice(&bytes[..chunk.len()]);
+		}
+	}
+	fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> { self.fill_bytes(dest); Ok(()) }
+}
+impl SeedableRng for SimRng {
+	type Seed = [u8; 8];
+	fn from_seed(seed: Self::Seed) -> Self { Self { state: u64::from_le_bytes(seed) } }
+}